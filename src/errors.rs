@@ -1,5 +1,6 @@
-//! This module contains the Errors struct, which holds all the errors generated by parts of the
-//! program, as well as the Error enum, which enumerates the possible errors.
+//! This module contains the Errors struct, which holds all the errors and warnings generated by
+//! parts of the program, as well as the Error and Warning enums, which enumerate the possible
+//! errors and warnings.
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Error {
@@ -9,18 +10,43 @@ pub enum Error {
     },
     Parser {
         message: String, // description of the error
-        position: usize, // index into the token stream
+        position: usize, // index into the source code
+    },
+    Typeck {
+        message: String, // description of the error
+        position: usize, // index into the source code
+    },
+    IR {
+        message: String, // description of the error
+        position: usize, // index into the source code
+    },
+    Module {
+        message: String, // description of the error
+        position: usize, // index into the source code of the module using it
+    },
+    Llvm {
+        message: String, // description of the error, since IR doesn't know where it came from
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    Lexer {
+        message: String, // description of the warning
+        position: usize, // index into the source code
     },
 }
 
 pub struct Errors {
     pub errors: Vec<Error>,
+    pub warnings: Vec<Warning>,
 }
 
 impl Errors {
     pub fn new() -> Self {
         Errors {
             errors: vec![],
+            warnings: vec![],
         }
     }
 
@@ -35,4 +61,34 @@ impl Errors {
             Error::Parser { message, position }
         );
     }
+
+    pub fn typeck(&mut self, message: String, position: usize) {
+        self.errors.push(
+            Error::Typeck { message, position }
+        );
+    }
+
+    pub fn ir(&mut self, message: String, position: usize) {
+        self.errors.push(
+            Error::IR { message, position }
+        );
+    }
+
+    pub fn module(&mut self, message: String, position: usize) {
+        self.errors.push(
+            Error::Module { message, position }
+        );
+    }
+
+    pub fn llvm(&mut self, message: String) {
+        self.errors.push(
+            Error::Llvm { message }
+        );
+    }
+
+    pub fn lexer_warning(&mut self, message: String, position: usize) {
+        self.warnings.push(
+            Warning::Lexer { message, position }
+        );
+    }
 }
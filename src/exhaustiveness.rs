@@ -0,0 +1,214 @@
+//! This module checks that match expressions cover every value they could be given, using the
+//! usefulness algorithm from "Warnings for pattern matching" (Maranget, 2007)
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::parser::{
+    Pattern,
+    Type,
+};
+
+/// The variants of every enum that has been declared, along with how many values each one holds
+pub type Enums = HashMap<String, Vec<(String, usize)>>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Constructor {
+    Variant(String, String),
+    Tuple(usize),
+    Bool(bool),
+    Literal(String), // there are too many numbers and strings to ever list all of them
+}
+
+#[derive(Debug, Clone)]
+enum Pat {
+    Wildcard,
+    Constructed(Constructor, Vec<Pat>),
+}
+
+impl fmt::Display for Pat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Constructor::*;
+        match self {
+            Pat::Wildcard => write!(f, "_"),
+            Pat::Constructed(Variant(enum_name, variant), args) if args.is_empty() => {
+                write!(f, "{}.{}", enum_name, variant)
+            },
+            Pat::Constructed(Variant(enum_name, variant), args) => {
+                write!(f, "{}.{}({})", enum_name, variant, join(args))
+            },
+            Pat::Constructed(Tuple(_), args) => write!(f, "({})", join(args)),
+            Pat::Constructed(Bool(b), _) => write!(f, "{}", b),
+            Pat::Constructed(Literal(value), _) => write!(f, "{}", value),
+        }
+    }
+}
+
+fn join(pats: &[Pat]) -> String {
+    pats.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Every case the patterns don't cover, written as patterns themselves. Patterns are expected to
+/// only name enums and variants which exist, with the right number of values.
+pub fn missing_cases(patterns: &[&Pattern], enums: &Enums) -> Vec<String> {
+    let mut rows: Vec<Vec<Pat>> = patterns.iter().map(|p| vec![lower(p)]).collect();
+    let mut cases = vec![];
+    while let Some(witness) = missing(&rows, 1, enums) {
+        cases.push(witness[0].to_string());
+        rows.push(witness);
+    }
+    cases
+}
+
+fn lower(pattern: &Pattern) -> Pat {
+    match pattern {
+        Pattern::Wildcard | Pattern::Binding(_) => Pat::Wildcard,
+        Pattern::Literal { typ: Type::Bool, value } => {
+            Pat::Constructed(Constructor::Bool(value == "true"), vec![])
+        },
+        Pattern::Literal { value, .. } => Pat::Constructed(Constructor::Literal(value.clone()), vec![]),
+        Pattern::Tuple(elements) => Pat::Constructed(
+            Constructor::Tuple(elements.len()),
+            elements.iter().map(lower).collect(),
+        ),
+        Pattern::Variant { enum_name, variant, payload } => Pat::Constructed(
+            Constructor::Variant(enum_name.clone(), variant.clone()),
+            payload.iter().map(lower).collect(),
+        ),
+    }
+}
+
+fn arity(constructor: &Constructor, enums: &Enums) -> usize {
+    match constructor {
+        Constructor::Variant(enum_name, variant) => enums.get(enum_name)
+            .and_then(|variants| variants.iter().find(|(name, _)| name == variant))
+            .map_or(0, |(_, arity)| *arity),
+        Constructor::Tuple(n) => *n,
+        Constructor::Bool(_) | Constructor::Literal(_) => 0,
+    }
+}
+
+/// Every constructor of the type the given constructors belong to, if they can all be listed
+fn signature(heads: &[Constructor], enums: &Enums) -> Option<Vec<Constructor>> {
+    match heads.first()? {
+        Constructor::Variant(enum_name, _) => enums.get(enum_name).map(|variants| {
+            variants.iter()
+                .map(|(variant, _)| Constructor::Variant(enum_name.clone(), variant.clone()))
+                .collect()
+        }),
+        Constructor::Tuple(n) => Some(vec![Constructor::Tuple(*n)]),
+        Constructor::Bool(_) => Some(vec![Constructor::Bool(true), Constructor::Bool(false)]),
+        Constructor::Literal(_) => None,
+    }
+}
+
+/// The rows that match a value built with the constructor, with its values spread into columns
+fn specialize(rows: &[Vec<Pat>], constructor: &Constructor, arity: usize) -> Vec<Vec<Pat>> {
+    rows.iter().filter_map(|row| {
+        let mut specialized = match &row[0] {
+            Pat::Wildcard => vec![Pat::Wildcard; arity],
+            Pat::Constructed(c, args) if c == constructor => args.clone(),
+            Pat::Constructed(..) => return None,
+        };
+        specialized.extend_from_slice(&row[1..]);
+        Some(specialized)
+    }).collect()
+}
+
+/// A row of `width` patterns which none of the rows match, if there is one
+fn missing(rows: &[Vec<Pat>], width: usize, enums: &Enums) -> Option<Vec<Pat>> {
+    if width == 0 {
+        return if rows.is_empty() { Some(vec![]) } else { None };
+    }
+
+    let mut heads = vec![];
+    for row in rows {
+        if let Pat::Constructed(c, _) = &row[0] {
+            if !heads.contains(c) {
+                heads.push(c.clone());
+            }
+        }
+    }
+
+    match signature(&heads, enums) {
+        // every constructor is used, so one of them has to be missing something further in
+        Some(constructors) if constructors.iter().all(|c| heads.contains(c)) => {
+            constructors.into_iter().find_map(|c| {
+                let arity = arity(&c, enums);
+                let mut witness = missing(&specialize(rows, &c, arity), arity + width - 1, enums)?;
+                let rest = witness.split_off(arity);
+                let mut row = vec![Pat::Constructed(c, witness)];
+                row.extend(rest);
+                Some(row)
+            })
+        },
+        // otherwise only the rows starting with a wildcard can match the unused constructors
+        signature => {
+            let default: Vec<_> = rows.iter()
+                .filter(|row| matches!(row[0], Pat::Wildcard))
+                .map(|row| row[1..].to_vec())
+                .collect();
+            let mut witness = missing(&default, width - 1, enums)?;
+            let unused = signature.and_then(|constructors| {
+                constructors.into_iter().find(|c| !heads.contains(c))
+            });
+            witness.insert(0, match unused {
+                Some(c) if !heads.is_empty() => {
+                    let arity = arity(&c, enums);
+                    Pat::Constructed(c, vec![Pat::Wildcard; arity])
+                },
+                _ => Pat::Wildcard,
+            });
+            Some(witness)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape() -> Enums {
+        let mut enums = HashMap::new();
+        enums.insert("Shape".to_owned(), vec![
+            ("Circle".to_owned(), 1),
+            ("Rect".to_owned(), 2),
+            ("Empty".to_owned(), 0),
+        ]);
+        enums
+    }
+
+    fn variant(name: &str, payload: Vec<Pattern>) -> Pattern {
+        Pattern::Variant {
+            enum_name: "Shape".to_owned(),
+            variant: name.to_owned(),
+            payload,
+        }
+    }
+
+    fn boolean(value: &str) -> Pattern {
+        Pattern::Literal { typ: Type::Bool, value: value.to_owned() }
+    }
+
+    #[test]
+    fn missing_variants() {
+        let circle = variant("Circle", vec![Pattern::Binding("r".to_owned())]);
+        let square = variant("Rect", vec![Pattern::Wildcard, Pattern::Wildcard]);
+        assert_eq!(missing_cases(&[&circle], &shape()), vec!["Shape.Rect(_, _)", "Shape.Empty"]);
+        assert!(missing_cases(&[&circle, &square, &Pattern::Wildcard], &shape()).is_empty());
+    }
+
+    #[test]
+    fn nested_patterns() {
+        let both = Pattern::Tuple(vec![boolean("true"), boolean("true")]);
+        let first = Pattern::Tuple(vec![boolean("false"), Pattern::Wildcard]);
+        assert_eq!(missing_cases(&[&both, &first], &shape()), vec!["(true, false)"]);
+    }
+
+    #[test]
+    fn literals_need_a_wildcard() {
+        let one = Pattern::Literal { typ: Type::IntLiteral, value: "1".to_owned() };
+        assert_eq!(missing_cases(&[&one], &shape()), vec!["_"]);
+        assert!(missing_cases(&[&one, &Pattern::Binding("n".to_owned())], &shape()).is_empty());
+    }
+}
@@ -7,6 +7,10 @@ use crate::ir::{
     Environment,
     Function,
     InstructionKind,
+    IntType,
+    NumberType,
+    Overflow,
+    Scope,
     Value,
 };
 
@@ -30,39 +34,59 @@ pub struct Interpreter<'i> {
 
     current: Location,
     finished: bool,
+    pub overflow: Overflow, // what plain integer arithmetic does with results that don't fit
 }
 
 impl<'i> Interpreter<'i> {
     pub fn new(env: &'i mut Environment, func_id: usize) -> Self {
-        let first_block = env.functions[&func_id].blocks[1].id;
-
-        Interpreter {
+        let mut interpreter = Interpreter {
             env,
             stack: vec![],
             call_stack: vec![],
 
             current: Location {
                 function: func_id,
-                block: first_block,
+                block: 0,
                 instruction: 0,
             },
             finished: false,
-        } 
+            overflow: Overflow::Trap,
+        };
+        let module = interpreter.env.functions[&func_id].module;
+        interpreter.env.enter_module(module);
+        interpreter.settle();
+        interpreter
     }
 
     fn advance(&mut self) {
         dbg!("advancing");
         self.current.instruction += 1;
-        if self.current.instruction >= self.env.functions[&self.current.function].blocks[self.current.block].instructions.len() {
+        self.settle();
+    }
+
+    /// Falls through from the end of a block into the next one (skipping empty blocks), finishing
+    /// once there are no blocks left in the function
+    fn settle(&mut self) {
+        let blocks = &self.env.functions[&self.current.function].blocks;
+        while self.current.block < blocks.len()
+            && self.current.instruction >= blocks[self.current.block].instructions.len() {
             self.current.block += 1;
             self.current.instruction = 0;
         }
 
-        if self.current.block >= self.env.functions[&self.current.function].blocks.len() {
+        if self.current.block >= blocks.len() {
             self.finished = true;
         }
     }
 
+    /// Locations hold the index of a block in its function, but instructions refer to blocks by id
+    fn block_index(&self, id: usize) -> usize {
+        self.env.functions[&self.current.function].blocks
+            .iter()
+            .position(|block| block.id == id)
+            .unwrap()
+    }
+
     pub fn go(&mut self) { // TODO at some point this will return something???
         loop {
             use InstructionKind::*;
@@ -81,25 +105,52 @@ impl<'i> Interpreter<'i> {
                 ConstInt(value) => self.const_int(value),
                 ConstFloat(value) => self.const_float(value),
                 ConstString(value) => self.const_string(value),
+                ConstUndefined => self.const_undefined(),
+                MakeTuple(count) => self.make_tuple(count),
+                Unpack(count) => self.unpack(count),
+                MakeArray(count) => self.make_array(count),
+                MakeRange => self.make_range(),
+                Index => self.index(),
+                HasNext(name) => self.has_next(name),
+                Next(name) => self.next(name),
+                MakeStructType(fields) => self.make_struct_type(fields),
+                MakeStruct(fields) => self.make_struct(fields),
+                GetField(field) => self.get_field(field),
+                SetField(path) => self.set_field(path),
+                MakeEnumType(variants) => self.make_enum_type(variants),
+                IsVariant(variant) => self.is_variant(variant),
+                GetPayload(index) => self.get_payload(index),
+                GetElement(index) => self.get_element(index),
 
                 Allocate(name) => self.allocate(name),
                 Push(name) => self.push(name),
                 Pop(name) => self.pop(name),
+                Discard => self.discard(),
+                Drop(count) => self.drop(count),
+                EnterScope => self.enter_scope(),
+                ExitScope(count) => self.exit_scope(count),
 
-                Add => self.add(),
-                Subtract => self.subtract(),
-                Multiply => self.multiply(),
+                Add(int, overflow) => self.add(int, overflow),
+                Subtract(int, overflow) => self.subtract(int, overflow),
+                Multiply(int, overflow) => self.multiply(int, overflow),
                 ExactDivide => self.exact_divide(),
-                FloorDivide => self.floor_divide(),
-                Negate => self.negate(),
+                FloorDivide(int) => self.floor_divide(int),
+                Negate(int) => self.negate(int),
+                Not => self.not(),
+                Convert(number) => self.convert(number),
                 Test(compare_type) => self.test(compare_type),
 
-                Call => self.call(),
+                Call(args) => self.call(args),
+                CallMember(name, args) => self.call_member(name, args),
                 Return => self.return_(),
                 BranchIf(then_block, else_block) => self.branch_if(then_block, else_block),
                 Jump(block) => self.jump(block),
+                Unreachable => panic!("No match arm matched the value at {:?}", self.current),
 
                 GetFunction(func) => self.get_function(func),
+                MakeClosure(names) => self.make_closure(names),
+                GetModule(module) => self.get_module(module),
+                EnterModule(module) => self.enter_module(module),
             }
         }
     }
@@ -124,27 +175,253 @@ impl<'i> Interpreter<'i> {
         self.advance();
     }
 
+    fn const_undefined(&mut self) {
+        self.stack.push(Value::Undefined);
+        self.advance();
+    }
+
+    fn make_tuple(&mut self, count: &usize) {
+        let elements = self.stack.split_off(self.stack.len() - count);
+        self.stack.push(Value::Tuple(elements));
+        self.advance();
+    }
+
+    fn unpack(&mut self, count: &usize) {
+        match self.stack.pop().unwrap() {
+            Value::Tuple(elements) if elements.len() == *count => self.stack.extend(elements),
+            Value::Tuple(elements) => panic!("Expected {} values to unpack, but found {}", count, elements.len()),
+            value => panic!("Expected {} values to unpack, but found {:?}", count, value),
+        }
+        self.advance();
+    }
+
+    fn make_array(&mut self, count: &usize) {
+        let elements = self.stack.split_off(self.stack.len() - count);
+        self.stack.push(Value::Array(elements));
+        self.advance();
+    }
+
+    fn make_range(&mut self) {
+        let end = self.stack.pop().unwrap();
+        let start = self.stack.pop().unwrap();
+        let bound = |value| match value {
+            Value::Integer(i) => Some(i),
+            Value::Undefined => None,
+            value => panic!("Expected an integer as the bound of a range, but found {:?}", value),
+        };
+        self.stack.push(Value::Range(bound(start), bound(end)));
+        self.advance();
+    }
+
+    /// Indexing with a range takes a slice of the array, where missing bounds stand for the start
+    /// or end of the array
+    fn index(&mut self) {
+        let index = self.stack.pop().unwrap();
+        let value = match (self.stack.pop().unwrap(), index) {
+            (Value::Array(mut elements), Value::Integer(i)) => {
+                if i < 0 || i as usize >= elements.len() {
+                    panic!("Index {} is out of bounds for an array of length {}", i, elements.len());
+                }
+                elements.swap_remove(i as usize)
+            },
+            (Value::Array(mut elements), Value::Range(start, end)) => {
+                let (start, end) = (start.unwrap_or(0), end.unwrap_or(elements.len() as i128));
+                if start < 0 || start > end || end > elements.len() as i128 {
+                    panic!("Slice {}..{} is out of bounds for an array of length {}", start, end, elements.len());
+                }
+                elements.truncate(end as usize);
+                Value::Array(elements.split_off(start as usize))
+            },
+            (value, index) => panic!("Tried to index {:?} with {:?}", value, index),
+        };
+        self.stack.push(value);
+        self.advance();
+    }
+
+    /// For loops keep what they are iterating over in a variable. Ranges count up as they go, and
+    /// arrays become an iterator which steps through them.
+    fn has_next(&mut self, name: &str) {
+        let iterable = self.env.lookup(name).unwrap();
+        if let Value::Array(elements) = iterable {
+            *iterable = Value::Iterator(std::mem::take(elements), 0);
+        }
+        let has_next = match iterable {
+            Value::Iterator(elements, index) => *index < elements.len(),
+            Value::Range(Some(start), Some(end)) => start < end,
+            Value::Range(Some(_), None) => true,
+            value => panic!("Can't iterate over {:?}", value),
+        };
+        self.stack.push(Value::Bool(has_next));
+        self.advance();
+    }
+
+    fn next(&mut self, name: &str) {
+        let value = match self.env.lookup(name).unwrap() {
+            Value::Iterator(elements, index) => {
+                *index += 1;
+                // the iterator is the only thing holding the array, so elements are moved out of it
+                std::mem::replace(&mut elements[*index - 1], Value::Undefined)
+            },
+            Value::Range(Some(start), _) => {
+                *start += 1;
+                Value::Integer(*start - 1)
+            },
+            value => panic!("Can't iterate over {:?}", value),
+        };
+        self.stack.push(value);
+        self.advance();
+    }
+
+    fn make_struct_type(&mut self, fields: &[String]) {
+        let module = self.env.frames.last().unwrap().module;
+        self.stack.push(Value::StructType(module, fields.to_vec()));
+        self.advance();
+    }
+
+    /// Literals can list their fields in any order, but the struct keeps the order of its type
+    fn make_struct(&mut self, names: &[String]) {
+        let mut values: Vec<_> = names.iter()
+            .cloned()
+            .zip(self.stack.split_off(self.stack.len() - names.len()))
+            .collect();
+        let (module, fields) = match self.stack.pop().unwrap() {
+            Value::StructType(module, fields) => (module, fields),
+            value => panic!("Expected a struct type, but found {:?}", value),
+        };
+
+        if let Some((name, _)) = values.iter().find(|(name, _)| !fields.contains(name)) {
+            panic!("Struct has no field {}", name);
+        }
+        let value = Value::Struct(module, fields.into_iter().map(|field| {
+            match values.iter().position(|(name, _)| *name == field) {
+                Some(i) => values.swap_remove(i),
+                None => panic!("Missing field {} in struct literal", field),
+            }
+        }).collect());
+
+        self.stack.push(value);
+        self.advance();
+    }
+
+    fn get_field(&mut self, field: &str) {
+        let value = match self.stack.pop().unwrap() {
+            Value::Struct(_, mut fields) => match fields.iter().position(|(name, _)| name == field) {
+                Some(i) => fields.swap_remove(i).1,
+                None => panic!("Struct has no field {}", field),
+            },
+            Value::Module(module) => self.module_member(module, field),
+            // variants without a payload are written like fields of their enum, `Shape.Empty`
+            Value::EnumType(variants) => match variants.iter().find(|(name, _)| name == field) {
+                Some((_, 0)) => Value::Variant(field.to_owned(), vec![]),
+                Some((_, count)) => panic!("Variant {} holds {} value(s), but none were given", field, count),
+                None => panic!("Enum has no variant {}", field),
+            },
+            value => panic!("Tried to get field {} of {:?}, which isn't a struct", field, value),
+        };
+        self.stack.push(value);
+        self.advance();
+    }
+
+    fn make_enum_type(&mut self, variants: &[(String, usize)]) {
+        self.stack.push(Value::EnumType(variants.to_vec()));
+        self.advance();
+    }
+
+    fn is_variant(&mut self, variant: &str) {
+        let is = match self.stack.pop().unwrap() {
+            Value::Variant(name, _) => name == variant,
+            value => panic!("Expected an enum value, but found {:?}", value),
+        };
+        self.stack.push(Value::Bool(is));
+        self.advance();
+    }
+
+    fn get_payload(&mut self, index: &usize) {
+        let value = match self.stack.pop().unwrap() {
+            Value::Variant(_, mut payload) => payload.swap_remove(*index),
+            value => panic!("Expected an enum value, but found {:?}", value),
+        };
+        self.stack.push(value);
+        self.advance();
+    }
+
+    fn get_element(&mut self, index: &usize) {
+        let value = match self.stack.pop().unwrap() {
+            Value::Tuple(mut elements) => elements.swap_remove(*index),
+            value => panic!("Expected a tuple, but found {:?}", value),
+        };
+        self.stack.push(value);
+        self.advance();
+    }
+
+    fn set_field(&mut self, path: &[String]) {
+        let value = self.stack.pop().unwrap();
+        let mut target = self.stack.last_mut().unwrap();
+        for field in path {
+            target = match target {
+                Value::Struct(_, fields) => match fields.iter_mut().find(|(name, _)| name == field) {
+                    Some((_, value)) => value,
+                    None => panic!("Struct has no field {}", field),
+                },
+                value => panic!("Tried to set field {} of {:?}, which isn't a struct", field, value),
+            };
+        }
+        *target = value;
+        self.advance();
+    }
+
     fn allocate(&mut self, name: &str) {
         self.env.current_scope().insert(name.to_owned(), self.stack.pop().unwrap());
         self.advance();
     }
 
     fn push(&mut self, name: &str) {
-        self.stack.push(self.env.current_scope()[name].clone());
+        let value = self.env.get(name).unwrap_or_else(|| panic!("Variable {} is not defined", name));
+        self.stack.push(value);
         self.advance();
     }
 
     fn pop(&mut self, name: &str) {
-        *self.env.current_scope().get_mut(name).unwrap() = self.stack.pop().unwrap();
+        let value = self.stack.pop().unwrap();
+        if !self.env.set(name, value) {
+            panic!("Variable {} is not defined", name);
+        }
+        self.advance();
+    }
+
+    fn discard(&mut self) {
+        self.stack.pop().unwrap();
         self.advance();
     }
 
-    fn add(&mut self) {
+    fn drop(&mut self, count: &usize) {
+        self.stack.truncate(self.stack.len() - count);
+        self.advance();
+    }
+
+    /// Fits the result of integer arithmetic into the type it was checked to have. `exact` is None
+    /// when the result doesn't even fit in 128 bits, and then `negative` says which way it went.
+    fn fit(&self, exact: Option<i128>, wrapped: i128, negative: bool, int: &Option<IntType>, overflow: &Option<Overflow>) -> i128 {
+        let int = match (int, exact) {
+            (Some(int), _) => *int,
+            (None, Some(value)) => return value,
+            (None, None) => panic!("Integer overflow at {:?}", self.current),
+        };
+        let (min, max) = int.bounds();
+        match (exact, overflow.unwrap_or(self.overflow)) {
+            (Some(value), _) if min <= value && value <= max => value,
+            (_, Overflow::Wrap) => int.wrap(wrapped),
+            (_, Overflow::Saturate) => if exact.map_or(negative, |value| value < min) { min } else { max },
+            (_, Overflow::Trap) => panic!("Integer overflow in {:?} at {:?}", int, self.current),
+        }
+    }
+
+    fn add(&mut self, int: &Option<IntType>, overflow: &Option<Overflow>) {
         let v1 = self.stack.pop().unwrap();
         let v2 = self.stack.pop().unwrap();
         self.stack.push(match v1 {
             Value::Integer(i1) => if let Value::Integer(i2) = v2 {
-                Value::Integer(i1 + i2)
+                Value::Integer(self.fit(i2.checked_add(i1), i2.wrapping_add(i1), i2 < 0, int, overflow))
             } else {
                 panic!()
             },
@@ -153,17 +430,22 @@ impl<'i> Interpreter<'i> {
             } else {
                 panic!()
             },
+            Value::String(s1) => if let Value::String(s2) = v2 {
+                Value::String(s2 + &s1)
+            } else {
+                panic!()
+            },
             _ => panic!(),
         });
         self.advance();
     }
 
-    fn subtract(&mut self) {
+    fn subtract(&mut self, int: &Option<IntType>, overflow: &Option<Overflow>) {
         let v1 = self.stack.pop().unwrap();
         let v2 = self.stack.pop().unwrap();
         self.stack.push(match v1 {
             Value::Integer(i1) => if let Value::Integer(i2) = v2 {
-                Value::Integer(i2 - i1)
+                Value::Integer(self.fit(i2.checked_sub(i1), i2.wrapping_sub(i1), i2 < 0, int, overflow))
             } else {
                 panic!()
             },
@@ -177,12 +459,12 @@ impl<'i> Interpreter<'i> {
         self.advance();
     }
 
-    fn multiply(&mut self) {
+    fn multiply(&mut self, int: &Option<IntType>, overflow: &Option<Overflow>) {
         let v1 = self.stack.pop().unwrap();
         let v2 = self.stack.pop().unwrap();
         self.stack.push(match v1 {
             Value::Integer(i1) => if let Value::Integer(i2) = v2 {
-                Value::Integer(i1 * i2)
+                Value::Integer(self.fit(i2.checked_mul(i1), i2.wrapping_mul(i1), (i1 < 0) != (i2 < 0), int, overflow))
             } else {
                 panic!()
             },
@@ -215,17 +497,19 @@ impl<'i> Interpreter<'i> {
         self.advance();
     }
 
-    fn floor_divide(&mut self) {
+    /// Only dividing the smallest signed integer by -1 can overflow
+    fn floor_divide(&mut self, int: &Option<IntType>) {
         let v1 = self.stack.pop().unwrap();
         let v2 = self.stack.pop().unwrap();
         self.stack.push(match v1 {
+            Value::Integer(0) => panic!("Division by zero at {:?}", self.current),
             Value::Integer(i1) => if let Value::Integer(i2) = v2 {
-                Value::Integer(i2 / i1)
+                Value::Integer(self.fit(floor_div(i2, i1), i2.wrapping_div(i1), (i1 < 0) != (i2 < 0), int, &None))
             } else {
                 panic!()
             },
             Value::Float(f1) => if let Value::Float(f2) = v2 {
-                Value::Integer((f2 / f1).floor() as i128)
+                Value::Float((f2 / f1).floor())
             } else {
                 panic!()
             },
@@ -234,50 +518,69 @@ impl<'i> Interpreter<'i> {
         self.advance();
     }
 
-    fn negate(&mut self) {
+    fn negate(&mut self, int: &Option<IntType>) {
         let v1 = self.stack.pop().unwrap();
         self.stack.push(match v1 {
-            Value::Integer(i1) => Value::Integer(-i1),
+            Value::Integer(i1) => Value::Integer(self.fit(i1.checked_neg(), i1.wrapping_neg(), i1 > 0, int, &None)),
             Value::Float(f1) => Value::Float(-f1),
             _ => panic!(),
         });
         self.advance();
     }
 
+    /// Converting a float to an integer type cuts off its fraction. A number which still doesn't
+    /// fit in an integer type overflows like arithmetic does.
+    fn convert(&mut self, number: &NumberType) {
+        let value = self.stack.pop().unwrap();
+        self.stack.push(match (value, number) {
+            (Value::Integer(i), NumberType::Integer(int)) => {
+                Value::Integer(self.fit(Some(i), i, i < 0, &Some(*int), &None))
+            },
+            (Value::Float(f), NumberType::Integer(int)) => {
+                let truncated = f.trunc();
+                let exact = (truncated.abs() < 1e38).then_some(truncated as i128);
+                Value::Integer(self.fit(exact, truncated as i128, f < 0.0, &Some(*int), &None))
+            },
+            (Value::Integer(i), NumberType::Float { bits: 32 }) => Value::Float(i as f32 as f64),
+            (Value::Integer(i), NumberType::Float { .. }) => Value::Float(i as f64),
+            (Value::Float(f), NumberType::Float { bits: 32 }) => Value::Float(f as f32 as f64),
+            (Value::Float(f), NumberType::Float { .. }) => Value::Float(f),
+            (value, number) => panic!("Can't convert {:?} to {:?}", value, number),
+        });
+        self.advance();
+    }
+
+    fn not(&mut self) {
+        let v1 = self.stack.pop().unwrap();
+        self.stack.push(match v1 {
+            Value::Bool(b1) => Value::Bool(!b1),
+            _ => panic!(),
+        });
+        self.advance();
+    }
+
     fn test(&mut self, compare_type: &CompareType) {
         let v1 = self.stack.pop().unwrap();  
         let v2 = self.stack.pop().unwrap();  
 
         self.stack.push(match v1 {
             Value::Integer(i1) => if let Value::Integer(i2) = v2 {
-                Value::Bool(match compare_type {
-                    CompareType::EQ => i1 == i2,
-                    _ => unreachable!(),
-                })
+                Value::Bool(compare(compare_type, i2, i1))
             } else {
                 panic!()
             },
             Value::Float(f1) => if let Value::Float(f2) = v2 {
-                Value::Bool(match compare_type {
-                    CompareType::EQ => f1 == f2,
-                    _ => unreachable!(),
-                })
+                Value::Bool(compare(compare_type, f2, f1))
             } else {
                 panic!()
             },
             Value::Bool(b1) => if let Value::Bool(b2) = v2 {
-                Value::Bool(match compare_type {
-                    CompareType::EQ => b1 == b2,
-                    _ => unreachable!(),
-                })
+                Value::Bool(compare(compare_type, b2, b1))
             } else {
                 panic!()
             },
             Value::String(s1) => if let Value::String(s2) = v2 {
-                Value::Bool(match compare_type {
-                    CompareType::EQ => s1 == s2,
-                    _ => unreachable!(),
-                })
+                Value::Bool(compare(compare_type, s2, s1))
             } else {
                 panic!()
             },
@@ -286,20 +589,119 @@ impl<'i> Interpreter<'i> {
         self.advance();
     }
 
-    fn call(&mut self) {
+    fn enter_scope(&mut self) {
+        self.env.scopes.push(Scope::new());
+        self.advance();
+    }
+
+    fn exit_scope(&mut self, count: &usize) {
+        let len = self.env.scopes.len();
+        self.env.scopes.truncate(len - count);
+        self.advance();
+    }
+
+    /// Calls get a fresh frame, which the function's first block binds the arguments in
+    fn call(&mut self, args: &usize) {
+        match self.stack.pop().unwrap() {
+            Value::Function(func) => self.enter_function(func, vec![], args),
+            Value::Closure(func, captures) => self.enter_function(func, captures, args),
+            // natives run straight away, without a frame of their own
+            Value::Native(native) => {
+                if *args != native.params.len() {
+                    panic!("Function {} takes {} argument(s), but was called with {}", native.name, native.params.len(), args);
+                }
+                let values = self.stack.split_off(self.stack.len() - args);
+                self.stack.push(native.call(values));
+                self.advance();
+            },
+            func => panic!("Tried to call {:?}, which isn't a function", func),
+        }
+    }
+
+    /// Captured variables go in the same scope as the arguments, so arguments hide them
+    fn enter_function(&mut self, Function { id, args: arity, .. }: Function, captures: Vec<(String, Value)>, args: &usize) {
+        if *args != arity {
+            panic!("Function {} takes {} argument(s), but was called with {}", id, arity, args);
+        }
         self.call_stack.push(self.current);
-        let func = self.stack.pop().unwrap();
-        if let Value::Function(Function { id, .. }) = func {
-            self.current.function = id;
-            self.current.block = self.env.functions[&id].blocks.last().unwrap().id;
-            self.current.instruction = 0;
+        self.env.enter_frame(self.env.functions[&id].module);
+        self.env.current_scope().extend(captures);
+        self.current.function = id;
+        self.current.block = 0;
+        self.current.instruction = 0;
+        self.settle();
+    }
+
+    /// `a.b(c)` constructs a variant if `a` is an enum, calls the function in field `b` if `a` is
+    /// a struct that has one, and otherwise calls the function `b` with `a` as its first argument
+    fn call_member(&mut self, name: &str, args: &usize) {
+        let object_index = self.stack.len() - args - 1;
+        match &self.stack[object_index] {
+            Value::EnumType(variants) => {
+                match variants.iter().find(|(variant, _)| variant == name) {
+                    Some((_, arity)) if arity == args => {},
+                    Some((_, arity)) => panic!("Variant {} holds {} value(s), but {} were given", name, arity, args),
+                    None => panic!("Enum has no variant {}", name),
+                }
+                let payload = self.stack.split_off(object_index + 1);
+                self.stack.pop();
+                self.stack.push(Value::Variant(name.to_owned(), payload));
+                self.advance();
+            },
+            Value::Module(module) => {
+                let func = self.module_member(*module, name);
+                self.stack.remove(object_index);
+                self.stack.push(func);
+                self.call(args);
+            },
+            Value::Struct(_, fields) if fields.iter().any(|(field, _)| field == name) => {
+                let (_, func) = fields.iter().find(|(field, _)| field == name).unwrap();
+                let func = func.clone();
+                self.stack.remove(object_index);
+                self.stack.push(func);
+                self.call(args);
+            },
+            // a struct from another module finds its methods among that module's pub variables
+            Value::Struct(module, _) if *module != self.env.frames.last().unwrap().module => {
+                let func = self.module_member(*module, name);
+                self.stack.push(func);
+                self.call(&(args + 1));
+            },
+            _ => {
+                let func = self.env.get(name).unwrap_or_else(|| panic!("Variable {} is not defined", name));
+                self.stack.push(func);
+                self.call(&(args + 1));
+            },
+        }
+    }
+
+    fn module_member(&self, module: usize, name: &str) -> Value {
+        let module = &self.env.modules[module];
+        if !module.public.contains(name) {
+            panic!("{} is not public in module {}", name, module.name);
         }
+        module.scope.get(name)
+            .unwrap_or_else(|| panic!("{} hasn't been declared in module {} yet", name, module.name))
+            .clone()
+    }
+
+    fn get_module(&mut self, module: &usize) {
+        self.stack.push(Value::Module(*module));
+        self.advance();
+    }
+
+    /// The top level of each module runs in turn, declaring its variables in its own scope
+    fn enter_module(&mut self, module: &usize) {
+        self.env.frames.last_mut().unwrap().module = *module;
+        self.advance();
     }
 
     fn return_(&mut self) {
-        if self.call_stack.len() > 0 {
-            let ret_location = self.call_stack.pop().unwrap();
+        if let Some(ret_location) = self.call_stack.pop() {
+            self.env.exit_frame();
+            // carry on after the call instruction
             self.current = ret_location;
+            self.advance();
         } else {
             self.finished = true;
         }
@@ -307,21 +709,631 @@ impl<'i> Interpreter<'i> {
 
     fn branch_if(&mut self, then_block: &usize, else_block: &usize) {
         match self.stack.pop().unwrap() {
-            Value::Bool(true) => self.current.block = *then_block,
-            Value::Bool(false) => self.current.block = *else_block,
+            Value::Bool(true) => self.jump(then_block),
+            Value::Bool(false) => self.jump(else_block),
             _ => panic!(),
         };
-        self.current.instruction = 0;
     }
 
     fn jump(&mut self, block: &usize) {
-        self.current.block = *block;
+        self.current.block = self.block_index(*block);
         self.current.instruction = 0;
+        self.settle();
     }
 
     fn get_function(&mut self, func: &usize) {
         self.stack.push(Value::Function(self.env.functions[func].clone()));
         self.advance();
     }
+
+    /// Closures share the local variables they use with the scope they were made in, so a change
+    /// made to one of those variables on either side is seen by the other
+    fn make_closure(&mut self, names: &[String]) {
+        let func = match self.stack.pop().unwrap() {
+            Value::Function(func) => func,
+            value => panic!("Expected a function to make a closure from, but found {:?}", value),
+        };
+        let captures: Vec<_> = names.iter()
+            .filter_map(|name| self.env.share(name).map(|shared| (name.clone(), shared)))
+            .collect();
+        self.stack.push(if captures.is_empty() {
+            Value::Function(func)
+        } else {
+            Value::Closure(func, captures)
+        });
+        self.advance();
+    }
+}
+
+/// Integer division rounds down, like float division does, rather than toward zero
+fn floor_div(left: i128, right: i128) -> Option<i128> {
+    let quotient = left.checked_div(right)?;
+    Some(if left % right != 0 && (left < 0) != (right < 0) { quotient - 1 } else { quotient })
+}
+
+fn compare<T: PartialOrd>(compare_type: &CompareType, left: T, right: T) -> bool {
+    match compare_type {
+        CompareType::EQ => left == right,
+        CompareType::NE => left != right,
+        CompareType::LT => left < right,
+        CompareType::GT => left > right,
+        CompareType::LE => left <= right,
+        CompareType::GE => left >= right,
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::cell::{
+        Cell,
+        RefCell,
+    };
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        errors::{
+            Error,
+            Errors,
+        },
+        ir::IRGenerator,
+        lexer::Lexer,
+        modules::{
+            tests::project,
+            ModuleLoader,
+        },
+        natives::{
+            self,
+            Registry,
+        },
+        parser::Parser,
+        typeck::TypeChecker,
+    };
+
+    fn run(contents: &'static str) -> Vec<Value> {
+        let errors = RefCell::new(Errors::new());
+        let ast = Parser::new(Lexer::new(contents, errors.borrow_mut())).go().unwrap();
+        let mut ir_generator = IRGenerator::new(&ast, errors.borrow_mut());
+        ir_generator.go();
+        let mut interpreter = Interpreter::new(&mut ir_generator.env, 0);
+        interpreter.go();
+        interpreter.stack
+    }
+
+    /// Runs a program after type checking it, so its integer arithmetic is done in fixed widths
+    fn run_checked(contents: &'static str, overflow: Overflow) -> Vec<Value> {
+        let errors = RefCell::new(Errors::new());
+        let ast = Parser::new(Lexer::new(contents, errors.borrow_mut())).go().unwrap();
+        let annotations = TypeChecker::new(&ast, errors.borrow_mut()).go();
+        assert!(errors.borrow().errors.is_empty(), "{:?}", errors.borrow().errors);
+        let mut ir_generator = IRGenerator::new(&ast, errors.borrow_mut()).with_annotations(annotations);
+        ir_generator.go();
+        let mut interpreter = Interpreter::new(&mut ir_generator.env, 0);
+        interpreter.overflow = overflow;
+        interpreter.go();
+        interpreter.stack
+    }
+
+    #[test]
+    fn while_loop() {
+        let stack = run("main := fn() n64 {
+            i := 0
+            total := 0
+            while i < 10 {
+                i = i + 1
+                total = total + i
+            }
+            total
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(55))));
+    }
+
+    #[test]
+    fn nested_while_loops() {
+        let stack = run("main := fn() n64 {
+            count := 0
+            i := 0
+            while i < 4 {
+                j := 0
+                while j < i and j < 2 {
+                    count = count + 1
+                    j = j + 1
+                }
+                i = i + 1
+            }
+            count
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(5))));
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let stack = run("main := fn() n64 {
+            i := 0
+            odd_total := 0
+            while true {
+                i = i + 1
+                if i > 9 {
+                    break
+                }
+                if i // 2 * 2 == i {
+                    continue
+                }
+                odd_total = odd_total + i
+            }
+            odd_total
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(25))));
+    }
+
+    #[test]
+    fn labeled_loop_with_value() {
+        let stack = run("main := fn() n64 {
+            i := 0
+            found := outer: loop {
+                j := 0
+                while j < 10 {
+                    if i * j == 12 {
+                        break :outer 100 * i + j
+                    }
+                    j = j + 1
+                }
+                i = i + 1
+            }
+            found
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(206))));
+        // nothing should be left behind by the nested loops
+        assert_eq!(stack.len(), 2);
+    }
+
+    #[test]
+    fn break_unwinds_partial_expressions() {
+        let stack = run("main := fn() n64 {
+            x := loop {
+                1 + 2 * (if true { break 7 } else { 3 })
+            }
+            x
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(7))));
+        assert_eq!(stack.len(), 2);
+    }
+
+    #[test]
+    fn early_return() {
+        let stack = run("first_multiple := fn() n64 {
+            i := 1
+            while true {
+                if i * 7 > 30 {
+                    return 1 + i * 7
+                }
+                i = i + 1
+            }
+            0
+        }
+
+        main := fn() n64 {
+            10 + first_multiple()
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(46))));
+    }
+
+    #[test]
+    fn arguments() {
+        let stack = run("factorial := fn(n: n64) n64 {
+            if n == 0 {
+                1
+            } else {
+                n * factorial(n - 1)
+            }
+        }
+
+        sub := fn(a: n64, b: n64) n64 { a - b }
+
+        main := fn() n64 {
+            sub(factorial(5), 20)
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(100))));
+        assert_eq!(stack.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "takes 2 argument(s), but was called with 1")]
+    fn wrong_arity() {
+        run("sub := fn(a: n64, b: n64) n64 { a - b }
+        main := fn() n64 { sub(1) }");
+    }
+
+    #[test]
+    fn block_scopes() {
+        let stack = run("x := 1
+        main := fn() n64 {
+            y := 10
+            if true {
+                x := 100
+                y = y + x
+            }
+            a := 0
+            while a < 3 {
+                z := a
+                a = z + 1
+            }
+            x + y + a
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(114))));
+    }
+
+    #[test]
+    fn recursion() {
+        let stack = run("fibonacci := fn(n: n64) n64 {
+            if n < 2 {
+                1
+            } else {
+                a := fibonacci(n - 2)
+                b := fibonacci(n - 1)
+                a + b
+            }
+        }
+
+        main := fn() n64 {
+            fibonacci(10)
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(89))));
+    }
+
+    #[test]
+    fn multiple_return_values() {
+        let stack = run("divmod := fn(a: n64, b: n64) (n64, n64) {
+            (a // b, a - a // b * b)
+        }
+
+        main := fn() n64 {
+            q, r := divmod(47, 10)
+            q * 100 + r
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(407))));
+    }
+
+    #[test]
+    fn structs() {
+        let stack = run("Point := struct { x: n64, y: n64 }
+        Line := struct {
+            start: Point
+            end: Point
+        }
+
+        main := fn() n64 {
+            p := Point{ .y = 2, .x = 1 }
+            line := Line{
+                .start = p,
+                .end = Point{ .x = 10, .y = 20 },
+            }
+            p.x = 5
+            line.end.y = line.end.y + p.x
+            line.start.x * 1000 + line.end.y
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(1025))));
+    }
+
+    #[test]
+    fn match_expressions() {
+        let stack = run("Shape := enum {
+            Circle(n64)
+            Rect(n64, n64)
+            Empty
+        }
+
+        area := fn(shape: Shape) n64 {
+            match shape {
+                Shape.Circle(r) => 3 * r * r
+                Shape.Rect(w, h) if w == h => 1000 + w * h
+                Shape.Rect(w, h) => w * h
+                Shape.Empty => 0
+            }
+        }
+
+        classify := fn(a: n64, b: bool) n64 {
+            match (a, b) {
+                (0, _) => 1
+                (n, true) if n > 10 => 2
+                (_, true) => 3
+                _ => 4
+            }
+        }
+
+        main := fn() n64 {
+            total := area(Shape.Circle(2)) + area(Shape.Rect(2, 3)) + area(Shape.Rect(3, 3))
+            total = total + area(Shape.Empty)
+            total * 10000 + classify(0, false) * 1000 + classify(11, true) * 100 + classify(5, true) * 10 + classify(5, false)
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(10271234))));
+    }
+
+    #[test]
+    fn arrays() {
+        let stack = run("second := fn(a: []n64) n64 { a[1] }
+
+        main := fn() n64 {
+            a: [5]n64 = [1, 2, 3, 4, 5]
+            second(a[1..3]) * 100 + a[..2][0] * 10 + a[3..][1]
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(315))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Index 3 is out of bounds for an array of length 3")]
+    fn out_of_bounds() {
+        run("main := fn() n64 { [1, 2, 3][3] }");
+    }
+
+    #[test]
+    fn for_loops() {
+        let stack = run("main := fn() n64 {
+            total := 0
+            for i in 0..10 {
+                if i == 2 { continue }
+                total = total + i
+            }
+            for x in [100, 200, 300] {
+                total = total + x
+            }
+            outer: for i in 1.. {
+                for j in [1, 2, 3] {
+                    if i * j == 6 { break :outer }
+                }
+            }
+            total
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(643))));
+    }
+
+    #[test]
+    fn member_calls() {
+        let stack = run("Point := struct { x: n64, y: n64 }
+
+        double := fn(n: n64) n64 { n * 2 }
+        sum := fn(p: Point, z: n64) n64 { p.x + p.y + z }
+
+        main := fn() n64 {
+            p := Point{ .x = 1, .y = 2 }
+            functions := [double, double]
+            p.sum(10) * 100 + functions[1](3) + (double)(1)
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(1308))));
+    }
+
+    #[test]
+    fn closures() {
+        let stack = run("main := fn() n64 {
+            k := 3
+            triple := fn(x: n64) n64 { x * k }
+            k = 100
+            total := 0
+            for f in [triple, fn(x: n64) n64 { x + k }] {
+                total = total + f(2)
+            }
+            nested := fn() n64 {
+                inner := fn(k: n64) n64 { k + 1 }
+                inner(k) + inner(0)
+            }
+            total * 1000 + nested()
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(302102))));
+    }
+
+    #[test]
+    fn closures_share_variables() {
+        let stack = run_checked("main := fn() (n64, n64, n64) {
+            total: n64 = 0
+            counter := fn() n64 {
+                total = total + 1
+                total
+            }
+            counter()
+            counter()
+            fact := fn(n: n64) n64 {
+                if n == 0 { 1 } else { n * fact(n - 1) }
+            }
+            (counter(), total, fact(5))
+        }", Overflow::Trap);
+        assert!(matches!(stack.last(), Some(Value::Tuple(values))
+            if matches!(values.as_slice(), [Value::Integer(3), Value::Integer(3), Value::Integer(120)])));
+    }
+
+    #[test]
+    fn higher_order_functions() {
+        let stack = run("apply := fn(f: fn(n64) n64, x: n64) n64 { f(x) }
+        compose := fn(f: fn(n64) n64, g: fn(n64) n64) fn(n64) n64 {
+            fn(x: n64) n64 { g(f(x)) }
+        }
+        double := fn(x: n64) n64 { x * 2 }
+
+        main := fn() n64 {
+            k := 5
+            functions: []fn(n64) n64 = [double, fn(x: n64) n64 { x + k }]
+            apply(compose(functions[0], functions[1]), 10)
+        }");
+        assert!(matches!(stack.last(), Some(Value::Integer(25))));
+    }
+
+    #[test]
+    fn modules() {
+        let dir = project("interpreter", &[
+            ("main.meg", "use geometry.shapes
+            scale := 3
+            main := fn() n64 {
+                shapes.area(shapes.unit) * shapes.count() * scale
+            }"),
+            ("geometry/shapes.meg", "pub Square := struct { side: n64 }
+            pub unit := Square{ .side = 2 }
+            scale := 10
+            pub area := fn(s: Square) n64 { s.side * s.side * scale }
+            pub count := fn() n64 { 7 }"),
+        ]);
+
+        let errors = RefCell::new(Errors::new());
+        let modules = ModuleLoader::new(vec![], &errors).load(&dir.join("main.meg"));
+        let root = modules.last().unwrap();
+        let mut ir_generator = IRGenerator::with_modules(&root.ast, &modules, errors.borrow_mut());
+        ir_generator.go();
+        let mut interpreter = Interpreter::new(&mut ir_generator.env, 0);
+        interpreter.go();
+        assert!(matches!(interpreter.stack.last(), Some(Value::Integer(840))));
+    }
+
+    #[test]
+    fn native_functions() {
+        let calls = Rc::new(Cell::new(0));
+        let mut registry = Registry::new();
+        let counter = Rc::clone(&calls);
+        registry.register("count", &[natives::N64], natives::N64, move |args| {
+            counter.set(counter.get() + 1);
+            match args[0] {
+                Value::Integer(n) => Value::Integer(n * 10),
+                _ => panic!(),
+            }
+        });
+
+        let errors = RefCell::new(Errors::new());
+        let ast = Parser::new(Lexer::new("main := fn() n64 {
+            count(len(\"abc\")) + count(len(to_string(sqrt(16.0))))
+        }", errors.borrow_mut())).go().unwrap();
+        let mut ir_generator = IRGenerator::new(&ast, errors.borrow_mut());
+        ir_generator.go();
+        registry.declare(&mut ir_generator.env.scopes[0]);
+        let mut interpreter = Interpreter::new(&mut ir_generator.env, 0);
+        interpreter.go();
+        assert!(matches!(interpreter.stack.last(), Some(Value::Integer(40))));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn core_library() {
+        let file = std::env::temp_dir().join(format!("meg-interpreter-core-{}.txt", std::process::id()));
+        let dir = project("interpreter-core", &[("main.meg", &format!(r#"use core.io
+        use core.math
+        use core.string
+        use core.collections
+        main := fn() (n64, i64) {{
+            write_file("{}", string.join(["a", "b", "c"], "\t"))
+            text := read_file("{}")
+            numbers := collections.reverse(collections.push([1, 2], 3))
+            numbers = collections.filter(collections.map(numbers, fn(n: n64) n64 {{ n * 2 }}), fn(n: n64) bool {{ n > 2 }})
+            (string.length(text) * 1000 + numbers[0] + collections.sum(numbers), math.pow(2, 5))
+        }}"#, file.display(), file.display()))]);
+
+        let errors = RefCell::new(Errors::new());
+        let search_path = vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("lib")];
+        let modules = ModuleLoader::new(search_path, &errors).load(&dir.join("main.meg"));
+        let root = modules.last().unwrap();
+        let mut ir_generator = IRGenerator::with_modules(&root.ast, &modules, errors.borrow_mut());
+        ir_generator.go();
+        let mut interpreter = Interpreter::new(&mut ir_generator.env, 0);
+        interpreter.go();
+        assert!(matches!(interpreter.stack.last(), Some(Value::Tuple(values))
+            if matches!(values.as_slice(), [Value::Integer(5016), Value::Integer(32)])));
+    }
+
+    #[test]
+    fn private_members() {
+        let dir = project("interpreter-private", &[
+            ("main.meg", "use secrets
+            main := fn() n64 { secrets.hidden + secrets.missing }"),
+            ("secrets.meg", "hidden := 1"),
+        ]);
+
+        let errors = RefCell::new(Errors::new());
+        let modules = ModuleLoader::new(vec![], &errors).load(&dir.join("main.meg"));
+        let root = modules.last().unwrap();
+        IRGenerator::with_modules(&root.ast, &modules, errors.borrow_mut()).go();
+        assert_eq!(errors.borrow().errors, vec![
+            Error::IR { message: "hidden is not public in module secrets".to_owned(), position: 50 },
+            Error::IR { message: "Module secrets has nothing called missing".to_owned(), position: 67 },
+        ]);
+    }
+
+    #[test]
+    fn fixed_width_integers() {
+        let stack = run_checked("main := fn() (n8, n8, n8, i8, n8, i16) {
+            a: n8 = 250
+            b: n8 = 10
+            c: i8 = -100
+            x: i16 = 300
+            (a + b, a +% b, a +| b, c -| 100, b -% 11, x *% x)
+        }", Overflow::Wrap);
+        assert!(matches!(stack.last(), Some(Value::Tuple(values)) if matches!(values.as_slice(), [
+            Value::Integer(4),
+            Value::Integer(4),
+            Value::Integer(255),
+            Value::Integer(-128),
+            Value::Integer(255),
+            Value::Integer(24464),
+        ])));
+    }
+
+    #[test]
+    #[should_panic(expected = "Integer overflow in n8")]
+    fn overflow_traps() {
+        run_checked("main := fn() n8 {
+            a: n8 = 200
+            a + 100
+        }", Overflow::Trap);
+    }
+
+    #[test]
+    #[should_panic(expected = "Integer overflow in i32")]
+    fn checked_operators_trap_when_wrapping() {
+        run_checked("main := fn() i32 {
+            a: i32 = 2147483647
+            a +% 1 -% 1 +? 1
+        }", Overflow::Wrap);
+    }
+
+    #[test]
+    fn numeric_conversions() {
+        let stack = run_checked("half := fn(x: f64) f64 { x / 2.0 }
+        main := fn() (f64, i32, n8, f64, f64, i64) {
+            a: i32 = 7
+            b: f64 = 2.5
+            c: n16 = 1000
+            wide: i64 = c
+            (a + b, i32(b * 3.0), n8(c), half(a), f64(a) / 4.0, wide)
+        }", Overflow::Wrap);
+        assert_eq!(
+            format!("{:?}", stack.last().unwrap()),
+            "Tuple([Float(9.5), Integer(7), Integer(232), Float(3.5), Float(1.75), Integer(1000)])",
+        );
+    }
+
+    #[test]
+    fn integer_floor_division() {
+        let stack = run_checked("main := fn() (i32, i32, i32, i32, n8) {
+            a: i32 = -7
+            b: n8 = 7
+            (a // 2, 7 // -2, -8 // 2, 7 // 2, b // 2)
+        }", Overflow::Trap);
+        assert_eq!(
+            format!("{:?}", stack.last().unwrap()),
+            "Tuple([Integer(-4), Integer(-4), Integer(-4), Integer(3), Integer(3)])",
+        );
+    }
+
+    #[test]
+    fn float_literals_and_floor_division() {
+        let stack = run_checked("main := fn() (f64, f64, f64) {
+            a: i32 = 7
+            b: f64 = -7.5
+            (a + 0.5, 7.5 // 2.0, b // 2.0)
+        }", Overflow::Trap);
+        assert_eq!(format!("{:?}", stack.last().unwrap()), "Tuple([Float(7.5), Float(3.0), Float(-4.0)])");
+    }
+
+    #[test]
+    #[should_panic(expected = "Integer overflow in n8")]
+    fn narrowing_conversions_trap() {
+        run_checked("main := fn() n8 {
+            n8(300.5)
+        }", Overflow::Trap);
+    }
+}
@@ -1,14 +1,29 @@
 //! This module converts the AST of a Meg program into Meg IR.
 
-use std::cell::RefMut;
-use std::collections::HashMap;
+use std::cell::{
+    RefCell,
+    RefMut,
+};
+use std::collections::{
+    HashMap,
+    HashSet,
+};
 use std::fmt;
+use std::rc::Rc;
 
 use crate::{
     errors::Errors,
+    exhaustiveness::{
+        self,
+        Enums,
+    },
+    modules::ParsedModule,
+    natives,
     parser::{
+        MatchArm,
         Node,
         NodeContext,
+        Pattern,
         Type,
     },
 };
@@ -23,31 +38,166 @@ pub enum CompareType {
     GE,
 }
 
+/// The width and signedness of an integer type, which integer arithmetic is done in
+#[derive(Clone, Copy, PartialEq)]
+pub struct IntType {
+    pub signed: bool,
+    pub bits: u8,
+}
+
+impl IntType {
+    /// The smallest and largest values of the type
+    pub fn bounds(self) -> (i128, i128) {
+        if self.signed {
+            (-(1 << (self.bits - 1)), (1 << (self.bits - 1)) - 1)
+        } else {
+            (0, (1 << self.bits) - 1)
+        }
+    }
+
+    /// Wraps a value around into the range of the type, keeping only its lowest bits the way two's
+    /// complement arithmetic does
+    pub fn wrap(self, value: i128) -> i128 {
+        let low = value & ((1 << self.bits) - 1);
+        if self.signed && low >= 1 << (self.bits - 1) {
+            low - (1 << self.bits)
+        } else {
+            low
+        }
+    }
+}
+
+impl fmt::Debug for IntType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", if self.signed { "i" } else { "n" }, self.bits)
+    }
+}
+
+/// The types numbers can be converted between
+#[derive(Clone, Copy, PartialEq)]
+pub enum NumberType {
+    Integer(IntType),
+    Float {
+        bits: u8,
+    },
+}
+
+impl NumberType {
+    /// The number type a builtin type name stands for, so calling it converts to that type
+    pub fn from_name(name: &str) -> Option<NumberType> {
+        let (signed, bits) = match name {
+            "n8" => (false, 8),
+            "n16" => (false, 16),
+            "n32" => (false, 32),
+            "n64" => (false, 64),
+            "i8" => (true, 8),
+            "i16" => (true, 16),
+            "i32" => (true, 32),
+            "i64" => (true, 64),
+            "f32" => return Some(NumberType::Float { bits: 32 }),
+            "f64" => return Some(NumberType::Float { bits: 64 }),
+            _ => return None,
+        };
+        Some(NumberType::Integer(IntType { signed, bits }))
+    }
+}
+
+impl fmt::Debug for NumberType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumberType::Integer(int) => write!(f, "{:?}", int),
+            NumberType::Float { bits } => write!(f, "f{}", bits),
+        }
+    }
+}
+
+/// The types of values which compiled code keeps in registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalarType {
+    Bool,
+    Number(NumberType),
+}
+
+/// The parameter and return types of a function, for functions which only take and return
+/// scalars. Only these functions can be compiled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<ScalarType>,
+    pub rets: Vec<ScalarType>, // empty for functions returning void
+}
+
+/// What the type checker found out that the IR generator needs to know. Nodes are known by their
+/// address, since the AST outlives both passes.
+#[derive(Default)]
+pub struct TypeAnnotations {
+    pub integers: HashMap<*const NodeContext, IntType>, // the type each arithmetic operator works in
+    pub conversions: HashMap<*const NodeContext, NumberType>, // integers used where floats are expected
+    pub signatures: HashMap<*const NodeContext, Signature>, // the types of function expressions
+}
+
+/// What integer arithmetic does with a result that doesn't fit in its type. Plain operators leave
+/// it to the interpreter, which traps unless Meg is run with `--release`, where it wraps. `+%`
+/// always wraps, `+|` always saturates and `+?` always traps, and likewise for `-` and `*`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    Wrap,
+    Saturate,
+    Trap,
+}
+
 #[derive(Debug, Clone)]
 pub enum InstructionKind {
     ConstBool(bool),
     ConstInt(i128),
     ConstFloat(f64),
     ConstString(String),
+    ConstUndefined,
+    MakeTuple(usize),
+    Unpack(usize),
+    MakeArray(usize),
+    MakeRange,
+    Index,
+    HasNext(String),
+    Next(String),
+    MakeStructType(Vec<String>),
+    MakeStruct(Vec<String>),
+    GetField(String),
+    SetField(Vec<String>),
+    MakeEnumType(Vec<(String, usize)>),
+    IsVariant(String),
+    GetPayload(usize),
+    GetElement(usize),
 
     Allocate(String),
     Push(String),
     Pop(String),
+    Discard,
+    Drop(usize),
+    EnterScope,
+    ExitScope(usize),
 
-    Add,
-    Subtract,
-    Multiply,
+    // integer arithmetic is only sized when the type checker knew its type
+    Add(Option<IntType>, Option<Overflow>),
+    Subtract(Option<IntType>, Option<Overflow>),
+    Multiply(Option<IntType>, Option<Overflow>),
     ExactDivide,
-    FloorDivide,
-    Negate,
+    FloorDivide(Option<IntType>),
+    Negate(Option<IntType>),
+    Not,
+    Convert(NumberType),
     Test(CompareType),
 
-    Call,
+    Call(usize),
+    CallMember(String, usize),
     Return,
     BranchIf(usize, usize),
     Jump(usize),
+    Unreachable,
 
     GetFunction(usize),
+    MakeClosure(Vec<String>), // the variables the function might need from where it was made
+    GetModule(usize),
+    EnterModule(usize),
 }
 
 #[derive(Clone)]
@@ -81,14 +231,16 @@ impl fmt::Debug for BasicBlock {
 #[derive(Clone)]
 pub struct Function {
     pub id: usize,
+    pub module: usize, // the module whose variables the function can see
     pub args: usize,
     pub retvals: usize,
+    pub signature: Option<Signature>, // only known for functions of scalars
     pub blocks: Vec<BasicBlock>,
 }
 
 impl fmt::Debug for Function {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "function {} (args: {}, retvals: {})", self.id, self.args, self.retvals)?;
+        writeln!(f, "function {} (module: {}, args: {}, retvals: {})", self.id, self.module, self.args, self.retvals)?;
         for block in &self.blocks {
             writeln!(f, "{:?}", block)?;
         }
@@ -102,51 +254,218 @@ pub enum Value {
     Integer(i128),
     Float(f64),
     String(String),
+    Undefined,
+    Tuple(Vec<Value>),
+    Array(Vec<Value>),
+    Range(Option<i128>, Option<i128>), // half-open, and either end can be left out
+    Iterator(Vec<Value>, usize), // an array a for loop is going through, and the index of its next element
+    // structs remember the module their type was declared in, which is where their methods are
+    StructType(usize, Vec<String>),
+    Struct(usize, Vec<(String, Value)>),
+    EnumType(Vec<(String, usize)>),
+    Variant(String, Vec<Value>),
 
     Function(Function),
+    Closure(Function, Vec<(String, Value)>),
+    Native(natives::Native),
+    Module(usize),
+    Shared(Shared),
+}
+
+/// A local variable which closures have captured, so that they and the scope it was declared in
+/// all see the same value. Only variables are ever shared, never values on the stack.
+#[derive(Clone)]
+pub struct Shared(Rc<RefCell<Value>>);
+
+impl fmt::Debug for Shared {
+    // a recursive closure holds the variable it is in, so its value can't be printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "shared variable")
+    }
 }
 
 pub type Scope = HashMap<String, Value>;
 
+/// The top level variables of a module, which other modules can only see if they are `pub`
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub scope: Scope,
+    pub public: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub start: usize, // where the scopes of the call start
+    pub module: usize,
+}
+
 #[derive(Debug)]
 pub struct Environment {
-    pub scopes: Vec<Scope>,
+    pub scopes: Vec<Scope>, // the global scope comes first
+    pub frames: Vec<Frame>,
+    pub modules: Vec<Module>,
     pub functions: HashMap<usize, Function>, // TODO this could probably be optimized down to a Vec??
 }
 
 impl Environment {
+    /// The innermost scope of the current call, which at the top level of a module is the
+    /// module's own scope
     pub fn current_scope(&mut self) -> &mut Scope {
-        self.scopes.last_mut().unwrap()
+        match self.frames.last() {
+            Some(frame) if frame.start == self.scopes.len() => &mut self.modules[frame.module].scope,
+            _ => self.scopes.last_mut().unwrap(),
+        }
+    }
+
+    /// Finds a variable in the innermost scope of the current call that has it, falling back to
+    /// the current module and then the global scope. Functions can't see the variables of whoever
+    /// called them.
+    pub fn lookup(&mut self, name: &str) -> Option<&mut Value> {
+        let frame = self.frames.last().copied().unwrap_or(Frame { start: 0, module: 0 });
+        match (frame.start..self.scopes.len()).rev().find(|&i| self.scopes[i].contains_key(name)) {
+            Some(i) => self.scopes[i].get_mut(name),
+            None if self.modules[frame.module].scope.contains_key(name) => {
+                self.modules[frame.module].scope.get_mut(name)
+            },
+            None => self.scopes[0].get_mut(name),
+        }
+    }
+
+    /// The value of a variable, from inside its cell if closures share it
+    pub fn get(&mut self, name: &str) -> Option<Value> {
+        self.lookup(name).map(|value| match value {
+            Value::Shared(shared) => shared.0.borrow().clone(),
+            value => value.clone(),
+        })
+    }
+
+    /// Changes the value of a variable, returning false if there is no such variable
+    pub fn set(&mut self, name: &str, new: Value) -> bool {
+        match self.lookup(name) {
+            Some(Value::Shared(shared)) => *shared.0.borrow_mut() = new,
+            Some(value) => *value = new,
+            None => return false,
+        }
+        true
+    }
+
+    /// Moves a variable from the scopes of the current call into a cell that closures can share,
+    /// unless it's already in one. Variables in the module or the global scope are never shared.
+    pub fn share(&mut self, name: &str) -> Option<Value> {
+        let frame = self.frames.last().copied().unwrap_or(Frame { start: 0, module: 0 });
+        let value = self.scopes[frame.start..].iter_mut().rev().find_map(|scope| scope.get_mut(name))?;
+        if !matches!(value, Value::Shared(_)) {
+            let unshared = std::mem::replace(value, Value::Undefined);
+            *value = Value::Shared(Shared(Rc::new(RefCell::new(unshared))));
+        }
+        Some(value.clone())
+    }
+
+    /// Function calls get a fresh scope for their arguments
+    pub fn enter_frame(&mut self, module: usize) {
+        self.frames.push(Frame { start: self.scopes.len(), module });
+        self.scopes.push(Scope::new());
+    }
+
+    /// The top level of a module declares its variables straight into the module
+    pub fn enter_module(&mut self, module: usize) {
+        self.frames.push(Frame { start: self.scopes.len(), module });
+    }
+
+    pub fn exit_frame(&mut self) {
+        let frame = self.frames.pop().unwrap();
+        self.scopes.truncate(frame.start);
     }
 }
 
+/// A loop which is currently being generated, for break and continue to jump out of
+struct Loop {
+    label: Option<String>,
+    continue_block_id: usize,
+    break_block_id: usize,
+    depth: usize, // the stack depth just before the loop started
+    scopes: usize, // how many block scopes were open just before the loop started
+    infinite: bool, // only infinite loops can break with a value
+}
+
 pub struct IRGenerator<'i> {
     ast: &'i NodeContext,
+    modules: &'i [ParsedModule], // the program's modules, ending with the one `ast` comes from
     pub errors: RefMut<'i, Errors>,
     pub env: Environment,
     next_func_id: usize,
     next_block_id: usize,
+    depth: usize, // how many values the current function has on the stack at this point
+    loops: Vec<Loop>,
+    scopes: usize, // how many block scopes the current function has open at this point
+    enums: Vec<Enums>, // the enums each module declares, for checking match expressions
+    module: usize, // the module being generated
+    aliases: HashMap<String, usize>, // the modules the current module has used, by name
+    in_function: bool, // whether the code being generated belongs to a function expression
+    annotations: TypeAnnotations,
 }
 
 impl<'i> IRGenerator<'i> {
+    /// Generates IR for a program which is just one module
+    #[cfg(test)]
     pub fn new(ast: &'i NodeContext, errors: RefMut<'i, Errors>) -> Self {
+        Self::with_modules(ast, &[], errors)
+    }
+
+    /// Generates IR for a whole program, where modules are in the order the module loader gives
+    /// them, so the module `ast` comes from is last
+    pub fn with_modules(ast: &'i NodeContext, modules: &'i [ParsedModule], errors: RefMut<'i, Errors>) -> Self {
+        let asts: Vec<_> = modules.iter()
+            .map(|module| (module.name.clone(), &module.ast))
+            .chain(modules.is_empty().then(|| (String::new(), ast)))
+            .collect();
+
         IRGenerator {
             ast,
+            modules,
             errors,
             env: Environment {
                 scopes: vec![new_global_scope()],
+                frames: vec![],
+                modules: asts.iter().map(|(name, ast)| Module {
+                    name: name.clone(),
+                    scope: Scope::new(),
+                    public: top_level_declarations(ast)
+                        .filter(|(_, public)| *public)
+                        .map(|(name, _)| name.clone())
+                        .collect(),
+                }).collect(),
                 functions: HashMap::new(),
             },
             next_func_id: 0,
             next_block_id: 0,
+            depth: 0,
+            loops: vec![],
+            scopes: 0,
+            enums: asts.iter().map(|(_, ast)| top_level_enums(ast)).collect(),
+            module: modules.len().saturating_sub(1),
+            aliases: HashMap::new(),
+            in_function: false,
+            annotations: TypeAnnotations::default(),
         }
     }
 
+    /// Without what the type checker found, integer arithmetic isn't done in any fixed width and
+    /// integers are never converted to floats implicitly
+    pub fn with_annotations(mut self, annotations: TypeAnnotations) -> Self {
+        self.annotations = annotations;
+        self
+    }
+
     pub fn go(&mut self) -> &Environment {
+        let root = self.module;
         let mut top_level_fn = Function {
             id: self.get_next_func_id(),
+            module: root,
             args: 0,
             retvals: 0,
+            signature: None,
             blocks: vec![
                 BasicBlock {
                     id: self.get_next_block_id(),
@@ -159,21 +478,33 @@ impl<'i> IRGenerator<'i> {
             ],
         };
 
-        self.node(&mut top_level_fn, self.ast);
-
-        top_level_fn.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::Push("main".to_owned()),
-                constant: false,
+        // every module runs its top level before the module that uses it, and throws away its value
+        let modules = self.modules;
+        for (id, module) in modules[..root].iter().enumerate() {
+            self.module = id;
+            self.aliases.clear();
+            self.emit(&mut top_level_fn, InstructionKind::EnterModule(id), false);
+            match &module.ast.node {
+                Node::Block { nodes } => self.block(&mut top_level_fn, nodes, module.ast.constant),
+                _ => self.node(&mut top_level_fn, &module.ast),
             }
-        );
+            self.emit(&mut top_level_fn, InstructionKind::Discard, false);
+        }
+        self.module = root;
+        self.aliases.clear();
+        if root > 0 {
+            self.emit(&mut top_level_fn, InstructionKind::EnterModule(root), false);
+        }
 
-        top_level_fn.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::Call,
-                constant: false,
-            }
-        );
+        // top level declarations go straight into the module's scope
+        match &self.ast.node {
+            Node::Block { nodes } => self.block(&mut top_level_fn, nodes, self.ast.constant),
+            _ => self.node(&mut top_level_fn, self.ast),
+        }
+
+        self.emit(&mut top_level_fn, InstructionKind::Push("main".to_owned()), false);
+
+        self.emit(&mut top_level_fn, InstructionKind::Call(0), false);
 
         self.env.functions.insert(0, top_level_fn);
 
@@ -185,16 +516,16 @@ impl<'i> IRGenerator<'i> {
         match &node.node {
             Block {
                 nodes,
-            } => self.block(func, nodes, node.constant),
+            } => self.scoped_block(func, nodes, node.constant),
             InfixOp {
                 op,
                 left,
                 right,
-            } => self.infix_op(func, op, left, right, node.constant),
+            } => self.infix_op(func, op, left, right, self.integer_type(node), node.constant),
             PrefixOp {
                 op,
                 right,
-            } => self.prefix_op(func, op, right, node.constant),
+            } => self.prefix_op(func, op, right, self.integer_type(node), node.constant),
             PostfixOp {
                 op,
                 left,
@@ -207,24 +538,56 @@ impl<'i> IRGenerator<'i> {
                 typ,
                 value,
             } => self.literal(func, typ, value, node.constant),
+            Tuple {
+                elements,
+            } => self.tuple(func, elements, node.constant),
+            ArrayLiteral {
+                elements,
+            } => self.array_literal(func, elements, node.constant),
+            ArrayType { .. } | FunctionType { .. } => self.type_expression(func, node.constant),
+            StructType {
+                field_names,
+                ..
+            } => self.struct_type(func, field_names, node.constant),
+            StructLiteral {
+                typ,
+                field_names,
+                field_values,
+            } => self.struct_literal(func, typ, field_names, field_values, node.constant),
+            FieldAccess {
+                object,
+                field,
+            } => self.field_access(func, object, field, node.position, node.constant),
+            EnumType {
+                variant_names,
+                variant_payloads,
+            } => self.enum_type(func, variant_names, variant_payloads, node.constant),
+            Match {
+                value,
+                arms,
+            } => self.match_expression(func, value, arms, node.position, node.constant),
             Call {
-                name,
+                callee,
                 args,
-            } => self.call(func, name, args, node.constant),
+            } => self.call(func, callee, args, node.constant),
             VariableRef {
                 name,
             } => self.variable_ref(func, name, node.constant),
             Declaration {
                 name,
-                typ,
                 body,
-            } => self.declaration(func, name, typ, body, node.constant),
+                ..
+            } => self.declaration(func, name, body, node.constant),
+            Destructure {
+                names,
+                body,
+            } => self.destructure(func, names, body, node.constant),
             FunctionExpression {
                 arg_types,
                 arg_names,
                 ret_types,
                 body,
-            } => self.function_expression(func, arg_types, arg_names, ret_types, body, node.constant),
+            } => self.function_expression(func, node, arg_types, arg_names, ret_types, body),
             IfExpression {
                 condition,
                 then_body,
@@ -233,177 +596,613 @@ impl<'i> IRGenerator<'i> {
             WhileExpression {
                 condition,
                 body,
-            } => self.while_expression(func, condition, body, node.constant),
+                label,
+            } => self.while_expression(func, condition, body, label, node.constant),
             Assignment {
                 name,
                 value,
             } => self.assignment(func, name, value, node.constant),
+            FieldAssignment {
+                object,
+                field,
+                value,
+            } => self.field_assignment(func, object, field, value, node.position, node.constant),
+            Break {
+                label,
+                value,
+            } => self.break_expression(func, label, value, node.position, node.constant),
+            Continue {
+                label,
+            } => self.continue_expression(func, label, node.position, node.constant),
+            Return {
+                value,
+            } => self.return_expression(func, value, node.constant),
+            ForExpression {
+                variable,
+                iterable,
+                body,
+                label,
+            } => self.for_expression(func, variable, iterable, body, label, node.constant),
+            Use {
+                path,
+            } => self.use_declaration(func, path, node.position, node.constant),
+        }
+
+        if let Some(&number) = self.annotations.conversions.get(&(node as *const NodeContext)) {
+            self.emit(func, InstructionKind::Convert(number), node.constant);
         }
     }
 
-    fn block(&mut self, func: &mut Function, nodes: &[NodeContext], _constant: bool) {
-        for node in nodes {
+    /// Every node leaves exactly one value on the stack, so a block throws away the values of all
+    /// but its last node
+    fn block(&mut self, func: &mut Function, nodes: &[NodeContext], constant: bool) {
+        if nodes.is_empty() {
+            self.emit(func, InstructionKind::ConstUndefined, constant);
+        }
+
+        for (i, node) in nodes.iter().enumerate() {
+            if i > 0 {
+                self.emit(func, InstructionKind::Discard, constant);
+            }
             self.node(func, node);
         }
     }
 
-    fn infix_op(&mut self, func: &mut Function, op: &str, left: &Box<NodeContext>, right: &Box<NodeContext>, constant: bool) {
+    fn integer_type(&self, node: &NodeContext) -> Option<IntType> {
+        self.annotations.integers.get(&(node as *const NodeContext)).copied()
+    }
+
+    fn infix_op(&mut self,
+        func: &mut Function,
+        op: &str,
+        left: &NodeContext,
+        right: &NodeContext,
+        int: Option<IntType>,
+        constant: bool
+    ) {
+        if op == "and" || op == "or" {
+            return self.short_circuit_op(func, op, left, right, constant);
+        }
+
         self.node(func, left);
         self.node(func, right);
 
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: match op {
-                    "+" => InstructionKind::Add,
-                    "-" => InstructionKind::Subtract,
-                    "*" => InstructionKind::Multiply,
-                    "/" => InstructionKind::ExactDivide,
-                    "//" => InstructionKind::FloorDivide,
-
-                    "==" => InstructionKind::Test(CompareType::EQ),
-                    "!=" => InstructionKind::Test(CompareType::NE),
-                    "<" => InstructionKind::Test(CompareType::LT),
-                    ">" => InstructionKind::Test(CompareType::GT),
-                    "<=" => InstructionKind::Test(CompareType::LE),
-                    ">=" => InstructionKind::Test(CompareType::GE),
-
-                    _ => unreachable!(),
-                },
-                constant,
-            }
-        );
+        let kind = match op {
+            ".." => InstructionKind::MakeRange,
+
+            "+" | "+%" | "+|" | "+?" => InstructionKind::Add(int, overflow(op)),
+            "-" | "-%" | "-|" | "-?" => InstructionKind::Subtract(int, overflow(op)),
+            "*" | "*%" | "*|" | "*?" => InstructionKind::Multiply(int, overflow(op)),
+            "/" => InstructionKind::ExactDivide,
+            "//" => InstructionKind::FloorDivide(int),
+
+            "==" => InstructionKind::Test(CompareType::EQ),
+            "!=" => InstructionKind::Test(CompareType::NE),
+            "<" => InstructionKind::Test(CompareType::LT),
+            ">" => InstructionKind::Test(CompareType::GT),
+            "<=" => InstructionKind::Test(CompareType::LE),
+            ">=" => InstructionKind::Test(CompareType::GE),
+
+            _ => unreachable!(),
+        };
+        self.emit(func, kind, constant);
+    }
+
+    /// Declarations inside a block are only visible until the end of the block, and may shadow
+    /// variables from outside it
+    fn scoped_block(&mut self, func: &mut Function, nodes: &[NodeContext], constant: bool) {
+        self.emit(func, InstructionKind::EnterScope, constant);
+        self.scopes += 1;
+        self.block(func, nodes, constant);
+        self.scopes -= 1;
+        self.emit(func, InstructionKind::ExitScope(1), constant);
     }
 
-    fn prefix_op(&mut self, func: &mut Function, op: &str, right: &Box<NodeContext>, constant: bool) {
+    /// `and` and `or` only evaluate their right side if the left side doesn't already decide the
+    /// result, so `a and b` is lowered like `if a { b } else { false }`
+    fn short_circuit_op(&mut self, func: &mut Function, op: &str, left: &NodeContext, right: &NodeContext, constant: bool) {
+        self.node(func, left);
+        let right_block_id = self.get_next_block_id();
+        let decided_block_id = self.get_next_block_id();
+        let end_block_id = self.get_next_block_id();
+
+        let kind = if op == "and" {
+            InstructionKind::BranchIf(right_block_id, decided_block_id)
+        } else {
+            InstructionKind::BranchIf(decided_block_id, right_block_id)
+        };
+        self.emit(func, kind, constant);
+
+        func.blocks.push(BasicBlock {
+            id: right_block_id,
+            instructions: vec![],
+        });
+        let depth = self.depth;
         self.node(func, right);
+        self.emit(func, InstructionKind::Jump(end_block_id), constant);
+        self.depth = depth;
 
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: match op {
-                    "-" => InstructionKind::Negate,
-                    _ => unreachable!(),
-                },
-                constant,
-            }
-        );
+        func.blocks.push(BasicBlock {
+            id: decided_block_id,
+            instructions: vec![],
+        });
+        self.emit(func, InstructionKind::ConstBool(op == "or"), constant);
+        self.emit(func, InstructionKind::Jump(end_block_id), constant);
+
+        func.blocks.push(BasicBlock {
+            id: end_block_id,
+            instructions: vec![],
+        });
+    }
+
+    fn prefix_op(&mut self, func: &mut Function, op: &str, right: &NodeContext, int: Option<IntType>, constant: bool) {
+        if op == ".." {
+            self.emit(func, InstructionKind::ConstUndefined, constant);
+        }
+        self.node(func, right);
+
+        let kind = match op {
+            ".." => InstructionKind::MakeRange,
+            "-" => InstructionKind::Negate(int),
+            "not" => InstructionKind::Not,
+            _ => unreachable!(),
+        };
+        self.emit(func, kind, constant);
  
     }
 
-    fn postfix_op(&mut self, func: &mut Function, op: &str, left: &Box<NodeContext>, constant: bool) {
-        todo!("{:?}{:?}{:?}{:?}", func, op, left, constant)
+    /// Ranges without an end, `a..`, are the only postfix operator so far
+    fn postfix_op(&mut self, func: &mut Function, op: &str, left: &NodeContext, constant: bool) {
+        self.node(func, left);
+
+        match op {
+            ".." => {
+                self.emit(func, InstructionKind::ConstUndefined, constant);
+                self.emit(func, InstructionKind::MakeRange, constant);
+            },
+            _ => unreachable!(),
+        }
     }
 
-    fn index_op(&mut self, func: &mut Function, object: &Box<NodeContext>, index: &Box<NodeContext>, constant: bool) {
-        todo!("{:?}{:?}{:?}{:?}", func, object, index, constant)
+    /// Indexing with a range like `a[1..3]`, `a[1..]` or `a[..3]` takes a slice of the array
+    fn index_op(&mut self, func: &mut Function, object: &NodeContext, index: &NodeContext, constant: bool) {
+        self.node(func, object);
+        self.node(func, index);
+        self.emit(func, InstructionKind::Index, constant);
     }
 
     fn literal(&mut self, func: &mut Function, typ: &Type, value: &str, constant: bool) {
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: match typ {
-                    Type::Bool => InstructionKind::ConstBool(match value {
-                        "true" => true,
-                        "false" => false,
-                        _ => unreachable!(),
-                    }),
-                    Type::IntLiteral => InstructionKind::ConstInt(value.parse().unwrap()),
-                    Type::FloatLiteral => InstructionKind::ConstFloat(value.parse().unwrap()),
-                    Type::StrLiteral => InstructionKind::ConstString(value.to_owned()),
-                    _ => todo!(),
-                },
-                constant,
-            }
-        );
+        let kind = match typ {
+            Type::Bool => InstructionKind::ConstBool(match value {
+                "true" => true,
+                "false" => false,
+                _ => unreachable!(),
+            }),
+            Type::IntLiteral => InstructionKind::ConstInt(value.parse().unwrap()),
+            Type::FloatLiteral => InstructionKind::ConstFloat(value.parse().unwrap()),
+            Type::StrLiteral => InstructionKind::ConstString(value.to_owned()),
+            Type::Undefined | Type::Unknown => InstructionKind::ConstUndefined,
+        };
+        self.emit(func, kind, constant);
     }
 
-    fn call(&mut self, func: &mut Function, name: &str, args: &[NodeContext], constant: bool) {
+    /// Calling a member, `a.b(c)`, can't be told apart from calling a method on `a` until the
+    /// program runs, so it gets its own instruction
+    fn call(&mut self, func: &mut Function, callee: &NodeContext, args: &[NodeContext], constant: bool) {
+        if let Node::FieldAccess { object, field } = &callee.node {
+            self.check_visibility(object, field, callee.position);
+            self.node(func, object);
+            for arg in args {
+                self.node(func, arg);
+            }
+            return self.emit(func, InstructionKind::CallMember(field.clone(), args.len()), constant);
+        }
+        // calling a number type converts its argument to it
+        if let (Node::VariableRef { name }, [arg]) = (&callee.node, args) {
+            if let Some(number) = NumberType::from_name(name) {
+                self.node(func, arg);
+                return self.emit(func, InstructionKind::Convert(number), constant);
+            }
+        }
+
         for arg in args {
             self.node(func, arg);
         }
+        self.node(func, callee);
+        self.emit(func, InstructionKind::Call(args.len()), constant);
+    }
 
-        func.blocks.last_mut().unwrap().instructions.append(&mut vec![
-            Instruction {
-                kind: InstructionKind::Push(name.into()),
-                constant,
+    fn array_literal(&mut self, func: &mut Function, elements: &[NodeContext], constant: bool) {
+        for element in elements {
+            self.node(func, element);
+        }
+        self.emit(func, InstructionKind::MakeArray(elements.len()), constant);
+    }
+
+    /// Array and function types only matter for type checking, so there is nothing to run
+    fn type_expression(&mut self, func: &mut Function, constant: bool) {
+        self.emit(func, InstructionKind::ConstUndefined, constant);
+    }
+
+    /// Tuples are a single value on the stack, so functions can return several values at once
+    fn tuple(&mut self, func: &mut Function, elements: &[NodeContext], constant: bool) {
+        for element in elements {
+            self.node(func, element);
+        }
+        self.emit(func, InstructionKind::MakeTuple(elements.len()), constant);
+    }
+
+    /// Field types aren't checked yet, so a struct type only needs to know the names of its fields
+    fn struct_type(&mut self, func: &mut Function, field_names: &[String], constant: bool) {
+        self.emit(func, InstructionKind::MakeStructType(field_names.to_vec()), constant);
+    }
+
+    fn struct_literal(&mut self,
+        func: &mut Function,
+        typ: &NodeContext,
+        field_names: &[String],
+        field_values: &[NodeContext],
+        constant: bool
+    ) {
+        self.node(func, typ);
+        for value in field_values {
+            self.node(func, value);
+        }
+        self.emit(func, InstructionKind::MakeStruct(field_names.to_vec()), constant);
+    }
+
+    fn field_access(&mut self, func: &mut Function, object: &NodeContext, field: &str, position: usize, constant: bool) {
+        self.check_visibility(object, field, position);
+        self.node(func, object);
+        self.emit(func, InstructionKind::GetField(field.into()), constant);
+    }
+
+    /// `use core.io` declares `io`, as long as the module loader found it
+    fn use_declaration(&mut self, func: &mut Function, path: &[String], position: usize, constant: bool) {
+        if self.scopes > 0 {
+            self.errors.ir("use declarations can only be at the top level of a module".to_owned(), position);
+        } else if let Some(&id) = self.modules.get(self.module).and_then(|module| module.imports.get(path)) {
+            let name = path.last().unwrap();
+            self.aliases.insert(name.clone(), id);
+            self.emit(func, InstructionKind::GetModule(id), constant);
+            self.emit(func, InstructionKind::Allocate(name.clone()), constant);
+        }
+        self.emit(func, InstructionKind::ConstUndefined, constant);
+    }
+
+    /// Only `pub` declarations can be used from outside their module
+    fn check_visibility(&mut self, object: &NodeContext, field: &str, position: usize) {
+        let id = match &object.node {
+            Node::VariableRef { name } => match self.aliases.get(name) {
+                Some(&id) => id,
+                None => return,
             },
-            Instruction {
-                kind: InstructionKind::Call,
-                constant,
+            _ => return,
+        };
+        let module = &self.env.modules[id];
+        if module.public.contains(field) {
+            return;
+        }
+
+        let declared = top_level_declarations(&self.modules[id].ast).any(|(name, _)| name == field);
+        self.errors.ir(
+            if declared {
+                format!("{} is not public in module {}", field, module.name)
+            } else {
+                format!("Module {} has nothing called {}", module.name, field)
             },
-        ]);
+            position,
+        );
     }
 
-    fn variable_ref(&mut self, func: &mut Function, name: &str, constant: bool) {
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::Push(name.into()),
-                constant,
+    fn enum_type(&mut self,
+        func: &mut Function,
+        variant_names: &[String],
+        variant_payloads: &[Vec<NodeContext>],
+        constant: bool
+    ) {
+        self.emit(func, InstructionKind::MakeEnumType(enum_variants(variant_names, variant_payloads)), constant);
+    }
+
+    /// Matches test each arm's pattern in turn, in a chain of blocks which falls through to the
+    /// next arm as soon as a test (or the guard) fails. The value being matched lives in a hidden
+    /// variable called `match`, which can't clash with anything since it is a keyword.
+    fn match_expression(&mut self,
+        func: &mut Function,
+        value: &NodeContext,
+        arms: &[MatchArm],
+        position: usize,
+        constant: bool
+    ) {
+        let mut valid = true;
+        for arm in arms {
+            valid &= self.check_pattern(&arm.pattern, position);
+        }
+        if valid {
+            let unguarded: Vec<_> = arms.iter()
+                .filter(|arm| arm.guard.is_none())
+                .map(|arm| &arm.pattern)
+                .collect();
+            let missing = exhaustiveness::missing_cases(&unguarded, &self.enums[self.module]);
+            if !missing.is_empty() {
+                self.errors.ir(
+                    format!("Match is not exhaustive, missing {}", missing.join(", ")),
+                    position,
+                );
             }
-        );
+        }
+
+        self.node(func, value);
+        self.emit(func, InstructionKind::EnterScope, constant);
+        self.scopes += 1;
+        self.emit(func, InstructionKind::Allocate("match".to_owned()), constant);
+
+        let depth = self.depth;
+        let end_block_id = self.get_next_block_id();
+        let mut arm_block_id = self.get_next_block_id();
+        self.emit(func, InstructionKind::Jump(arm_block_id), constant);
+
+        for arm in arms {
+            func.blocks.push(BasicBlock {
+                id: arm_block_id,
+                instructions: vec![],
+            });
+            arm_block_id = self.get_next_block_id();
+            self.depth = depth;
+
+            self.pattern_tests(func, &arm.pattern, &mut vec![], arm_block_id, constant);
+
+            self.emit(func, InstructionKind::EnterScope, constant);
+            self.scopes += 1;
+            self.pattern_bindings(func, &arm.pattern, &mut vec![], constant);
+
+            if let Some(guard) = &arm.guard {
+                let body_block_id = self.get_next_block_id();
+                let guard_failed_block_id = self.get_next_block_id();
+                self.node(func, guard);
+                self.emit(func, InstructionKind::BranchIf(body_block_id, guard_failed_block_id), constant);
+
+                func.blocks.push(BasicBlock {
+                    id: guard_failed_block_id,
+                    instructions: vec![],
+                });
+                self.emit(func, InstructionKind::ExitScope(1), constant);
+                self.emit(func, InstructionKind::Jump(arm_block_id), constant);
+
+                func.blocks.push(BasicBlock {
+                    id: body_block_id,
+                    instructions: vec![],
+                });
+            }
+
+            self.node(func, &arm.body);
+            self.scopes -= 1;
+            self.emit(func, InstructionKind::ExitScope(1), constant);
+            self.emit(func, InstructionKind::Jump(end_block_id), constant);
+        }
+
+        // only reachable if every arm that could have matched had a guard which failed
+        func.blocks.push(BasicBlock {
+            id: arm_block_id,
+            instructions: vec![],
+        });
+        self.emit(func, InstructionKind::Unreachable, constant);
+
+        func.blocks.push(BasicBlock {
+            id: end_block_id,
+            instructions: vec![],
+        });
+        self.depth = depth + 1;
+        self.scopes -= 1;
+        self.emit(func, InstructionKind::ExitScope(1), constant);
+    }
+
+    /// Makes sure variant patterns name a variant which exists, with the right number of values
+    fn check_pattern(&mut self, pattern: &Pattern, position: usize) -> bool {
+        match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) | Pattern::Literal { .. } => true,
+            Pattern::Tuple(elements) => self.check_patterns(elements, position),
+            Pattern::Variant { enum_name, variant, payload } => {
+                let arity = match self.enums[self.module].get(enum_name) {
+                    Some(variants) => variants.iter().find(|(name, _)| name == variant).map(|(_, arity)| *arity),
+                    None => {
+                        self.errors.ir(format!("There is no enum called {}", enum_name), position);
+                        return false;
+                    },
+                };
+                match arity {
+                    None => {
+                        self.errors.ir(format!("Enum {} has no variant {}", enum_name, variant), position);
+                        false
+                    },
+                    Some(arity) if arity != payload.len() => {
+                        self.errors.ir(
+                            format!(
+                                "Variant {}.{} holds {} value(s), but the pattern has {}",
+                                enum_name, variant, arity, payload.len(),
+                            ),
+                            position,
+                        );
+                        false
+                    },
+                    Some(_) => self.check_patterns(payload, position),
+                }
+            },
+        }
+    }
+
+    fn check_patterns(&mut self, patterns: &[Pattern], position: usize) -> bool {
+        // every pattern is checked, rather than stopping at the first one with an error
+        let valid: Vec<_> = patterns.iter().map(|pattern| self.check_pattern(pattern, position)).collect();
+        valid.into_iter().all(|valid| valid)
+    }
+
+    /// Pushes the part of the matched value which `path` leads to
+    fn pattern_value(&mut self, func: &mut Function, path: &[InstructionKind], constant: bool) {
+        self.emit(func, InstructionKind::Push("match".to_owned()), constant);
+        for kind in path {
+            self.emit(func, kind.clone(), constant);
+        }
+    }
+
+    /// Tests the pattern, jumping to the failed block if it doesn't match and otherwise carrying
+    /// on in a new block
+    fn pattern_tests(&mut self,
+        func: &mut Function,
+        pattern: &Pattern,
+        path: &mut Vec<InstructionKind>,
+        failed_block_id: usize,
+        constant: bool
+    ) {
+        let test = match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) => return,
+            Pattern::Literal { typ, value } => {
+                self.pattern_value(func, path, constant);
+                self.literal(func, typ, value, constant);
+                InstructionKind::Test(CompareType::EQ)
+            },
+            Pattern::Tuple(elements) => {
+                for (i, element) in elements.iter().enumerate() {
+                    path.push(InstructionKind::GetElement(i));
+                    self.pattern_tests(func, element, path, failed_block_id, constant);
+                    path.pop();
+                }
+                return;
+            },
+            Pattern::Variant { variant, .. } => {
+                self.pattern_value(func, path, constant);
+                InstructionKind::IsVariant(variant.clone())
+            },
+        };
+        self.emit(func, test, constant);
+
+        let passed_block_id = self.get_next_block_id();
+        self.emit(func, InstructionKind::BranchIf(passed_block_id, failed_block_id), constant);
+        func.blocks.push(BasicBlock {
+            id: passed_block_id,
+            instructions: vec![],
+        });
+
+        if let Pattern::Variant { payload, .. } = pattern {
+            for (i, element) in payload.iter().enumerate() {
+                path.push(InstructionKind::GetPayload(i));
+                self.pattern_tests(func, element, path, failed_block_id, constant);
+                path.pop();
+            }
+        }
+    }
+
+    fn pattern_bindings(&mut self, func: &mut Function, pattern: &Pattern, path: &mut Vec<InstructionKind>, constant: bool) {
+        let (elements, access): (&[Pattern], fn(usize) -> InstructionKind) = match pattern {
+            Pattern::Binding(name) => {
+                self.pattern_value(func, path, constant);
+                return self.emit(func, InstructionKind::Allocate(name.clone()), constant);
+            },
+            Pattern::Wildcard | Pattern::Literal { .. } => return,
+            Pattern::Tuple(elements) => (elements, InstructionKind::GetElement),
+            Pattern::Variant { payload, .. } => (payload, InstructionKind::GetPayload),
+        };
+        for (i, element) in elements.iter().enumerate() {
+            path.push(access(i));
+            self.pattern_bindings(func, element, path, constant);
+            path.pop();
+        }
+    }
+
+    fn variable_ref(&mut self, func: &mut Function, name: &str, constant: bool) {
+        self.emit(func, InstructionKind::Push(name.into()), constant);
     }
 
     fn declaration(&mut self,
         func: &mut Function,
         name: &str,
-        typ: &Box<NodeContext>,
-        body: &Box<NodeContext>,
+        body: &NodeContext,
         constant: bool
     ) {
-        self.node(func, typ);
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::Allocate(name.into()),
-                constant,
-            }
-        );
+        // top level enums were found up front, but local ones are only known from here on
+        if let Node::EnumType { variant_names, variant_payloads } = &body.node {
+            self.enums[self.module].insert(name.to_owned(), enum_variants(variant_names, variant_payloads));
+        }
+
+        // the declared type was already checked, so the variable starts off undefined
+        self.emit(func, InstructionKind::ConstUndefined, constant);
+        self.emit(func, InstructionKind::Allocate(name.into()), constant);
         self.node(func, body);
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::Pop(name.into()),
-                constant,
-            }
-        );
+        self.emit(func, InstructionKind::Pop(name.into()), constant);
+        self.emit(func, InstructionKind::ConstUndefined, constant);
+    }
+
+    fn destructure(&mut self, func: &mut Function, names: &[String], body: &NodeContext, constant: bool) {
+        self.node(func, body);
+        self.emit(func, InstructionKind::Unpack(names.len()), constant);
+        for name in names.iter().rev() {
+            self.emit(func, InstructionKind::Allocate(name.clone()), constant);
+        }
+        self.emit(func, InstructionKind::ConstUndefined, constant);
     }
 
     fn function_expression(&mut self,
         func: &mut Function,
+        node: &NodeContext,
         arg_types: &[NodeContext],
-        _arg_names: &[String],
+        arg_names: &[String],
         ret_types: &[NodeContext],
-        body: &Box<NodeContext>,
-        constant: bool
+        body: &NodeContext,
     ) {
+        let constant = node.constant;
         let mut new_func = Function {
             id: self.get_next_func_id(),
+            module: self.module,
             args: arg_types.len(),
-            retvals: ret_types.len(),
+            retvals: declared_retvals(ret_types),
+            signature: self.annotations.signatures.get(&(node as *const NodeContext)).cloned(),
             blocks: vec![
                 BasicBlock {
                     id: self.get_next_block_id(),
                     instructions: vec![],
                 },
-                BasicBlock {
-                    id: self.get_next_block_id(),
-                    instructions: vec![],
-                },
             ],
         };
 
+        // break and continue can't leave the function
+        let outer_depth = self.depth;
+        let outer_loops = std::mem::take(&mut self.loops);
+        let outer_scopes = self.scopes;
+        let outer_in_function = self.in_function;
+        self.depth = arg_names.len();
+        self.scopes = 0;
+        self.in_function = true;
+
+        // the first block binds the arguments, which the caller left on the stack in order
+        for name in arg_names.iter().rev() {
+            self.emit(&mut new_func, InstructionKind::Allocate(name.clone()), constant);
+        }
+        new_func.blocks.push(BasicBlock {
+            id: self.get_next_block_id(),
+            instructions: vec![],
+        });
+
         self.node(&mut new_func, body);
 
-        new_func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::Return,
-                constant,
-            }
-        );
+        self.emit(&mut new_func, InstructionKind::Return, constant);
 
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::GetFunction(new_func.id),
-                constant,
+        self.depth = outer_depth;
+        self.loops = outer_loops;
+        self.scopes = outer_scopes;
+        self.in_function = outer_in_function;
+
+        self.emit(func, InstructionKind::GetFunction(new_func.id), constant);
+
+        // only functions made inside a function or a block can have local variables to capture
+        if self.in_function || self.scopes > 0 {
+            let captures: Vec<_> = free_variables(arg_names, body)
+                .into_iter()
+                .filter(|name| !self.env.scopes[0].contains_key(name))
+                .collect();
+            if !captures.is_empty() {
+                self.emit(func, InstructionKind::MakeClosure(captures), constant);
             }
-        );
+        }
 
         self.env.functions.insert(new_func.id, new_func);
     }
@@ -411,9 +1210,9 @@ impl<'i> IRGenerator<'i> {
     fn if_expression(
         &mut self,
         func: &mut Function,
-        condition: &Box<NodeContext>,
-        then_body: &Box<NodeContext>,
-        else_body: &Box<NodeContext>,
+        condition: &NodeContext,
+        then_body: &NodeContext,
+        else_body: &NodeContext,
         constant: bool
     ) {
         self.node(func, condition);
@@ -421,12 +1220,8 @@ impl<'i> IRGenerator<'i> {
         let else_block_id = self.get_next_block_id();
         let end_block_id = self.get_next_block_id();
 
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::BranchIf(then_block_id, else_block_id),
-                constant,
-            }
-        );
+        self.emit(func, InstructionKind::BranchIf(then_block_id, else_block_id), constant);
+        let depth = self.depth;
 
         func.blocks.push(BasicBlock {
             id: then_block_id,
@@ -434,12 +1229,8 @@ impl<'i> IRGenerator<'i> {
         }); 
         
         self.node(func, then_body);
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::Jump(end_block_id),
-                constant,
-            }
-        );
+        self.emit(func, InstructionKind::Jump(end_block_id), constant);
+        self.depth = depth;
 
         func.blocks.push(BasicBlock {
             id: else_block_id,
@@ -448,12 +1239,7 @@ impl<'i> IRGenerator<'i> {
 
         self.node(func, else_body);
 
-        func.blocks.last_mut().unwrap().instructions.push(
-            Instruction {
-                kind: InstructionKind::Jump(end_block_id),
-                constant,
-            }
-        );
+        self.emit(func, InstructionKind::Jump(end_block_id), constant);
 
         func.blocks.push(BasicBlock {
             id: end_block_id,
@@ -461,18 +1247,262 @@ impl<'i> IRGenerator<'i> {
         }); 
     }
 
-    fn while_expression(&mut self, func: &mut Function, condition: &Box<NodeContext>, body: &Box<NodeContext>, constant: bool) {}
+    /// Loops are lowered into a header block which tests the condition, a body block which jumps
+    /// back to the header and an exit block, which the loop's (undefined) value is pushed in.
+    /// Breaking out of the loop skips the exit block, since break provides its own value.
+    fn while_expression(&mut self,
+        func: &mut Function,
+        condition: &NodeContext,
+        body: &NodeContext,
+        label: &Option<String>,
+        constant: bool
+    ) {
+        let header_block_id = self.get_next_block_id();
+        let body_block_id = self.get_next_block_id();
+        let exit_block_id = self.get_next_block_id();
+        let end_block_id = self.get_next_block_id();
+
+        self.emit(func, InstructionKind::Jump(header_block_id), constant);
+
+        func.blocks.push(BasicBlock {
+            id: header_block_id,
+            instructions: vec![],
+        });
+
+        self.node(func, condition);
+        self.emit(func, InstructionKind::BranchIf(body_block_id, exit_block_id), constant);
+
+        func.blocks.push(BasicBlock {
+            id: body_block_id,
+            instructions: vec![],
+        });
+
+        self.loops.push(Loop {
+            label: label.clone(),
+            continue_block_id: header_block_id,
+            break_block_id: end_block_id,
+            depth: self.depth,
+            scopes: self.scopes,
+            // `loop` is parsed as a while loop with a literal true condition
+            infinite: matches!(&condition.node, Node::Literal { typ: Type::Bool, value } if value == "true"),
+        });
+        self.node(func, body);
+        self.loops.pop();
+
+        self.emit(func, InstructionKind::Discard, constant);
+        self.emit(func, InstructionKind::Jump(header_block_id), constant);
+
+        func.blocks.push(BasicBlock {
+            id: exit_block_id,
+            instructions: vec![],
+        });
+        self.emit(func, InstructionKind::ConstUndefined, constant);
+
+        func.blocks.push(BasicBlock {
+            id: end_block_id,
+            instructions: vec![],
+        });
+    }
+
+    /// For loops are lowered just like while loops, where the header asks the iterable (kept in
+    /// a hidden variable called `for`) whether it has another value, and the body takes it out
+    fn for_expression(&mut self,
+        func: &mut Function,
+        variable: &str,
+        iterable: &NodeContext,
+        body: &NodeContext,
+        label: &Option<String>,
+        constant: bool
+    ) {
+        let header_block_id = self.get_next_block_id();
+        let body_block_id = self.get_next_block_id();
+        let exit_block_id = self.get_next_block_id();
+        let end_block_id = self.get_next_block_id();
+
+        self.node(func, iterable);
+        self.emit(func, InstructionKind::EnterScope, constant);
+        self.scopes += 1;
+        self.emit(func, InstructionKind::Allocate("for".to_owned()), constant);
+        self.emit(func, InstructionKind::Jump(header_block_id), constant);
+
+        func.blocks.push(BasicBlock {
+            id: header_block_id,
+            instructions: vec![],
+        });
+        self.emit(func, InstructionKind::HasNext("for".to_owned()), constant);
+        self.emit(func, InstructionKind::BranchIf(body_block_id, exit_block_id), constant);
+
+        func.blocks.push(BasicBlock {
+            id: body_block_id,
+            instructions: vec![],
+        });
+
+        self.loops.push(Loop {
+            label: label.clone(),
+            continue_block_id: header_block_id,
+            break_block_id: end_block_id,
+            depth: self.depth,
+            scopes: self.scopes,
+            infinite: false,
+        });
+        // every iteration gets its own variable
+        self.emit(func, InstructionKind::Next("for".to_owned()), constant);
+        self.emit(func, InstructionKind::EnterScope, constant);
+        self.scopes += 1;
+        self.emit(func, InstructionKind::Allocate(variable.to_owned()), constant);
+        self.node(func, body);
+        self.scopes -= 1;
+        self.emit(func, InstructionKind::ExitScope(1), constant);
+        self.loops.pop();
+
+        self.emit(func, InstructionKind::Discard, constant);
+        self.emit(func, InstructionKind::Jump(header_block_id), constant);
+
+        func.blocks.push(BasicBlock {
+            id: exit_block_id,
+            instructions: vec![],
+        });
+        self.emit(func, InstructionKind::ConstUndefined, constant);
+
+        func.blocks.push(BasicBlock {
+            id: end_block_id,
+            instructions: vec![],
+        });
+        self.scopes -= 1;
+        self.emit(func, InstructionKind::ExitScope(1), constant);
+    }
+
+    fn break_expression(&mut self,
+        func: &mut Function,
+        label: &Option<String>,
+        value: &NodeContext,
+        position: usize,
+        constant: bool
+    ) {
+        let depth = self.depth;
+        let (loop_depth, loop_scopes, break_block_id, infinite) = match self.find_loop(label, true, position) {
+            Some(l) => (l.depth, l.scopes, l.break_block_id, l.infinite),
+            None => return,
+        };
+
+        if !infinite && !matches!(value.node, Node::Literal { typ: Type::Undefined, .. }) {
+            self.errors.ir(
+                "Only loop expressions can break with a value".to_owned(),
+                position,
+            );
+        }
+
+        // anything left on the stack by the expressions the break is nested in has to go
+        if depth > loop_depth {
+            self.emit(func, InstructionKind::Drop(depth - loop_depth), constant);
+        }
+        self.node(func, value);
+        if self.scopes > loop_scopes {
+            self.emit(func, InstructionKind::ExitScope(self.scopes - loop_scopes), constant);
+        }
+        self.emit(func, InstructionKind::Jump(break_block_id), constant);
+
+        // the rest of the block is unreachable, but it still expects break to leave a value
+        self.depth = depth + 1;
+    }
+
+    fn continue_expression(&mut self, func: &mut Function, label: &Option<String>, position: usize, constant: bool) {
+        let depth = self.depth;
+        let (loop_depth, loop_scopes, continue_block_id) = match self.find_loop(label, false, position) {
+            Some(l) => (l.depth, l.scopes, l.continue_block_id),
+            None => return,
+        };
+
+        if depth > loop_depth {
+            self.emit(func, InstructionKind::Drop(depth - loop_depth), constant);
+        }
+        if self.scopes > loop_scopes {
+            self.emit(func, InstructionKind::ExitScope(self.scopes - loop_scopes), constant);
+        }
+        self.emit(func, InstructionKind::Jump(continue_block_id), constant);
+
+        self.depth = depth + 1;
+    }
+
+    /// Returning early throws away everything the function has on the stack before pushing the
+    /// return value, so the caller sees the same stack as if the function ended normally
+    fn return_expression(&mut self, func: &mut Function, value: &NodeContext, constant: bool) {
+        let depth = self.depth;
+        if depth > 0 {
+            self.emit(func, InstructionKind::Drop(depth), constant);
+        }
+        self.node(func, value);
+        self.emit(func, InstructionKind::Return, constant);
+
+        // the rest of the block is unreachable, but it still expects return to leave a value
+        self.depth = depth + 1;
+    }
+
+    /// The innermost loop with the given label, or just the innermost loop if there is no label.
+    /// `breaking` says whether it's for a break or a continue, which only matters to the error.
+    fn find_loop(&mut self, label: &Option<String>, breaking: bool, position: usize) -> Option<&Loop> {
+        let found = self.loops.iter().rposition(|l| label.is_none() || l.label == *label);
+        if found.is_none() {
+            self.errors.ir(
+                match (label, breaking) {
+                    (Some(label), true) => format!("There is no loop labeled {} to break out of", label),
+                    (Some(label), false) => format!("There is no loop labeled {} to continue", label),
+                    (None, true) => "Can't use break outside of a loop".to_owned(),
+                    (None, false) => "Can't use continue outside of a loop".to_owned(),
+                },
+                position,
+            );
+        }
+        found.map(move |i| &self.loops[i])
+    }
+
+    fn assignment(&mut self, func: &mut Function, name: &str, value: &NodeContext, constant: bool) {
+        self.node(func, value);
+
+        self.emit(func, InstructionKind::Pop(name.into()), constant);
+        self.emit(func, InstructionKind::ConstUndefined, constant);
+    }
+
+    /// Structs are values, so assigning to a field (even a nested one like `a.b.c = 1`) takes the
+    /// whole struct out of its variable, changes it and puts it back
+    fn field_assignment(&mut self,
+        func: &mut Function,
+        object: &NodeContext,
+        field: &str,
+        value: &NodeContext,
+        position: usize,
+        constant: bool
+    ) {
+        let mut path = vec![field.to_owned()];
+        let mut root = object;
+        while let Node::FieldAccess { object, field } = &root.node {
+            path.insert(0, field.clone());
+            root = object;
+        }
+        let name = match &root.node {
+            Node::VariableRef { name } => name,
+            _ => {
+                self.errors.ir("Can only assign to the fields of a variable".to_owned(), position);
+                return self.emit(func, InstructionKind::ConstUndefined, constant);
+            },
+        };
 
-    fn assignment(&mut self, func: &mut Function, name: &str, value: &Box<NodeContext>, constant: bool) {
+        self.emit(func, InstructionKind::Push(name.clone()), constant);
         self.node(func, value);
+        self.emit(func, InstructionKind::SetField(path), constant);
+        self.emit(func, InstructionKind::Pop(name.clone()), constant);
+        self.emit(func, InstructionKind::ConstUndefined, constant);
+    }
 
+    /// Adds an instruction to the end of the function, keeping track of the stack depth
+    fn emit(&mut self, func: &mut Function, kind: InstructionKind, constant: bool) {
+        self.depth = (self.depth as isize + stack_effect(&kind)) as usize;
         func.blocks.last_mut().unwrap().instructions.push(
             Instruction {
-                kind: InstructionKind::Pop(name.into()),
+                kind,
                 constant,
             }
         );
-
     }
 
     fn get_next_block_id(&mut self) -> usize {
@@ -486,11 +1516,328 @@ impl<'i> IRGenerator<'i> {
     }
 }
 
+/// How many values a function returns, where `void` means it doesn't return anything
+fn declared_retvals(ret_types: &[NodeContext]) -> usize {
+    match ret_types {
+        [NodeContext { node: Node::VariableRef { name }, .. }] if name == "void" => 0,
+        _ => ret_types.len(),
+    }
+}
+
+fn top_level_nodes(ast: &NodeContext) -> &[NodeContext] {
+    match &ast.node {
+        Node::Block { nodes } => nodes,
+        _ => &[],
+    }
+}
+
+/// The name of everything declared at the top level of a module, and whether it is public
+pub fn top_level_declarations(ast: &NodeContext) -> impl Iterator<Item = (&String, bool)> {
+    top_level_nodes(ast).iter().filter_map(|node| match &node.node {
+        Node::Declaration { name, public, .. } => Some((name, *public)),
+        _ => None,
+    })
+}
+
+/// The enums declared at the top level of a module, which can be matched on anywhere in it
+fn top_level_enums(ast: &NodeContext) -> Enums {
+    top_level_nodes(ast).iter().filter_map(|node| match &node.node {
+        Node::Declaration { name, body, .. } => match &body.node {
+            Node::EnumType { variant_names, variant_payloads } => {
+                Some((name.clone(), enum_variants(variant_names, variant_payloads)))
+            },
+            _ => None,
+        },
+        _ => None,
+    }).collect()
+}
+
+/// The variables a function uses without declaring them itself. Those which turn out to be local
+/// variables where the function is made get captured, and the rest are found when it's called.
+fn free_variables(arg_names: &[String], body: &NodeContext) -> Vec<String> {
+    let mut bound = arg_names.to_vec();
+    let mut free = vec![];
+    collect_free_variables(body, &mut bound, &mut free);
+    free
+}
+
+fn collect_free_variables(node: &NodeContext, bound: &mut Vec<String>, free: &mut Vec<String>) {
+    fn mention(name: &String, bound: &[String], free: &mut Vec<String>) {
+        if !bound.contains(name) && !free.contains(name) {
+            free.push(name.clone());
+        }
+    }
+    let outer = bound.len();
+    match &node.node {
+        Node::Block { nodes } => {
+            for node in nodes {
+                collect_free_variables(node, bound, free);
+            }
+        },
+        Node::InfixOp { left, right, .. } => {
+            collect_free_variables(left, bound, free);
+            collect_free_variables(right, bound, free);
+        },
+        Node::PrefixOp { right: value, .. }
+        | Node::PostfixOp { left: value, .. }
+        | Node::FieldAccess { object: value, .. }
+        | Node::Return { value }
+        | Node::Break { value, .. } => collect_free_variables(value, bound, free),
+        Node::IndexOp { object, index } => {
+            collect_free_variables(object, bound, free);
+            collect_free_variables(index, bound, free);
+        },
+        Node::Tuple { elements } | Node::ArrayLiteral { elements } => {
+            for element in elements {
+                collect_free_variables(element, bound, free);
+            }
+        },
+        Node::StructLiteral { typ, field_values, .. } => {
+            collect_free_variables(typ, bound, free);
+            for value in field_values {
+                collect_free_variables(value, bound, free);
+            }
+        },
+        Node::Match { value, arms } => {
+            collect_free_variables(value, bound, free);
+            for arm in arms {
+                pattern_names(&arm.pattern, bound);
+                if let Some(guard) = &arm.guard {
+                    collect_free_variables(guard, bound, free);
+                }
+                collect_free_variables(&arm.body, bound, free);
+                bound.truncate(outer);
+            }
+        },
+        Node::Call { callee, args } => {
+            // `a.b(c)` might be calling the variable b with a as its first argument
+            if let Node::FieldAccess { field, .. } = &callee.node {
+                mention(field, bound, free);
+            }
+            collect_free_variables(callee, bound, free);
+            for arg in args {
+                collect_free_variables(arg, bound, free);
+            }
+        },
+        Node::VariableRef { name } => mention(name, bound, free),
+        // a function can call itself by the name it's declared with
+        Node::Declaration { name, body, .. } if matches!(body.node, Node::FunctionExpression { .. }) => {
+            bound.push(name.clone());
+            collect_free_variables(body, bound, free);
+        },
+        Node::Declaration { name, body, .. } => {
+            collect_free_variables(body, bound, free);
+            bound.push(name.clone());
+        },
+        Node::Destructure { names, body } => {
+            collect_free_variables(body, bound, free);
+            bound.extend(names.iter().cloned());
+        },
+        Node::IfExpression { condition, then_body, else_body } => {
+            collect_free_variables(condition, bound, free);
+            collect_free_variables(then_body, bound, free);
+            collect_free_variables(else_body, bound, free);
+        },
+        Node::WhileExpression { condition, body, .. } => {
+            collect_free_variables(condition, bound, free);
+            collect_free_variables(body, bound, free);
+        },
+        Node::Assignment { name, value } => {
+            mention(name, bound, free);
+            collect_free_variables(value, bound, free);
+        },
+        Node::FieldAssignment { object, value, .. } => {
+            collect_free_variables(object, bound, free);
+            collect_free_variables(value, bound, free);
+        },
+        Node::FunctionExpression { arg_names, body, .. } => {
+            bound.extend(arg_names.iter().cloned());
+            collect_free_variables(body, bound, free);
+        },
+        Node::ForExpression { variable, iterable, body, .. } => {
+            collect_free_variables(iterable, bound, free);
+            bound.push(variable.clone());
+            collect_free_variables(body, bound, free);
+        },
+        Node::Literal { .. }
+        | Node::ArrayType { .. }
+        | Node::FunctionType { .. }
+        | Node::StructType { .. }
+        | Node::EnumType { .. }
+        | Node::Continue { .. }
+        | Node::Use { .. } => {},
+    }
+    // declarations only last until the end of their block
+    if !matches!(node.node, Node::Declaration { .. } | Node::Destructure { .. }) {
+        bound.truncate(outer);
+    }
+}
+
+/// The variables a pattern binds
+fn pattern_names(pattern: &Pattern, names: &mut Vec<String>) {
+    match pattern {
+        Pattern::Binding(name) => names.push(name.clone()),
+        Pattern::Wildcard | Pattern::Literal { .. } => {},
+        Pattern::Tuple(elements) | Pattern::Variant { payload: elements, .. } => {
+            for element in elements {
+                pattern_names(element, names);
+            }
+        },
+    }
+}
+
+/// The name of each variant of an enum, along with how many values it holds
+fn enum_variants(variant_names: &[String], variant_payloads: &[Vec<NodeContext>]) -> Vec<(String, usize)> {
+    variant_names.iter()
+        .cloned()
+        .zip(variant_payloads.iter().map(Vec::len))
+        .collect()
+}
+
+/// What an arithmetic operator does on overflow, which its last character says
+fn overflow(op: &str) -> Option<Overflow> {
+    match op.chars().last() {
+        Some('%') => Some(Overflow::Wrap),
+        Some('|') => Some(Overflow::Saturate),
+        Some('?') => Some(Overflow::Trap),
+        _ => None,
+    }
+}
+
+/// How many values an instruction adds to (or removes from) the stack
+fn stack_effect(kind: &InstructionKind) -> isize {
+    use InstructionKind::*;
+    match kind {
+        ConstBool(_) | ConstInt(_) | ConstFloat(_) | ConstString(_) | ConstUndefined => 1,
+        Push(_) | GetFunction(_) => 1,
+        MakeClosure(_) => 0,
+        MakeTuple(n) | MakeArray(n) => 1 - *n as isize,
+        MakeRange | Index => -1,
+        HasNext(_) | Next(_) => 1,
+        MakeStructType(_) => 1,
+        MakeStruct(fields) => -(fields.len() as isize), // the type is replaced by the struct
+        GetField(_) => 0,
+        SetField(_) => -1,
+        MakeEnumType(_) => 1,
+        IsVariant(_) | GetPayload(_) | GetElement(_) => 0,
+        Unpack(n) => *n as isize - 1,
+        Allocate(_) | Pop(_) | Discard => -1,
+        Drop(n) => -(*n as isize),
+        Add(..) | Subtract(..) | Multiply(..) | ExactDivide | FloorDivide(_) | Test(_) => -1,
+        Negate(_) | Not | Convert(_) => 0,
+        EnterScope | ExitScope(_) => 0,
+        Call(args) => -(*args as isize), // the function and its arguments become its return value
+        CallMember(_, args) => -(*args as isize), // and so do the object and arguments
+        Return | Jump(_) | Unreachable => 0,
+        GetModule(_) => 1,
+        EnterModule(_) => 0,
+        BranchIf(..) => -1,
+    }
+}
+
 fn new_global_scope() -> Scope {
     let mut scope = HashMap::new();
-    scope.insert("true".to_owned(), Value::Bool(true));
-    scope.insert("false".to_owned(), Value::Bool(false));
-    scope.insert("i32".to_owned(), Value::Bool(false));
+    natives::builtins().declare(&mut scope);
     scope
 }
 
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{
+        errors::Error,
+        lexer::Lexer,
+        modules::{
+            tests::project,
+            ModuleLoader,
+        },
+        parser::Parser,
+    };
+
+    fn ir_errors(contents: &'static str) -> Vec<Error> {
+        let errors = RefCell::new(Errors::new());
+        let ast = Parser::new(Lexer::new(contents, errors.borrow_mut())).go().unwrap();
+        IRGenerator::new(&ast, errors.borrow_mut()).go();
+        let borrowed = errors.borrow();
+        borrowed.errors.clone()
+    }
+
+    #[test]
+    fn bad_breaks() {
+        assert_eq!(ir_errors("main := fn() n64 {
+            break
+            continue
+            while true { continue :outer }
+            while true { break :inner }
+            while 1 < 2 { break 5 }
+        }").into_iter().map(|e| match e {
+            Error::IR { message, .. } => message,
+            e => panic!("{:?}", e),
+        }).collect::<Vec<_>>(), vec![
+            "Can't use break outside of a loop".to_owned(),
+            "Can't use continue outside of a loop".to_owned(),
+            "There is no loop labeled outer to continue".to_owned(),
+            "There is no loop labeled inner to break out of".to_owned(),
+            "Only loop expressions can break with a value".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn match_errors() {
+        assert_eq!(ir_errors("Shape := enum { Circle(n64), Rect(n64, n64), Empty }
+        main := fn() n64 {
+            s := Shape.Empty
+            a := match s {
+                Shape.Circle(r) => r
+                Shape.Rect(w, h) if w == h => w
+            }
+            b := match s {
+                Shape.Circle(r, x) => r
+                Shape.Square(w) => w
+                Color.Red => 0
+            }
+            match (true, 1) {
+                (true, _) => 1
+                (false, 0) => 0
+            }
+        }").into_iter().map(|e| match e {
+            Error::IR { message, .. } => message,
+            e => panic!("{:?}", e),
+        }).collect::<Vec<_>>(), vec![
+            "Match is not exhaustive, missing Shape.Rect(_, _), Shape.Empty".to_owned(),
+            "Variant Shape.Circle holds 1 value(s), but the pattern has 2".to_owned(),
+            "Enum Shape has no variant Square".to_owned(),
+            "There is no enum called Color".to_owned(),
+            "Match is not exhaustive, missing (false, _)".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn enums_used_before_their_declaration() {
+        let dir = project("ir-enums", &[
+            ("main.meg", "use shapes
+            area := fn(s: Shape) n64 {
+                match s {
+                    Shape.Square(side) => side * side
+                }
+            }
+            Shape := enum { Square(n64) }
+            main := fn() n64 { area(Shape.Square(3)) }"),
+            ("shapes.meg", "pub Shape := enum { Circle(n64), Empty }
+            pub radius := fn(s: Shape) n64 {
+                match s {
+                    Shape.Circle(r) => r
+                    Shape.Empty => 0
+                }
+            }"),
+        ]);
+
+        let errors = RefCell::new(Errors::new());
+        let modules = ModuleLoader::new(vec![], &errors).load(&dir.join("main.meg"));
+        let root = modules.last().unwrap();
+        IRGenerator::with_modules(&root.ast, &modules, errors.borrow_mut()).go();
+        assert!(errors.borrow().errors.is_empty(), "{:?}", errors.borrow().errors);
+    }
+}
@@ -1,9 +1,13 @@
 //! This module converts raw Meg source code into a stream of tokens for parsing.
 
 use std::cell::RefMut;
-use std::iter::FromIterator;
+use std::collections::VecDeque;
 use std::fmt;
 
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{is_potential_mixed_script_confusable_char, MixedScript};
+use unicode_xid::UnicodeXID;
+
 use crate::errors::Errors;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -31,6 +35,19 @@ pub enum TokenKind {
     Else,
     While,
     Loop,
+    True,
+    False,
+    Return,
+    Break,
+    Continue,
+    Use,
+    Struct,
+    Enum,
+    Match,
+    For,
+    In,
+    Const,
+    Pub,
 
     Newline,
     EOF,
@@ -41,6 +58,23 @@ pub struct Token {
     pub kind: TokenKind,
     pub value: String,
     pub position: usize,
+    pub trivia: Option<Trivia>, // only recorded in lossless mode
+}
+
+/// Everything in the source code around a token, so that the token stream of a lossless lexer can
+/// be turned back into the exact source code it came from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trivia {
+    pub leading: String, // whitespace, comments and blank lines before the token
+    pub text: String, // the token exactly as it was written
+    pub trailing: String, // whitespace and comments after the token, up to the end of the line
+}
+
+impl Token {
+    /// The source code this token came from, including its trivia
+    pub fn lossless_text(&self) -> Option<String> {
+        self.trivia.as_ref().map(|t| format!("{}{}{}", t.leading, t.text, t.trailing))
+    }
 }
 
 impl fmt::Debug for Token {
@@ -60,70 +94,118 @@ enum LexerState {
     Integer,
     Float,
     Identifier, // or keyword
-    Operator,
+    Comment,
 }
 
 pub struct Lexer<'l> {
-    code: Vec<char>,
-    index: usize,
+    code: &'l str,
+    index: usize, // byte offset into code
     state: LexerState,
+    last_kind: Option<TokenKind>,
+    lookahead: VecDeque<Token>,
+    finished: bool,
+    lossless: bool,
+    trivia_start: usize, // where the leading trivia of the next token starts in lossless mode
     errors: RefMut<'l, Errors>,
 }
 
 impl<'l> Lexer<'l> {
     pub fn new(code: &'l str, errors: RefMut<'l, Errors>) -> Self {
         Lexer {
-            code: code.chars().collect(),
+            code,
             index: 0,
             state: LexerState::Normal,
+            last_kind: None,
+            lookahead: VecDeque::new(),
+            finished: false,
+            lossless: false,
+            trivia_start: 0,
             errors,
         } 
     }
 
-    pub fn go(&mut self) -> Vec<Token> {
-        let mut token = vec![];
-        let mut start_position = 0usize;
-        let mut tokens: Vec<Token> = vec![];
+    /// A lexer which records the trivia around every token
+    pub fn new_lossless(code: &'l str, errors: RefMut<'l, Errors>) -> Self {
+        Lexer {
+            lossless: true,
+            ..Lexer::new(code, errors)
+        }
+    }
+
+    /// Looks at the next token without consuming it
+    pub fn peek(&mut self) -> Option<&Token> {
+        self.peek_nth(0)
+    }
+
+    /// Looks n tokens ahead without consuming anything, so peek_nth(0) is the next token
+    pub fn peek_nth(&mut self, n: usize) -> Option<&Token> {
+        while self.lookahead.len() <= n {
+            let token = self.lex_token()?;
+            self.lookahead.push_back(token);
+        }
+        self.lookahead.get(n)
+    }
+
+    /// What comes after the EOF token, for a reader which doesn't stop there
+    pub fn end_of_file(&self) -> Token {
+        Token {
+            kind: TokenKind::EOF,
+            value: "".to_owned(),
+            position: self.code.len(),
+            trivia: None,
+        }
+    }
+
+    pub fn errors(&mut self) -> &mut Errors {
+        &mut self.errors
+    }
+
+    fn current(&self) -> char {
+        self.code[self.index..].chars().next().unwrap_or('\0')
+    }
+
+    fn lex_token(&mut self) -> Option<Token> {
+        if self.finished {
+            return None;
+        }
+
+        let mut start_position = self.index;
 
-        loop {
-            let ch = *self.code.get(self.index).unwrap_or(&'\0');
+        let token = loop {
+            let ch = self.current();
+            let mut token = None;
             match self.state {
                 LexerState::Normal => {
-                    if ch == '\n' && tokens.last()
-                        .map(|t| t.kind.clone())
+                    if ch == '\n' && self.last_kind
+                        .clone()
                         .unwrap_or(TokenKind::Newline) != TokenKind::Newline {
 
-                        tokens.push(Token {
+                        token = Some(Token {
                             kind: TokenKind::Newline,
                             value: "\n".to_owned(),
                             position: self.index,
+                            trivia: None,
                         });
                     } else if ch.is_whitespace() || ch == '\0' {
                     
+                    } else if ch == '#' {
+                        self.state = LexerState::Comment;
                     } else if ch == '"' {
                         self.state = LexerState::String;
                         start_position = self.index;
-                    } else if ch.is_digit(10) {
-                        token.push(ch);   
+                    } else if ch.is_ascii_digit() {
                         self.state = LexerState::Integer;
                         start_position = self.index;
-                    } else if ch == '=' {
-                        if self.code[self.index + 1] == '=' {
-                            self.index += 1;
-                            tokens.push(Token {
-                                kind: TokenKind::Operator,
-                                value: "==".to_owned(),
-                                position: self.index,
-                            });
-                        } else {
-                            tokens.push(Token {
-                                kind: TokenKind::Equals,
-                                value: "=".to_owned(),
-                                position: self.index,
-                            });
-                        }
+                    } else if let Some(op) = self.match_operator() {
+                        token = Some(Token {
+                            kind: TokenKind::Operator,
+                            value: op.to_owned(),
+                            position: self.index,
+                            trivia: None,
+                        });
+                        self.index += op.len() - 1;
                     } else if is_special(ch) {
-                        tokens.push(Token {
+                        token = Some(Token {
                             kind: match ch {
                                 '(' => TokenKind::LParen,
                                 ')' => TokenKind::RParen,
@@ -138,15 +220,18 @@ impl<'l> Lexer<'l> {
                             },
                             value: ch.to_string(),
                             position: self.index,
+                            trivia: None,
                         });
-                    } else if ch.is_ascii_punctuation() {
-                        token.push(ch);
-                        self.state = LexerState::Operator;
-                        start_position = self.index;
-                    } else if ch.is_alphabetic() || ch == '_' {
-                        token.push(ch);
+                    } else if UnicodeXID::is_xid_start(ch) || ch == '_' {
                         self.state = LexerState::Identifier;
                         start_position = self.index;
+                    } else if ch.is_ascii_punctuation() {
+                        let position = self.index;
+                        let run = self.operator_run();
+                        self.errors.lexer(
+                            format!("Found unknown operator {}", run),
+                            position,
+                        );
                     } else {
                         self.errors.lexer(
                             format!("Found invalid character {} ({})", ch, ch),
@@ -155,114 +240,209 @@ impl<'l> Lexer<'l> {
                     }
                 }
                 LexerState::Integer => {
-                    if ch.is_digit(10) {
-                        token.push(ch);
-                    } else if ch == '.' {
-                        token.push(ch);
+                    if ch == '.' && !self.code[self.index + 1..].starts_with('.') {
+                        // `1..5` is a range rather than a float
                         self.state = LexerState::Float;
-                    } else {
-                        tokens.push(Token {
+                    } else if !ch.is_ascii_digit() {
+                        self.state = LexerState::Normal;
+                        break Token {
                             kind: TokenKind::IntegerLiteral,
-                            value: String::from_iter(token.clone()),
+                            value: self.code[start_position..self.index].to_owned(),
                             position: start_position,
-                        });
-                        token.clear();
-                        self.state = LexerState::Normal;
-                        continue;
+                            trivia: None,
+                        };
                     }
                 }
                 LexerState::Float => {
-                    if ch.is_digit(10) {
-                        token.push(ch);
-                    } else if ch == '.' {
+                    if !ch.is_ascii_digit() {
                         self.state = LexerState::Normal;
-                        continue;
-                    } else {
-                        tokens.push(Token {
+                        break Token {
                             kind: TokenKind::FloatLiteral,
-                            value: String::from_iter(token.clone()),
+                            value: self.code[start_position..self.index].to_owned(),
                             position: start_position,
-                        });
-                        token.clear();
-                        self.state = LexerState::Normal;
-                        continue;
+                            trivia: None,
+                        };
                     }
-
                 }
                 LexerState::String => {
                     if ch == '"' {
-                        tokens.push(Token {
+                        token = Some(Token {
                             kind: TokenKind::StringLiteral,
-                            value: String::from_iter(token.clone()),
+                            value: self.unescape(start_position + 1),
                             position: start_position,
+                            trivia: None,
                         });
-                        token.clear();
                         self.state = LexerState::Normal;
                     } else if ch == '\\' {
-                        token.push(ch);
                         self.state = LexerState::Escape;
-                    } else if ch == '\0' {
-
-                    } else {
-                        token.push(ch);
                     }
                 }
-                LexerState::Operator => {
-                    if ch.is_ascii_punctuation() {
-                        token.push(ch);
-                    } else {
-                        tokens.push(Token {
-                            kind: TokenKind::Operator,
-                            value: String::from_iter(token.clone()),
+                LexerState::Identifier => {
+                    if !UnicodeXID::is_xid_continue(ch) {
+                        let value = self.identifier(start_position);
+                        self.state = LexerState::Normal;
+                        break try_convert_keyword(value.clone(), start_position).unwrap_or(Token {
+                            kind: TokenKind::Identifier,
+                            value,
                             position: start_position,
+                            trivia: None,
                         });
-                        token.clear();
-                        self.state = LexerState::Normal;
-                        continue;
                     }
                 }
-                LexerState::Identifier => {
-                    if ch.is_alphanumeric() || ch == '_' {
-                        token.push(ch);
-                    } else {
-                        tokens.push(try_convert_keyword(String::from_iter(token.clone()), start_position).unwrap_or(Token {
-                            kind: TokenKind::Identifier,
-                            value: String::from_iter(token.clone()),
-                            position: start_position,
-                        }));
-                        token.clear();
+                LexerState::Comment => {
+                    if ch == '\n' {
                         self.state = LexerState::Normal;
                         continue;
                     }
                 }
+                // escapes are only checked once the whole string is found, in unescape
                 LexerState::Escape => {
-                    todo!();
+                    self.state = LexerState::String;
                 }
             }
 
             if self.index >= self.code.len() {
-                if self.state == LexerState::String {
+                if self.state == LexerState::String || self.state == LexerState::Escape {
                     self.errors.lexer(
-                        format!("Found EOF while parsing a string literal \"{}\"", String::from_iter(token.clone())),
+                        format!("Found EOF while parsing a string literal \"{}\"", &self.code[start_position + 1..]),
                         self.index,
                     );
                 }
 
-                tokens.push(Token {
+                self.finished = true;
+                break Token {
                     kind: TokenKind::EOF,
                     value: "".to_owned(),
                     position: self.index,
-                });
-                break;
+                    trivia: None,
+                };
+            }
+
+            self.index += ch.len_utf8();
+
+            if let Some(token) = token {
+                break token;
+            }
+        };
+
+        self.last_kind = Some(token.kind.clone());
+        Some(if self.lossless {
+            self.with_trivia(token)
+        } else {
+            token
+        })
+    }
+
+    /// Attaches trivia to a token which has just been lexed, consuming its trailing trivia
+    fn with_trivia(&mut self, mut token: Token) -> Token {
+        let leading = self.code[self.trivia_start..token.position].to_owned();
+        let text = self.code[token.position..self.index].to_owned();
+
+        let trailing_start = self.index;
+        if !matches!(token.kind, TokenKind::Newline | TokenKind::EOF) {
+            loop {
+                let ch = self.current();
+                if ch == '#' {
+                    while !matches!(self.current(), '\n' | '\0') {
+                        self.index += self.current().len_utf8();
+                    }
+                } else if ch.is_whitespace() && ch != '\n' {
+                    self.index += ch.len_utf8();
+                } else {
+                    break;
+                }
             }
+        }
+        let trailing = self.code[trailing_start..self.index].to_owned();
+        self.trivia_start = self.index;
+
+        token.trivia = Some(Trivia { leading, text, trailing });
+        token
+    }
+
+    /// The contents of the string literal from `start` up to the current character, with its escape
+    /// sequences replaced
+    fn unescape(&mut self, start: usize) -> String {
+        let mut value = String::new();
+        let mut chars = self.code[start..self.index].char_indices();
+        while let Some((_, ch)) = chars.next() {
+            if ch != '\\' {
+                value.push(ch);
+                continue;
+            }
+            let (offset, escaped) = chars.next().unwrap();
+            value.push(match escaped {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                '\\' | '"' => escaped,
+                _ => {
+                    self.errors.lexer(
+                        format!("Found unknown escape sequence \\{}", escaped),
+                        start + offset - 1,
+                    );
+                    escaped
+                },
+            });
+        }
+        value
+    }
+
+    /// The NFC normalized value of the identifier which ends at the current index, warning if it
+    /// mixes scripts in a way that makes it look like a different identifier
+    fn identifier(&mut self, start_position: usize) -> String {
+        let raw = &self.code[start_position..self.index];
+        if !raw.is_single_script() && raw.chars().any(is_potential_mixed_script_confusable_char) {
+            self.errors.lexer_warning(
+                format!("Identifier {} mixes scripts and contains characters that can be confused with other scripts", raw),
+                start_position,
+            );
+        }
+        raw.nfc().collect()
+    }
 
+    /// Finds the longest operator in OPERATORS which starts at the current index
+    fn match_operator(&self) -> Option<&'static str> {
+        OPERATORS.iter()
+            .filter(|op| self.code[self.index..].starts_with(*op))
+            .max_by_key(|op| op.len())
+            .copied()
+    }
+
+    /// The run of punctuation starting at the current index, used when reporting unknown operators
+    fn operator_run(&mut self) -> &'l str {
+        let start = self.index;
+        while let Some(ch) = self.code[self.index..].chars().next() {
+            if !ch.is_ascii_punctuation() || is_special(ch) || ch == '"' || ch == '_'
+                || self.match_operator().is_some() {
+                break;
+            }
             self.index += 1;
         }
+        self.index -= 1;
+        &self.code[start..=self.index]
+    }
+}
 
-        tokens
+impl<'l> Iterator for Lexer<'l> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        self.lookahead.pop_front().or_else(|| self.lex_token())
     }
 }
 
+/// Every operator the lexer knows about. Operators are lexed with maximal munch, so when one
+/// operator is a prefix of another (such as `/` and `//`) the longer one always wins.
+const OPERATORS: &[&str] = &[
+    "..",
+    "==", "!=", ">=", "<=", "=>",
+    "+", "-", "*", "/", "//",
+    "+%", "-%", "*%", "+|", "-|", "*|", "+?", "-?", "*?",
+    "!", "<", ">", ".",
+];
+
 fn is_special(ch: char) -> bool {
     ['(', ')', '[', ']', '{', '}', ':', '=', ','].contains(&ch)
 }
@@ -276,10 +456,25 @@ fn try_convert_keyword(s: String, position: usize) -> Option<Token> {
             "else" => TokenKind::Else,
             "while" => TokenKind::While,
             "loop" => TokenKind::Loop,
+            "true" => TokenKind::True,
+            "false" => TokenKind::False,
+            "return" => TokenKind::Return,
+            "break" => TokenKind::Break,
+            "continue" => TokenKind::Continue,
+            "use" => TokenKind::Use,
+            "struct" => TokenKind::Struct,
+            "enum" => TokenKind::Enum,
+            "match" => TokenKind::Match,
+            "for" => TokenKind::For,
+            "in" => TokenKind::In,
+            "const" => TokenKind::Const,
+            "pub" => TokenKind::Pub,
+            "and" | "or" | "not" => TokenKind::Operator, // word operators can't be identifiers
             _ => return None,
         },
         value: s,
         position,
+        trivia: None,
     })
 }
 
@@ -291,15 +486,15 @@ mod tests {
 
     fn lexer_results(contents: &'static str) -> Vec<Token> {
         let errors = RefCell::new(crate::errors::Errors::new());
-        let mut lexer = Lexer::new(&contents, errors.borrow_mut());
-        lexer.go()
+        let lexer = Lexer::new(&contents, errors.borrow_mut());
+        lexer.collect()
     }
 
     fn lexer_errors(contents: &'static str) -> Vec<crate::errors::Error> {
         let errors = RefCell::new(crate::errors::Errors::new());
         {
-            let mut lexer = Lexer::new(&contents, errors.borrow_mut());
-            let _ = lexer.go();
+            let lexer = Lexer::new(&contents, errors.borrow_mut());
+            let _ = lexer.count();
         }
         let borrowed = errors.borrow();
         borrowed.errors.clone()
@@ -312,20 +507,38 @@ mod tests {
                 kind: TokenKind::StringLiteral,
                 value: "hello world".to_owned(),
                 position: 0,
+                trivia: None,
             },
             Token {
                 kind: TokenKind::Identifier,
                 value: "more_stuff".to_owned(),
                 position: 14,
+                trivia: None,
             },
             Token {
                 kind: TokenKind::EOF,
                 value: "".to_owned(),
                 position: 24,
+                trivia: None,
             },
         ]);
     }
 
+    #[test]
+    fn escape_sequences() {
+        assert_eq!(lexer_results(r#""a\"b\n\\" "\q""#).into_iter().map(|t| t.value).collect::<Vec<_>>(), vec![
+            "a\"b\n\\".to_owned(),
+            "q".to_owned(),
+            "".to_owned(),
+        ]);
+        assert_eq!(lexer_errors(r#""a\"b\n\\" "\q""#), vec![
+            crate::errors::Error::Lexer {
+                message: "Found unknown escape sequence \\q".to_owned(),
+                position: 12,
+            }
+        ]);
+    }
+
     #[test]
     fn string_literal_ends_too_early() {
         assert_eq!(lexer_results(r#""hello world more_stuff"#), vec![
@@ -333,6 +546,7 @@ mod tests {
                 kind: TokenKind::EOF,
                 value: "".to_owned(),
                 position: 23,
+                trivia: None,
             }
         ]);
         assert_eq!(lexer_errors(r#""hello world more_stuff"#), vec![
@@ -342,4 +556,206 @@ mod tests {
             }
         ]);
     }
+
+    #[test]
+    fn operators_maximal_munch() {
+        assert_eq!(lexer_results("a*-b//c"), vec![
+            Token {
+                kind: TokenKind::Identifier,
+                value: "a".to_owned(),
+                position: 0,
+                trivia: None,
+            },
+            Token {
+                kind: TokenKind::Operator,
+                value: "*".to_owned(),
+                position: 1,
+                trivia: None,
+            },
+            Token {
+                kind: TokenKind::Operator,
+                value: "-".to_owned(),
+                position: 2,
+                trivia: None,
+            },
+            Token {
+                kind: TokenKind::Identifier,
+                value: "b".to_owned(),
+                position: 3,
+                trivia: None,
+            },
+            Token {
+                kind: TokenKind::Operator,
+                value: "//".to_owned(),
+                position: 4,
+                trivia: None,
+            },
+            Token {
+                kind: TokenKind::Identifier,
+                value: "c".to_owned(),
+                position: 6,
+                trivia: None,
+            },
+            Token {
+                kind: TokenKind::EOF,
+                value: "".to_owned(),
+                position: 7,
+                trivia: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn unknown_operator() {
+        assert_eq!(lexer_errors("a +$% b"), vec![
+            crate::errors::Error::Lexer {
+                message: "Found unknown operator $%".to_owned(),
+                position: 3,
+            }
+        ]);
+    }
+
+    #[test]
+    fn byte_offsets() {
+        assert_eq!(lexer_results("\"héllo\" x"), vec![
+            Token {
+                kind: TokenKind::StringLiteral,
+                value: "héllo".to_owned(),
+                position: 0,
+                trivia: None,
+            },
+            Token {
+                kind: TokenKind::Identifier,
+                value: "x".to_owned(),
+                position: 9,
+                trivia: None,
+            },
+            Token {
+                kind: TokenKind::EOF,
+                value: "".to_owned(),
+                position: 10,
+                trivia: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn lookahead() {
+        let errors = RefCell::new(crate::errors::Errors::new());
+        let mut lexer = Lexer::new("a := 1", errors.borrow_mut());
+        assert_eq!(lexer.peek_nth(2).map(|t| t.kind.clone()), Some(TokenKind::Equals));
+        assert_eq!(lexer.peek().map(|t| t.value.clone()), Some("a".to_owned()));
+        assert_eq!(lexer.next().map(|t| t.value), Some("a".to_owned()));
+        assert_eq!(lexer.map(|t| t.kind).collect::<Vec<_>>(), vec![
+            TokenKind::Colon,
+            TokenKind::Equals,
+            TokenKind::IntegerLiteral,
+            TokenKind::EOF,
+        ]);
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(lexer_results("x # the answer\n").into_iter().map(|t| t.kind).collect::<Vec<_>>(), vec![
+            TokenKind::Identifier,
+            TokenKind::Newline,
+            TokenKind::EOF,
+        ]);
+    }
+
+    #[test]
+    fn lossless_round_trip() {
+        let source = "\n# leading comment\nx := 1  # trailing\n\n\n    \"s\" +  2.5\n}  ";
+        let errors = RefCell::new(crate::errors::Errors::new());
+        let lexer = Lexer::new_lossless(source, errors.borrow_mut());
+        let tokens: Vec<Token> = lexer.collect();
+
+        assert_eq!(tokens[0].trivia, Some(Trivia {
+            leading: "\n# leading comment\n".to_owned(),
+            text: "x".to_owned(),
+            trailing: " ".to_owned(),
+        }));
+        assert_eq!(tokens[3].trivia, Some(Trivia {
+            leading: "".to_owned(),
+            text: "1".to_owned(),
+            trailing: "  # trailing".to_owned(),
+        }));
+        assert_eq!(
+            tokens.iter().map(|t| t.lossless_text().unwrap()).collect::<String>(),
+            source,
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        // the second identifier is decomposed, with a combining acute accent
+        assert_eq!(lexer_results("größe café cafe\u{301}").into_iter().map(|t| t.value).collect::<Vec<_>>(), vec![
+            "größe".to_owned(),
+            "café".to_owned(),
+            "café".to_owned(),
+            "".to_owned(),
+        ]);
+        assert_eq!(lexer_errors("a\u{2060}b"), vec![
+            crate::errors::Error::Lexer {
+                message: "Found invalid character \u{2060} (\u{2060})".to_owned(),
+                position: 1,
+            }
+        ]);
+    }
+
+    #[test]
+    fn mixed_script_confusables() {
+        let errors = RefCell::new(crate::errors::Errors::new());
+        {
+            // the "а" in this identifier is Cyrillic
+            let lexer = Lexer::new("pаypal paypal", errors.borrow_mut());
+            let _ = lexer.count();
+        }
+        assert_eq!(errors.borrow().warnings, vec![
+            crate::errors::Warning::Lexer {
+                message: "Identifier pаypal mixes scripts and contains characters that can be confused with other scripts".to_owned(),
+                position: 0,
+            }
+        ]);
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(lexer_results("true or not false_ in").into_iter().map(|t| (t.kind, t.value)).collect::<Vec<_>>(), vec![
+            (TokenKind::True, "true".to_owned()),
+            (TokenKind::Operator, "or".to_owned()),
+            (TokenKind::Operator, "not".to_owned()),
+            (TokenKind::Identifier, "false_".to_owned()),
+            (TokenKind::In, "in".to_owned()),
+            (TokenKind::EOF, "".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn number_ranges() {
+        assert_eq!(lexer_results("1..5 1.5..2").into_iter().map(|t| (t.kind, t.value)).collect::<Vec<_>>(), vec![
+            (TokenKind::IntegerLiteral, "1".to_owned()),
+            (TokenKind::Operator, "..".to_owned()),
+            (TokenKind::IntegerLiteral, "5".to_owned()),
+            (TokenKind::FloatLiteral, "1.5".to_owned()),
+            (TokenKind::Operator, "..".to_owned()),
+            (TokenKind::IntegerLiteral, "2".to_owned()),
+            (TokenKind::EOF, "".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn overflow_operators() {
+        assert_eq!(lexer_results("a+%b*|-c-?1").into_iter().map(|t| (t.kind, t.value)).collect::<Vec<_>>(), vec![
+            (TokenKind::Identifier, "a".to_owned()),
+            (TokenKind::Operator, "+%".to_owned()),
+            (TokenKind::Identifier, "b".to_owned()),
+            (TokenKind::Operator, "*|".to_owned()),
+            (TokenKind::Operator, "-".to_owned()),
+            (TokenKind::Identifier, "c".to_owned()),
+            (TokenKind::Operator, "-?".to_owned()),
+            (TokenKind::IntegerLiteral, "1".to_owned()),
+            (TokenKind::EOF, "".to_owned()),
+        ]);
+    }
 }
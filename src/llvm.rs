@@ -1,2 +1,1461 @@
-//! This module converts Meg IR into LLVM IR
-//! TODO What goes here???
+//! This module converts Meg IR into LLVM IR. Compiled code keeps numbers and bools in registers
+//! and knows every other value while compiling, so for now only programs which stick to numbers,
+//! bools, functions of them and the natives which work on them can be compiled.
+
+use std::cell::RefMut;
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::ffi::{
+    CStr,
+    CString,
+};
+use std::fmt;
+use std::os::raw::c_char;
+use std::ptr;
+
+use llvm_sys::{
+    analysis::{
+        LLVMVerifierFailureAction,
+        LLVMVerifyModule,
+    },
+    core::*,
+    prelude::*,
+    LLVMIntPredicate,
+    LLVMRealPredicate,
+};
+
+use crate::{
+    errors::Errors,
+    ir::{
+        CompareType,
+        Environment,
+        InstructionKind,
+        IntType,
+        NumberType,
+        Overflow,
+        ScalarType,
+        Value,
+    },
+    natives::Native,
+    typeck::Type,
+};
+
+const NO_NAME: *const c_char = b"\0".as_ptr() as *const c_char;
+
+const TOP_LEVEL: usize = 0; // the function holding the top level of every module, which runs first
+
+// integers of unknown type, which are only ever literals, are worked on in 64 bits
+const I64: IntType = IntType { signed: true, bits: 64 };
+
+/// What a register holds. Integers are held in 64 bits, extended from the width of their type
+/// the way its signedness says, and floats are held in 64 bits even when they are f32.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Bool,
+    Integer(Option<IntType>),
+    Float,
+}
+
+impl From<ScalarType> for Kind {
+    fn from(typ: ScalarType) -> Self {
+        match typ {
+            ScalarType::Bool => Kind::Bool,
+            ScalarType::Number(NumberType::Integer(int)) => Kind::Integer(Some(int)),
+            ScalarType::Number(NumberType::Float { .. }) => Kind::Float,
+        }
+    }
+}
+
+/// A value on the stack of the function being compiled
+#[derive(Clone, Copy)]
+enum Operand<'g> {
+    Register(LLVMValueRef, Kind),
+    Undefined,
+    Function(usize),
+    Native(&'g Native),
+    Module(usize),
+}
+
+impl Operand<'_> {
+    /// Whether two values known while compiling are the same
+    fn same(&self, other: &Operand) -> bool {
+        match (self, other) {
+            (Operand::Undefined, Operand::Undefined) => true,
+            (Operand::Function(a), Operand::Function(b)) | (Operand::Module(a), Operand::Module(b)) => a == b,
+            (Operand::Native(a), Operand::Native(b)) => ptr::eq(*a, *b),
+            _ => false,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Operand::Register(_, Kind::Bool) => "a bool",
+            Operand::Register(_, Kind::Integer(_)) => "an integer",
+            Operand::Register(_, Kind::Float) => "a float",
+            Operand::Undefined => "undefined",
+            Operand::Function(_) => "a function",
+            Operand::Native(_) => "a native function",
+            Operand::Module(_) => "a module",
+        }
+    }
+}
+
+/// Variables holding numbers and bools live in memory, which mem2reg can turn back into registers
+#[derive(Clone, Copy)]
+enum Variable<'g> {
+    Unset, // declared, but not given a value yet
+    Memory(LLVMValueRef, Kind),
+    Known(Operand<'g>), // holding a value which is known while compiling
+}
+
+/// Where a variable is found: among the locals of the function being compiled or at the top level
+/// of a module
+enum Slot {
+    Local(usize),
+    Global(usize, String),
+}
+
+/// What the stack and the scopes hold on the way into a block. Values in registers come through
+/// phi nodes, so every way into the block has to leave the same kinds of values on the stack.
+#[derive(Clone)]
+struct Entry<'g> {
+    stack: Vec<Operand<'g>>,
+    scopes: Vec<HashMap<String, usize>>,
+    module: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arithmetic {
+    Add,
+    Subtract,
+    Multiply,
+}
+
+pub struct LLVMGenerator<'g> {
+    env: &'g Environment,
+    pub errors: RefMut<'g, Errors>,
+    pub overflow: Overflow, // what plain integer arithmetic does with results that don't fit
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    builder: LLVMBuilderRef,
+    functions: HashMap<usize, LLVMValueRef>, // Meg functions are declared the first time they are called
+    queue: Vec<usize>, // the functions which have been declared but not generated yet
+    globals: Vec<HashMap<String, Variable<'g>>>, // the top level variables of each module
+
+    // the function being generated
+    current: usize,
+    function: LLVMValueRef,
+    allocas: LLVMBasicBlockRef, // the first block, which holds the memory of every local variable
+    blocks: HashMap<usize, LLVMBasicBlockRef>,
+    entries: HashMap<usize, Entry<'g>>,
+    generated: HashSet<usize>, // the blocks which have been passed, whether or not anything reached them
+    variables: Vec<Variable<'g>>,
+    stack: Vec<Operand<'g>>,
+    scopes: Vec<HashMap<String, usize>>, // each variable's index in `variables`
+    current_module: usize, // the module whose variables the code being generated sees
+}
+
+impl<'g> LLVMGenerator<'g> {
+    pub fn new(env: &'g Environment, errors: RefMut<'g, Errors>) -> Self {
+        unsafe {
+            let context = LLVMContextCreate();
+            LLVMGenerator {
+                env,
+                errors,
+                overflow: Overflow::Trap,
+                context,
+                module: LLVMModuleCreateWithNameInContext(b"meg\0".as_ptr() as *const c_char, context),
+                builder: LLVMCreateBuilderInContext(context),
+                functions: HashMap::new(),
+                queue: vec![],
+                globals: env.modules.iter().map(|_| HashMap::new()).collect(),
+
+                current: TOP_LEVEL,
+                function: ptr::null_mut(),
+                allocas: ptr::null_mut(),
+                blocks: HashMap::new(),
+                entries: HashMap::new(),
+                generated: HashSet::new(),
+                variables: vec![],
+                stack: vec![],
+                scopes: vec![],
+                current_module: 0,
+            }
+        }
+    }
+
+    /// The top level of the program becomes the C `main` function. Meg functions are only
+    /// generated once something calls them.
+    pub fn go(&mut self) {
+        let main = unsafe {
+            let typ = LLVMFunctionType(self.int_type(32), ptr::null_mut(), 0, 0);
+            LLVMAddFunction(self.module, b"main\0".as_ptr() as *const c_char, typ)
+        };
+        self.functions.insert(TOP_LEVEL, main);
+        self.queue.push(TOP_LEVEL);
+        while let Some(id) = self.queue.pop() {
+            if self.generate(id).is_none() {
+                return;
+            }
+        }
+
+        unsafe {
+            let mut message = ptr::null_mut();
+            if LLVMVerifyModule(self.module, LLVMVerifierFailureAction::LLVMReturnStatusAction, &mut message) != 0 {
+                let message = CStr::from_ptr(message).to_string_lossy().into_owned();
+                self.errors.llvm(format!("Generated invalid LLVM IR: {}", message));
+            }
+            LLVMDisposeMessage(message);
+        }
+    }
+
+    fn error<T>(&mut self, message: String) -> Option<T> {
+        self.errors.llvm(message);
+        None
+    }
+
+    /// Blocks are generated in order, so a block is reached by a jump or a fall through from a
+    /// block before it, except for loops jumping back to where they started
+    fn generate(&mut self, id: usize) -> Option<()> {
+        let env = self.env;
+        let function = &env.functions[&id];
+        self.current = id;
+        self.function = self.functions[&id];
+        self.blocks.clear();
+        self.entries.clear();
+        self.generated.clear();
+        self.variables.clear();
+        self.current_module = function.module;
+        // the arguments are left on the stack for the first block to bind
+        self.scopes = if id == TOP_LEVEL { vec![] } else { vec![HashMap::new()] };
+        self.stack = match &function.signature {
+            Some(signature) => signature.params.iter()
+                .enumerate()
+                .map(|(i, param)| unsafe {
+                    Operand::Register(LLVMGetParam(self.function, i as u32), Kind::from(*param))
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        unsafe {
+            self.allocas = LLVMAppendBasicBlockInContext(self.context, self.function, b"entry\0".as_ptr() as *const c_char);
+            LLVMPositionBuilderAtEnd(self.builder, self.allocas);
+        }
+        let first = self.edge(function.blocks[0].id)?;
+
+        for (i, block) in function.blocks.iter().enumerate() {
+            self.generated.insert(block.id);
+            let entry = match self.entries.get(&block.id) {
+                Some(entry) => entry.clone(),
+                None => continue, // nothing can reach it
+            };
+            unsafe { LLVMPositionBuilderAtEnd(self.builder, self.blocks[&block.id]) };
+            self.stack = entry.stack;
+            self.scopes = entry.scopes;
+            self.current_module = entry.module;
+
+            let mut ended = false;
+            for ins in &block.instructions {
+                if self.instruction(&ins.kind)? {
+                    ended = true;
+                    break;
+                }
+            }
+            if !ended {
+                match function.blocks.get(i + 1) {
+                    Some(next) => {
+                        let next = self.edge(next.id)?;
+                        unsafe { LLVMBuildBr(self.builder, next) };
+                    },
+                    None => self.return_()?,
+                }
+            }
+        }
+
+        unsafe {
+            LLVMPositionBuilderAtEnd(self.builder, self.allocas);
+            LLVMBuildBr(self.builder, first);
+        }
+        Some(())
+    }
+
+    /// Generates an instruction, returning whether it ended its block. Anything after that in the
+    /// same block can never run.
+    fn instruction(&mut self, kind: &'g InstructionKind) -> Option<bool> {
+        use InstructionKind::*;
+        match kind {
+            ConstBool(value) => {
+                let value = unsafe { LLVMConstInt(self.int_type(1), *value as u64, 0) };
+                self.stack.push(Operand::Register(value, Kind::Bool));
+            },
+            ConstInt(value) => {
+                // a literal only goes past the i64 range when it's an n64
+                let int = (*value > i64::MAX as i128).then_some(IntType { signed: false, bits: 64 });
+                let value = self.const_int(self.int_type(64), *value);
+                self.stack.push(Operand::Register(value, Kind::Integer(int)));
+            },
+            ConstFloat(value) => {
+                let value = unsafe { LLVMConstReal(self.float_type(), *value) };
+                self.stack.push(Operand::Register(value, Kind::Float));
+            },
+            ConstUndefined => self.stack.push(Operand::Undefined),
+
+            Allocate(name) => self.allocate(name)?,
+            Push(name) => {
+                let operand = self.read(name)?;
+                self.stack.push(operand);
+            },
+            Pop(name) => {
+                let operand = self.stack.pop().unwrap();
+                let slot = match self.slot(name) {
+                    Some(slot) => slot,
+                    None => return self.error(format!("Variable {} is not defined", name)),
+                };
+                self.store(name, slot, operand)?;
+            },
+            Discard => {
+                self.stack.pop().unwrap();
+            },
+            Drop(count) => self.stack.truncate(self.stack.len() - count),
+            EnterScope => self.scopes.push(HashMap::new()),
+            ExitScope(count) => self.scopes.truncate(self.scopes.len() - count),
+
+            Add(int, overflow) => self.arithmetic(Arithmetic::Add, int, overflow)?,
+            Subtract(int, overflow) => self.arithmetic(Arithmetic::Subtract, int, overflow)?,
+            Multiply(int, overflow) => self.arithmetic(Arithmetic::Multiply, int, overflow)?,
+            ExactDivide => self.exact_divide()?,
+            FloorDivide(int) => self.floor_divide(int)?,
+            Negate(int) => self.negate(int)?,
+            Convert(number) => self.convert(number)?,
+            Not => self.not()?,
+            Test(compare_type) => self.test(compare_type)?,
+
+            Call(args) => {
+                let callee = self.stack.pop().unwrap();
+                self.call(callee, *args)?;
+            },
+            CallMember(name, args) => self.call_member(name, *args)?,
+            Return => {
+                self.return_()?;
+                return Some(true);
+            },
+            BranchIf(then_block, else_block) => {
+                self.branch_if(*then_block, *else_block)?;
+                return Some(true);
+            },
+            Jump(block) => {
+                let block = self.edge(*block)?;
+                unsafe { LLVMBuildBr(self.builder, block) };
+                return Some(true);
+            },
+            Unreachable => {
+                self.panic("No match arm matched the value");
+                return Some(true);
+            },
+
+            GetFunction(func) => self.stack.push(Operand::Function(*func)),
+            MakeClosure(names) => self.make_closure(names)?,
+            GetModule(module) => self.stack.push(Operand::Module(*module)),
+            EnterModule(module) => self.current_module = *module,
+
+            kind => return self.error(format!("{:?} can't be compiled yet", kind)),
+        }
+        Some(false)
+    }
+
+    /// The LLVM block an IR block becomes, which is made the first time something jumps to it
+    fn block(&mut self, id: usize) -> LLVMBasicBlockRef {
+        let (context, function) = (self.context, self.function);
+        *self.blocks.entry(id).or_insert_with(|| unsafe {
+            let name = CString::new(format!("block{}", id)).unwrap();
+            LLVMAppendBasicBlockInContext(context, function, name.as_ptr())
+        })
+    }
+
+    /// Carries the stack and the scopes over into the block being jumped or fallen through to,
+    /// returning the LLVM block to branch to
+    fn edge(&mut self, target: usize) -> Option<LLVMBasicBlockRef> {
+        let from = unsafe { LLVMGetInsertBlock(self.builder) };
+        let to = self.block(target);
+
+        if let Some(entry) = self.entries.get(&target) {
+            if entry.stack.len() != self.stack.len() {
+                return self.error(format!("Block {} is reached with different numbers of values on the stack", target));
+            }
+            let mut incoming = vec![];
+            for (phi, operand) in entry.stack.iter().zip(&self.stack) {
+                match (phi, operand) {
+                    (Operand::Register(phi, _), Operand::Register(value, _))
+                        if unsafe { LLVMTypeOf(*phi) == LLVMTypeOf(*value) } => incoming.push((*phi, *value)),
+                    (phi, operand) if phi.same(operand) => {},
+                    (phi, operand) => {
+                        let message = format!(
+                            "Block {} is reached with {} on the stack one way and {} another",
+                            target, phi.describe(), operand.describe(),
+                        );
+                        return self.error(message);
+                    },
+                }
+            }
+            for (phi, mut value) in incoming {
+                let mut from = from;
+                unsafe { LLVMAddIncoming(phi, &mut value, &mut from, 1) };
+            }
+            return Some(to);
+        }
+        if self.generated.contains(&target) {
+            return self.error(format!("Block {} is jumped back to, but was never reached before", target));
+        }
+
+        unsafe { LLVMPositionBuilderAtEnd(self.builder, to) };
+        let stack = self.stack.iter()
+            .map(|operand| match *operand {
+                Operand::Register(mut value, kind) => unsafe {
+                    let phi = LLVMBuildPhi(self.builder, LLVMTypeOf(value), NO_NAME);
+                    let mut from = from;
+                    LLVMAddIncoming(phi, &mut value, &mut from, 1);
+                    Operand::Register(phi, kind)
+                },
+                operand => operand,
+            })
+            .collect();
+        unsafe { LLVMPositionBuilderAtEnd(self.builder, from) };
+
+        self.entries.insert(target, Entry {
+            stack,
+            scopes: self.scopes.clone(),
+            module: self.current_module,
+        });
+        Some(to)
+    }
+
+    fn branch_if(&mut self, then_block: usize, else_block: usize) -> Option<()> {
+        let condition = match self.stack.pop().unwrap() {
+            Operand::Register(value, Kind::Bool) => value,
+            operand => return self.error(format!("Expected a bool to branch on, but found {}", operand.describe())),
+        };
+        let then_block = self.edge(then_block)?;
+        let else_block = self.edge(else_block)?;
+        unsafe { LLVMBuildCondBr(self.builder, condition, then_block, else_block) };
+        Some(())
+    }
+
+    /// The top level returns from `main` successfully, while the value a Meg function returns has
+    /// to be of the type it was declared to return
+    fn return_(&mut self) -> Option<()> {
+        if self.current == TOP_LEVEL {
+            unsafe { LLVMBuildRet(self.builder, LLVMConstInt(self.int_type(32), 0, 0)) };
+            return Some(());
+        }
+
+        let env = self.env;
+        match env.functions[&self.current].signature.as_ref().and_then(|signature| signature.rets.first()) {
+            Some(ret) => {
+                let operand = self.stack.pop().unwrap();
+                let value = self.register(operand, *ret)?;
+                unsafe { LLVMBuildRet(self.builder, value) };
+            },
+            None => unsafe {
+                LLVMBuildRetVoid(self.builder);
+            },
+        }
+        Some(())
+    }
+
+    /// Declares a variable in the innermost scope, which at the top level of a module is the
+    /// module's own scope
+    fn allocate(&mut self, name: &str) -> Option<()> {
+        let operand = self.stack.pop().unwrap();
+        let slot = match self.scopes.last_mut() {
+            Some(scope) => {
+                scope.insert(name.to_owned(), self.variables.len());
+                self.variables.push(Variable::Unset);
+                Slot::Local(self.variables.len() - 1)
+            },
+            None => {
+                self.globals[self.current_module].insert(name.to_owned(), Variable::Unset);
+                Slot::Global(self.current_module, name.to_owned())
+            },
+        };
+        self.store(name, slot, operand)
+    }
+
+    /// Functions only see their own variables and those of their module, like in the interpreter
+    fn slot(&self, name: &str) -> Option<Slot> {
+        if let Some(&index) = self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            Some(Slot::Local(index))
+        } else if self.globals[self.current_module].contains_key(name) {
+            Some(Slot::Global(self.current_module, name.to_owned()))
+        } else {
+            None
+        }
+    }
+
+    fn variable(&mut self, slot: &Slot) -> &mut Variable<'g> {
+        match slot {
+            Slot::Local(index) => &mut self.variables[*index],
+            Slot::Global(module, name) => self.globals[*module].get_mut(name).unwrap(),
+        }
+    }
+
+    fn read(&mut self, name: &str) -> Option<Operand<'g>> {
+        let env = self.env;
+        let variable = match self.slot(name) {
+            Some(slot) => *self.variable(&slot),
+            None => match env.scopes[0].get(name) {
+                Some(Value::Native(native)) => Variable::Known(Operand::Native(native)),
+                _ => return self.error(format!("Variable {} is not defined", name)),
+            },
+        };
+        match variable {
+            Variable::Unset => self.error(format!("{} is used before it is given a value", name)),
+            Variable::Memory(pointer, kind) => {
+                Some(Operand::Register(unsafe { LLVMBuildLoad(self.builder, pointer, NO_NAME) }, kind))
+            },
+            Variable::Known(operand) => Some(operand),
+        }
+    }
+
+    /// Memory is allocated the first time a variable is given a number or a bool. Any other value
+    /// has to be known while compiling, so it can only be given once.
+    fn store(&mut self, name: &str, slot: Slot, operand: Operand<'g>) -> Option<()> {
+        let variable = *self.variable(&slot);
+        let stored = match (variable, operand) {
+            // only declarations store undefined, before the variable is given its value
+            (variable, Operand::Undefined) => variable,
+            (Variable::Memory(pointer, kind), Operand::Register(value, stored))
+                if unsafe { LLVMTypeOf(value) == self.kind_type(kind) } => unsafe {
+                LLVMBuildStore(self.builder, value, pointer);
+                match (kind, stored) {
+                    (Kind::Integer(None), Kind::Integer(int)) => Variable::Memory(pointer, Kind::Integer(int)),
+                    _ => variable,
+                }
+            },
+            (Variable::Unset, Operand::Register(value, kind)) => unsafe {
+                let pointer = self.memory(name, &slot, LLVMTypeOf(value));
+                LLVMBuildStore(self.builder, value, pointer);
+                Variable::Memory(pointer, kind)
+            },
+            (Variable::Unset, operand) => Variable::Known(operand),
+            (Variable::Known(known), operand) if known.same(&operand) => variable,
+            _ => return self.error(format!("{} can't be given {} in compiled code", name, operand.describe())),
+        };
+        *self.variable(&slot) = stored;
+        Some(())
+    }
+
+    /// Local variables live on the stack of their function, and top level ones in globals
+    fn memory(&mut self, name: &str, slot: &Slot, typ: LLVMTypeRef) -> LLVMValueRef {
+        unsafe {
+            match slot {
+                Slot::Local(_) => {
+                    let current = LLVMGetInsertBlock(self.builder);
+                    LLVMPositionBuilderAtEnd(self.builder, self.allocas);
+                    let name = CString::new(name).unwrap();
+                    let pointer = LLVMBuildAlloca(self.builder, typ, name.as_ptr());
+                    LLVMPositionBuilderAtEnd(self.builder, current);
+                    pointer
+                },
+                Slot::Global(module, _) => {
+                    let name = CString::new(format!("{}.{}", self.env.modules[*module].name, name)).unwrap();
+                    let global = LLVMAddGlobal(self.module, typ, name.as_ptr());
+                    LLVMSetInitializer(global, LLVMConstNull(typ));
+                    global
+                },
+            }
+        }
+    }
+
+    /// Closures which capture local variables would need them moved somewhere that outlives the
+    /// call they belong to, which isn't done yet
+    fn make_closure(&mut self, names: &[String]) -> Option<()> {
+        match names.iter().find(|name| self.scopes.iter().any(|scope| scope.contains_key(*name))) {
+            Some(name) => self.error(format!("Closures can't capture local variables like {} in compiled code yet", name)),
+            None => Some(()),
+        }
+    }
+
+    /// Functions are declared the first time they are called, and generated later
+    fn declare(&mut self, id: usize) -> Option<LLVMValueRef> {
+        if let Some(&function) = self.functions.get(&id) {
+            return Some(function);
+        }
+        let env = self.env;
+        let signature = match &env.functions[&id].signature {
+            Some(signature) if signature.rets.len() <= 1 => signature,
+            _ => {
+                return self.error(format!(
+                    "Function {} can't be compiled, since it takes or returns something other than one number or bool",
+                    id,
+                ));
+            },
+        };
+
+        let mut params: Vec<_> = signature.params.iter().map(|param| self.kind_type(Kind::from(*param))).collect();
+        let function = unsafe {
+            let ret = match signature.rets.first() {
+                Some(ret) => self.kind_type(Kind::from(*ret)),
+                None => LLVMVoidTypeInContext(self.context),
+            };
+            let typ = LLVMFunctionType(ret, params.as_mut_ptr(), params.len() as u32, 0);
+            let name = CString::new(format!("fn.{}", id)).unwrap();
+            LLVMAddFunction(self.module, name.as_ptr(), typ)
+        };
+        self.functions.insert(id, function);
+        self.queue.push(id);
+        Some(function)
+    }
+
+    fn call(&mut self, callee: Operand<'g>, args: usize) -> Option<()> {
+        match callee {
+            Operand::Function(id) => self.call_function(id, args),
+            Operand::Native(native) => self.call_native(native, args),
+            callee => self.error(format!("Tried to call {}, which isn't a function", callee.describe())),
+        }
+    }
+
+    fn call_function(&mut self, id: usize, args: usize) -> Option<()> {
+        let function = self.declare(id)?;
+        let env = self.env;
+        let signature = env.functions[&id].signature.as_ref().unwrap();
+        if args != signature.params.len() {
+            return self.error(format!("Function {} takes {} argument(s), but was called with {}", id, signature.params.len(), args));
+        }
+
+        let operands = self.stack.split_off(self.stack.len() - args);
+        let mut values = vec![];
+        for (operand, param) in operands.into_iter().zip(&signature.params) {
+            values.push(self.register(operand, *param)?);
+        }
+        let result = unsafe {
+            LLVMBuildCall(self.builder, function, values.as_mut_ptr(), values.len() as u32, NO_NAME)
+        };
+        self.stack.push(match signature.rets.first() {
+            Some(ret) => Operand::Register(result, Kind::from(*ret)),
+            None => Operand::Undefined,
+        });
+        Some(())
+    }
+
+    /// `m.f(x)` calls function `f` of module `m`, and otherwise `x.f(y)` calls `f` with `x` as its
+    /// first argument
+    /// Calls a native through the C function it names. print takes any value, so it's done with
+    /// printf instead, which shows floats with up to 15 significant digits.
+    fn call_native(&mut self, native: &Native, args: usize) -> Option<()> {
+        if args != native.params.len() {
+            return self.error(format!("{} takes {} argument(s), but was called with {}", native.name, native.params.len(), args));
+        }
+        let operands = self.stack.split_off(self.stack.len() - args);
+        if native.name == "print" {
+            self.print(operands[0])?;
+            self.stack.push(Operand::Undefined);
+            return Some(());
+        }
+        let symbol = match &native.symbol {
+            Some(symbol) => symbol,
+            None => return self.error(format!("{} can't be called from compiled code", native.name)),
+        };
+
+        let mut params = vec![];
+        let mut values = vec![];
+        for (operand, param) in operands.into_iter().zip(&native.params) {
+            let typ = match param.scalar_type() {
+                Some(typ) => typ,
+                None => return self.error(format!("{} takes a {}, which compiled code can't pass", native.name, param)),
+            };
+            params.push(self.kind_type(Kind::from(typ)));
+            values.push(self.register(operand, typ)?);
+        }
+        let result = match native.ret {
+            Type::Void => {
+                let void = unsafe { LLVMVoidTypeInContext(self.context) };
+                self.call_external(symbol, void, &mut params, &mut values);
+                Operand::Undefined
+            },
+            ref ret => match ret.scalar_type() {
+                Some(typ) => {
+                    let kind = Kind::from(typ);
+                    Operand::Register(self.call_external(symbol, self.kind_type(kind), &mut params, &mut values), kind)
+                },
+                None => return self.error(format!("{} returns a {}, which compiled code can't hold", native.name, ret)),
+            },
+        };
+        self.stack.push(result);
+        Some(())
+    }
+
+    fn print(&mut self, operand: Operand) -> Option<()> {
+        let (format, value) = match operand {
+            Operand::Register(value, Kind::Bool) => unsafe {
+                let yes = LLVMBuildGlobalStringPtr(self.builder, b"true\0".as_ptr() as *const c_char, NO_NAME);
+                let no = LLVMBuildGlobalStringPtr(self.builder, b"false\0".as_ptr() as *const c_char, NO_NAME);
+                ("%s\n", LLVMBuildSelect(self.builder, value, yes, no, NO_NAME))
+            },
+            Operand::Register(value, Kind::Integer(Some(IntType { signed: false, .. }))) => ("%llu\n", value),
+            Operand::Register(value, Kind::Integer(_)) => ("%lld\n", value),
+            Operand::Register(value, Kind::Float) => ("%.15g\n", value),
+            operand => return self.error(format!("Can't print {} from compiled code", operand.describe())),
+        };
+        unsafe {
+            let string = LLVMPointerType(self.int_type(8), 0);
+            let typ = LLVMFunctionType(self.int_type(32), [string].as_mut_ptr(), 1, 1);
+            let printf = self.external("printf", typ);
+            let format = CString::new(format).unwrap();
+            let mut args = [LLVMBuildGlobalStringPtr(self.builder, format.as_ptr(), NO_NAME), value];
+            LLVMBuildCall(self.builder, printf, args.as_mut_ptr(), 2, NO_NAME);
+        }
+        Some(())
+    }
+
+    fn call_member(&mut self, name: &str, args: usize) -> Option<()> {
+        let object_index = self.stack.len() - args - 1;
+        match self.stack[object_index] {
+            Operand::Module(module) => {
+                let env = self.env;
+                let callee = match self.globals[module].get(name) {
+                    Some(Variable::Known(callee)) if env.modules[module].public.contains(name) => *callee,
+                    _ => return self.error(format!("Module {} has no pub function called {}", env.modules[module].name, name)),
+                };
+                self.stack.remove(object_index);
+                self.call(callee, args)
+            },
+            _ => {
+                let callee = self.read(name)?;
+                self.call(callee, args + 1)
+            },
+        }
+    }
+
+    /// The register holding a value which is passed or returned as a scalar type
+    fn register(&mut self, operand: Operand, typ: ScalarType) -> Option<LLVMValueRef> {
+        match operand {
+            Operand::Register(value, _) if unsafe { LLVMTypeOf(value) } == self.kind_type(Kind::from(typ)) => Some(value),
+            operand => self.error(format!("Expected {:?}, but found {}", typ, operand.describe())),
+        }
+    }
+
+    fn arithmetic(&mut self, op: Arithmetic, int: &Option<IntType>, overflow: &Option<Overflow>) -> Option<()> {
+        let right = self.stack.pop().unwrap();
+        let left = self.stack.pop().unwrap();
+        let result = match (left, right) {
+            (Operand::Register(left, Kind::Integer(left_int)), Operand::Register(right, Kind::Integer(right_int))) => {
+                match int {
+                    Some(int) => {
+                        let overflow = overflow.unwrap_or(self.overflow);
+                        Operand::Register(self.fixed(op, left, right, *int, overflow), Kind::Integer(Some(*int)))
+                    },
+                    None => {
+                        let result = self.fixed(op, left, right, I64, Overflow::Wrap);
+                        Operand::Register(result, Kind::Integer(left_int.or(right_int)))
+                    },
+                }
+            },
+            (Operand::Register(left, Kind::Float), Operand::Register(right, Kind::Float)) => unsafe {
+                let result = match op {
+                    Arithmetic::Add => LLVMBuildFAdd(self.builder, left, right, NO_NAME),
+                    Arithmetic::Subtract => LLVMBuildFSub(self.builder, left, right, NO_NAME),
+                    Arithmetic::Multiply => LLVMBuildFMul(self.builder, left, right, NO_NAME),
+                };
+                Operand::Register(result, Kind::Float)
+            },
+            (left, right) => {
+                return self.error(format!("Can't compile {:?} on {} and {}", op, left.describe(), right.describe()));
+            },
+        };
+        self.stack.push(result);
+        Some(())
+    }
+
+    /// Does integer arithmetic in the width of its type, where a result that doesn't fit is dealt
+    /// with the way `overflow` says. Saturating goes the same way the interpreter's does.
+    fn fixed(&mut self, op: Arithmetic, left: LLVMValueRef, right: LLVMValueRef, int: IntType, overflow: Overflow) -> LLVMValueRef {
+        let typ = self.int_type(int.bits);
+        let (left, right) = (self.narrow(left, int), self.narrow(right, int));
+        if overflow == Overflow::Wrap {
+            let result = unsafe {
+                match op {
+                    Arithmetic::Add => LLVMBuildAdd(self.builder, left, right, NO_NAME),
+                    Arithmetic::Subtract => LLVMBuildSub(self.builder, left, right, NO_NAME),
+                    Arithmetic::Multiply => LLVMBuildMul(self.builder, left, right, NO_NAME),
+                }
+            };
+            return self.widen(result, int);
+        }
+
+        let name = format!(
+            "llvm.{}{}.with.overflow.i{}",
+            if int.signed { "s" } else { "u" },
+            match op {
+                Arithmetic::Add => "add",
+                Arithmetic::Subtract => "sub",
+                Arithmetic::Multiply => "mul",
+            },
+            int.bits,
+        );
+        let result = unsafe {
+            let mut fields = [typ, self.int_type(1)];
+            let pair = LLVMStructTypeInContext(self.context, fields.as_mut_ptr(), 2, 0);
+            let pair = self.call_external(&name, pair, &mut [typ, typ], &mut [left, right]);
+            let result = LLVMBuildExtractValue(self.builder, pair, 0, NO_NAME);
+            let overflowed = LLVMBuildExtractValue(self.builder, pair, 1, NO_NAME);
+
+            if overflow == Overflow::Trap {
+                self.trap(overflowed, &format!("Integer overflow in {:?}", int));
+                result
+            } else {
+                let zero = self.const_int(typ, 0);
+                let is_negative = |value| LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntSLT, value, zero, NO_NAME);
+                let negative = match (op, int.signed) {
+                    (Arithmetic::Multiply, true) => {
+                        LLVMBuildXor(self.builder, is_negative(left), is_negative(right), NO_NAME)
+                    },
+                    (_, true) => is_negative(left),
+                    (Arithmetic::Subtract, false) => LLVMConstInt(self.int_type(1), 1, 0),
+                    (_, false) => LLVMConstInt(self.int_type(1), 0, 0),
+                };
+                let (min, max) = int.bounds();
+                let bound = LLVMBuildSelect(self.builder, negative, self.const_int(typ, min), self.const_int(typ, max), NO_NAME);
+                LLVMBuildSelect(self.builder, overflowed, bound, result, NO_NAME)
+            }
+        };
+        self.widen(result, int)
+    }
+
+    /// Fits an integer held in 64 bits, as signed or not, into an integer type, where one that
+    /// doesn't fit is dealt with the way `overflow` says
+    fn fit(&mut self, value: LLVMValueRef, signed: bool, int: IntType, overflow: Overflow) -> LLVMValueRef {
+        let typ = self.int_type(64);
+        let (min, max) = int.bounds();
+        unsafe {
+            let never = LLVMConstInt(self.int_type(1), 0, 0);
+            let (below, above) = if signed {
+                (
+                    LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntSLT, value, self.const_int(typ, min), NO_NAME),
+                    if max > i64::MAX as i128 {
+                        never
+                    } else {
+                        LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntSGT, value, self.const_int(typ, max), NO_NAME)
+                    },
+                )
+            } else {
+                (
+                    never,
+                    if max >= u64::MAX as i128 {
+                        never
+                    } else {
+                        LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntUGT, value, self.const_int(typ, max), NO_NAME)
+                    },
+                )
+            };
+            let wrapped = self.widen(self.narrow(value, int), int);
+            match overflow {
+                Overflow::Wrap => wrapped,
+                Overflow::Saturate => {
+                    let high = LLVMBuildSelect(self.builder, above, self.const_int(typ, max), wrapped, NO_NAME);
+                    LLVMBuildSelect(self.builder, below, self.const_int(typ, min), high, NO_NAME)
+                },
+                Overflow::Trap => {
+                    let overflowed = LLVMBuildOr(self.builder, below, above, NO_NAME);
+                    self.trap(overflowed, &format!("Integer overflow in {:?}", int));
+                    wrapped
+                },
+            }
+        }
+    }
+
+    /// Division is rounded down, for integers as well as floats. Dividing the smallest signed
+    /// integer by -1 is the only way to overflow.
+    fn floor_divide(&mut self, int: &Option<IntType>) -> Option<()> {
+        let right = self.stack.pop().unwrap();
+        let left = self.stack.pop().unwrap();
+        let result = match (left, right) {
+            (Operand::Register(left, Kind::Integer(left_int)), Operand::Register(right, Kind::Integer(right_int))) => {
+                let (fixed, overflow) = match int {
+                    Some(int) => (*int, self.overflow),
+                    None => (I64, Overflow::Wrap),
+                };
+                let result = self.divide(left, right, fixed, overflow);
+                Operand::Register(result, Kind::Integer(int.or(left_int).or(right_int)))
+            },
+            (Operand::Register(left, Kind::Float), Operand::Register(right, Kind::Float)) => unsafe {
+                let quotient = LLVMBuildFDiv(self.builder, left, right, NO_NAME);
+                let typ = self.float_type();
+                Operand::Register(self.call_external("llvm.floor.f64", typ, &mut [typ], &mut [quotient]), Kind::Float)
+            },
+            (left, right) => {
+                return self.error(format!("Can't compile // on {} and {}", left.describe(), right.describe()));
+            },
+        };
+        self.stack.push(result);
+        Some(())
+    }
+
+    fn divide(&mut self, left: LLVMValueRef, right: LLVMValueRef, int: IntType, overflow: Overflow) -> LLVMValueRef {
+        let typ = self.int_type(int.bits);
+        let (left, right) = (self.narrow(left, int), self.narrow(right, int));
+        unsafe {
+            let zero = LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntEQ, right, self.const_int(typ, 0), NO_NAME);
+            self.trap(zero, "Division by zero");
+            let quotient = if int.signed {
+                let (min, max) = int.bounds();
+                let overflowed = LLVMBuildAnd(
+                    self.builder,
+                    LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntEQ, left, self.const_int(typ, min), NO_NAME),
+                    LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntEQ, right, self.const_int(typ, -1), NO_NAME),
+                    NO_NAME,
+                );
+                // dividing by 1 instead keeps the division itself from overflowing
+                let divisor = LLVMBuildSelect(self.builder, overflowed, self.const_int(typ, 1), right, NO_NAME);
+                let quotient = LLVMBuildSDiv(self.builder, left, divisor, NO_NAME);
+                // sdiv rounds toward zero, which is one too high when the exact quotient is negative
+                let zero = self.const_int(typ, 0);
+                let remainder = LLVMBuildSRem(self.builder, left, divisor, NO_NAME);
+                let is_negative = |value| LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntSLT, value, zero, NO_NAME);
+                let round = LLVMBuildAnd(
+                    self.builder,
+                    LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntNE, remainder, zero, NO_NAME),
+                    LLVMBuildXor(self.builder, is_negative(remainder), is_negative(divisor), NO_NAME),
+                    NO_NAME,
+                );
+                let quotient = LLVMBuildSub(self.builder, quotient, LLVMBuildZExt(self.builder, round, typ, NO_NAME), NO_NAME);
+                match overflow {
+                    Overflow::Wrap => LLVMBuildSelect(self.builder, overflowed, self.const_int(typ, min), quotient, NO_NAME),
+                    Overflow::Saturate => LLVMBuildSelect(self.builder, overflowed, self.const_int(typ, max), quotient, NO_NAME),
+                    Overflow::Trap => {
+                        self.trap(overflowed, &format!("Integer overflow in {:?}", int));
+                        quotient
+                    },
+                }
+            } else {
+                LLVMBuildUDiv(self.builder, left, right, NO_NAME)
+            };
+            self.widen(quotient, int)
+        }
+    }
+
+    fn exact_divide(&mut self) -> Option<()> {
+        let right = self.stack.pop().unwrap();
+        let left = self.stack.pop().unwrap();
+        let (left, right) = match (left, right) {
+            (Operand::Register(left, Kind::Integer(left_int)), Operand::Register(right, Kind::Integer(right_int))) => {
+                (self.to_float(left, left_int), self.to_float(right, right_int))
+            },
+            (Operand::Register(left, Kind::Float), Operand::Register(right, Kind::Float)) => (left, right),
+            (left, right) => {
+                return self.error(format!("Can't compile / on {} and {}", left.describe(), right.describe()));
+            },
+        };
+        let result = unsafe { LLVMBuildFDiv(self.builder, left, right, NO_NAME) };
+        self.stack.push(Operand::Register(result, Kind::Float));
+        Some(())
+    }
+
+    fn negate(&mut self, int: &Option<IntType>) -> Option<()> {
+        let result = match self.stack.pop().unwrap() {
+            Operand::Register(value, Kind::Integer(known)) => {
+                let zero = self.const_int(self.int_type(64), 0);
+                let result = match int {
+                    // the value can be a literal which only fits once it's negated, like the 128 in -128
+                    Some(int) if int.bits < 64 => {
+                        let negated = unsafe { LLVMBuildNeg(self.builder, value, NO_NAME) };
+                        self.fit(negated, true, *int, self.overflow)
+                    },
+                    Some(int) => self.fixed(Arithmetic::Subtract, zero, value, *int, self.overflow),
+                    None => self.fixed(Arithmetic::Subtract, zero, value, I64, Overflow::Wrap),
+                };
+                Operand::Register(result, Kind::Integer(int.or(known)))
+            },
+            Operand::Register(value, Kind::Float) => {
+                Operand::Register(unsafe { LLVMBuildFNeg(self.builder, value, NO_NAME) }, Kind::Float)
+            },
+            operand => return self.error(format!("Can't negate {}", operand.describe())),
+        };
+        self.stack.push(result);
+        Some(())
+    }
+
+    /// Converts the way the interpreter does, so a float cut down to an integer that still doesn't
+    /// fit overflows like arithmetic, and an integer made an f32 is rounded to one
+    fn convert(&mut self, number: &NumberType) -> Option<()> {
+        let result = match (self.stack.pop().unwrap(), number) {
+            (Operand::Register(value, Kind::Integer(known)), NumberType::Integer(int)) => {
+                let signed = !matches!(known, Some(IntType { signed: false, .. }));
+                Operand::Register(self.fit(value, signed, *int, self.overflow), Kind::Integer(Some(*int)))
+            },
+            (Operand::Register(value, Kind::Float), NumberType::Integer(int)) => {
+                Operand::Register(self.float_to_int(value, *int), Kind::Integer(Some(*int)))
+            },
+            (Operand::Register(value, Kind::Integer(known)), NumberType::Float { bits: 32 }) => unsafe {
+                let single = LLVMFloatTypeInContext(self.context);
+                let value = match known {
+                    Some(IntType { signed: false, .. }) => LLVMBuildUIToFP(self.builder, value, single, NO_NAME),
+                    _ => LLVMBuildSIToFP(self.builder, value, single, NO_NAME),
+                };
+                Operand::Register(LLVMBuildFPExt(self.builder, value, self.float_type(), NO_NAME), Kind::Float)
+            },
+            (Operand::Register(value, Kind::Integer(known)), NumberType::Float { .. }) => {
+                Operand::Register(self.to_float(value, known), Kind::Float)
+            },
+            (Operand::Register(value, Kind::Float), NumberType::Float { bits: 32 }) => unsafe {
+                let value = LLVMBuildFPTrunc(self.builder, value, LLVMFloatTypeInContext(self.context), NO_NAME);
+                Operand::Register(LLVMBuildFPExt(self.builder, value, self.float_type(), NO_NAME), Kind::Float)
+            },
+            (operand @ Operand::Register(_, Kind::Float), NumberType::Float { .. }) => operand,
+            (operand, number) => return self.error(format!("Can't convert {} to {:?}", operand.describe(), number)),
+        };
+        self.stack.push(result);
+        Some(())
+    }
+
+    /// Cuts the fraction off a float and fits it into an integer type. Wrapping keeps the lowest
+    /// bits of the whole number, which saturates at the bounds of an i128 and is 0 for NaN.
+    fn float_to_int(&mut self, value: LLVMValueRef, int: IntType) -> LLVMValueRef {
+        let typ = self.int_type(64);
+        let float = self.float_type();
+        let (min, max) = int.bounds();
+        unsafe {
+            let truncated = self.call_external("llvm.trunc.f64", float, &mut [float], &mut [value]);
+            let compare = |predicate, bound: f64| {
+                LLVMBuildFCmp(self.builder, predicate, truncated, LLVMConstReal(float, bound), NO_NAME)
+            };
+            // NaN compares false, so it never fits
+            let fits = LLVMBuildAnd(
+                self.builder,
+                compare(LLVMRealPredicate::LLVMRealOGE, min as f64),
+                compare(LLVMRealPredicate::LLVMRealOLT, (max + 1) as f64),
+                NO_NAME,
+            );
+            let exact = |builder| if int.signed {
+                LLVMBuildFPToSI(builder, truncated, typ, NO_NAME)
+            } else {
+                LLVMBuildFPToUI(builder, truncated, typ, NO_NAME)
+            };
+            match self.overflow {
+                Overflow::Wrap => {
+                    let wide = self.int_type(128);
+                    let bound = 2f64.powi(127);
+                    let in_wide = LLVMBuildAnd(
+                        self.builder,
+                        compare(LLVMRealPredicate::LLVMRealOGE, -bound),
+                        compare(LLVMRealPredicate::LLVMRealOLT, bound),
+                        NO_NAME,
+                    );
+                    let above_wide = compare(LLVMRealPredicate::LLVMRealOGE, bound);
+                    // converting a float outside of the i128 range gives nothing, so 0 is converted instead
+                    let safe = LLVMBuildSelect(self.builder, in_wide, truncated, LLVMConstReal(float, 0.0), NO_NAME);
+                    let whole = LLVMBuildTrunc(self.builder, LLVMBuildFPToSI(self.builder, safe, wide, NO_NAME), typ, NO_NAME);
+                    let whole = LLVMBuildSelect(self.builder, above_wide, self.const_int(typ, -1), whole, NO_NAME);
+                    self.widen(self.narrow(whole, int), int)
+                },
+                Overflow::Saturate => {
+                    let below = compare(LLVMRealPredicate::LLVMRealOLT, min as f64);
+                    let bound = LLVMBuildSelect(self.builder, below, self.const_int(typ, min), self.const_int(typ, max), NO_NAME);
+                    LLVMBuildSelect(self.builder, fits, exact(self.builder), bound, NO_NAME)
+                },
+                Overflow::Trap => {
+                    let overflowed = LLVMBuildNot(self.builder, fits, NO_NAME);
+                    self.trap(overflowed, &format!("Integer overflow in {:?}", int));
+                    exact(self.builder)
+                },
+            }
+        }
+    }
+
+    fn not(&mut self) -> Option<()> {
+        let result = match self.stack.pop().unwrap() {
+            Operand::Register(value, Kind::Bool) => unsafe {
+                LLVMBuildXor(self.builder, value, LLVMConstInt(self.int_type(1), 1, 0), NO_NAME)
+            },
+            operand => return self.error(format!("Can't compile not on {}", operand.describe())),
+        };
+        self.stack.push(Operand::Register(result, Kind::Bool));
+        Some(())
+    }
+
+    /// Integers are compared as unsigned when either side is known to be, and as signed otherwise
+    fn test(&mut self, compare_type: &CompareType) -> Option<()> {
+        let right = self.stack.pop().unwrap();
+        let left = self.stack.pop().unwrap();
+        let result = unsafe {
+            match (left, right) {
+                (Operand::Register(left, Kind::Integer(left_int)), Operand::Register(right, Kind::Integer(right_int))) => {
+                    let signed = !matches!(left_int.or(right_int), Some(IntType { signed: false, .. }));
+                    LLVMBuildICmp(self.builder, int_predicate(compare_type, signed), left, right, NO_NAME)
+                },
+                (Operand::Register(left, Kind::Float), Operand::Register(right, Kind::Float)) => {
+                    LLVMBuildFCmp(self.builder, real_predicate(compare_type), left, right, NO_NAME)
+                },
+                (Operand::Register(left, Kind::Bool), Operand::Register(right, Kind::Bool)) => {
+                    LLVMBuildICmp(self.builder, int_predicate(compare_type, false), left, right, NO_NAME)
+                },
+                (left, right) => {
+                    return self.error(format!("Can't compare {} and {}", left.describe(), right.describe()));
+                },
+            }
+        };
+        self.stack.push(Operand::Register(result, Kind::Bool));
+        Some(())
+    }
+
+    /// Stops the program with a message if `condition` holds, and carries on in a new block
+    /// otherwise
+    fn trap(&mut self, condition: LLVMValueRef, message: &str) {
+        unsafe {
+            let fail = LLVMAppendBasicBlockInContext(self.context, self.function, b"trap\0".as_ptr() as *const c_char);
+            let carry_on = LLVMAppendBasicBlockInContext(self.context, self.function, NO_NAME);
+            LLVMBuildCondBr(self.builder, condition, fail, carry_on);
+            LLVMPositionBuilderAtEnd(self.builder, fail);
+            self.panic(message);
+            LLVMPositionBuilderAtEnd(self.builder, carry_on);
+        }
+    }
+
+    /// Prints a message and aborts, ending the block, where the interpreter would panic
+    fn panic(&mut self, message: &str) {
+        unsafe {
+            let message = CString::new(message).unwrap();
+            let text = LLVMBuildGlobalStringPtr(self.builder, message.as_ptr(), NO_NAME);
+            let string = LLVMPointerType(self.int_type(8), 0);
+            self.call_external("puts", self.int_type(32), &mut [string], &mut [text]);
+            self.call_external("abort", LLVMVoidTypeInContext(self.context), &mut [], &mut []);
+            LLVMBuildUnreachable(self.builder);
+        }
+    }
+
+    /// Calls a function which LLVM or the C library provides, declaring it the first time
+    fn call_external(&mut self, name: &str, ret: LLVMTypeRef, params: &mut [LLVMTypeRef], args: &mut [LLVMValueRef]) -> LLVMValueRef {
+        unsafe {
+            let typ = LLVMFunctionType(ret, params.as_mut_ptr(), params.len() as u32, 0);
+            let function = self.external(name, typ);
+            LLVMBuildCall(self.builder, function, args.as_mut_ptr(), args.len() as u32, NO_NAME)
+        }
+    }
+
+    fn external(&mut self, name: &str, typ: LLVMTypeRef) -> LLVMValueRef {
+        unsafe {
+            let name = CString::new(name).unwrap();
+            let function = LLVMGetNamedFunction(self.module, name.as_ptr());
+            if function.is_null() {
+                LLVMAddFunction(self.module, name.as_ptr(), typ)
+            } else {
+                function
+            }
+        }
+    }
+
+    /// Arithmetic is done in the width of the integer type
+    fn narrow(&self, value: LLVMValueRef, int: IntType) -> LLVMValueRef {
+        if int.bits == 64 {
+            value
+        } else {
+            unsafe { LLVMBuildTrunc(self.builder, value, self.int_type(int.bits), NO_NAME) }
+        }
+    }
+
+    /// Registers hold integers in 64 bits
+    fn widen(&self, value: LLVMValueRef, int: IntType) -> LLVMValueRef {
+        unsafe {
+            match int {
+                IntType { bits: 64, .. } => value,
+                IntType { signed: true, .. } => LLVMBuildSExt(self.builder, value, self.int_type(64), NO_NAME),
+                IntType { signed: false, .. } => LLVMBuildZExt(self.builder, value, self.int_type(64), NO_NAME),
+            }
+        }
+    }
+
+    fn to_float(&self, value: LLVMValueRef, int: Option<IntType>) -> LLVMValueRef {
+        unsafe {
+            match int {
+                Some(IntType { signed: false, .. }) => LLVMBuildUIToFP(self.builder, value, self.float_type(), NO_NAME),
+                _ => LLVMBuildSIToFP(self.builder, value, self.float_type(), NO_NAME),
+            }
+        }
+    }
+
+    fn int_type(&self, bits: u8) -> LLVMTypeRef {
+        unsafe { LLVMIntTypeInContext(self.context, bits as u32) }
+    }
+
+    fn float_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMDoubleTypeInContext(self.context) }
+    }
+
+    fn kind_type(&self, kind: Kind) -> LLVMTypeRef {
+        match kind {
+            Kind::Bool => self.int_type(1),
+            Kind::Integer(_) => self.int_type(64),
+            Kind::Float => self.float_type(),
+        }
+    }
+
+    /// Only the lowest bits of the value are kept, which is its two's complement in narrower types
+    fn const_int(&self, typ: LLVMTypeRef, value: i128) -> LLVMValueRef {
+        unsafe { LLVMConstInt(typ, value as u64, 0) }
+    }
+}
+
+impl fmt::Display for LLVMGenerator<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        unsafe {
+            let text = LLVMPrintModuleToString(self.module);
+            let result = write!(f, "{}", CStr::from_ptr(text).to_string_lossy());
+            LLVMDisposeMessage(text);
+            result
+        }
+    }
+}
+
+impl Drop for LLVMGenerator<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDisposeBuilder(self.builder);
+            // running the module hands it over to whatever runs it
+            if !self.module.is_null() {
+                LLVMDisposeModule(self.module);
+            }
+            LLVMContextDispose(self.context);
+        }
+    }
+}
+
+fn int_predicate(compare_type: &CompareType, signed: bool) -> LLVMIntPredicate {
+    use LLVMIntPredicate::*;
+    match (compare_type, signed) {
+        (CompareType::EQ, _) => LLVMIntEQ,
+        (CompareType::NE, _) => LLVMIntNE,
+        (CompareType::LT, true) => LLVMIntSLT,
+        (CompareType::GT, true) => LLVMIntSGT,
+        (CompareType::LE, true) => LLVMIntSLE,
+        (CompareType::GE, true) => LLVMIntSGE,
+        (CompareType::LT, false) => LLVMIntULT,
+        (CompareType::GT, false) => LLVMIntUGT,
+        (CompareType::LE, false) => LLVMIntULE,
+        (CompareType::GE, false) => LLVMIntUGE,
+    }
+}
+
+/// NaN is unequal to everything, including itself, and not ordered with anything
+fn real_predicate(compare_type: &CompareType) -> LLVMRealPredicate {
+    use LLVMRealPredicate::*;
+    match compare_type {
+        CompareType::EQ => LLVMRealOEQ,
+        CompareType::NE => LLVMRealUNE,
+        CompareType::LT => LLVMRealOLT,
+        CompareType::GT => LLVMRealOGT,
+        CompareType::LE => LLVMRealOLE,
+        CompareType::GE => LLVMRealOGE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    use llvm_sys::{
+        execution_engine::*,
+        target::*,
+    };
+
+    use super::*;
+    use crate::{
+        ir::IRGenerator,
+        lexer::Lexer,
+        parser::Parser,
+        typeck::TypeChecker,
+    };
+
+    /// Type checks and compiles a program, handing the generator over before it is dropped
+    fn compile<T>(contents: &'static str, overflow: Overflow, then: impl FnOnce(&mut LLVMGenerator) -> T) -> T {
+        let errors = RefCell::new(Errors::new());
+        let ast = Parser::new(Lexer::new(contents, errors.borrow_mut())).go().unwrap();
+        let annotations = TypeChecker::new(&ast, errors.borrow_mut()).go();
+        let mut ir_generator = IRGenerator::new(&ast, errors.borrow_mut()).with_annotations(annotations);
+        ir_generator.go();
+        let mut generator = LLVMGenerator::new(&ir_generator.env, ir_generator.errors);
+        generator.overflow = overflow;
+        generator.go();
+        assert!(generator.errors.errors.is_empty(), "{:?}", generator.errors.errors);
+        then(&mut generator)
+    }
+
+    /// Compiles a program and runs its main function, which must return a value of type `T`.
+    /// Integers are returned in 64 bits, whatever their type.
+    fn run<T>(contents: &'static str, overflow: Overflow) -> T {
+        static INITIALIZE: Once = Once::new();
+        INITIALIZE.call_once(|| unsafe {
+            LLVMLinkInMCJIT();
+            LLVM_InitializeNativeTarget();
+            LLVM_InitializeNativeAsmPrinter();
+        });
+
+        compile(contents, overflow, |generator| unsafe {
+            let main = match generator.globals[0].get("main") {
+                Some(Variable::Known(Operand::Function(id))) => CString::new(format!("fn.{}", id)).unwrap(),
+                _ => panic!("Expected a main function"),
+            };
+            let module = std::mem::replace(&mut generator.module, ptr::null_mut());
+            let mut engine = ptr::null_mut();
+            let mut error = ptr::null_mut();
+            if LLVMCreateExecutionEngineForModule(&mut engine, module, &mut error) != 0 {
+                panic!("{}", CStr::from_ptr(error).to_string_lossy());
+            }
+            let main: extern "C" fn() -> T = std::mem::transmute(LLVMGetFunctionAddress(engine, main.as_ptr()));
+            let result = main();
+            LLVMDisposeExecutionEngine(engine);
+            result
+        })
+    }
+
+    #[test]
+    fn loops_and_calls() {
+        assert_eq!(run::<i64>("square := fn(x: i32) i32 { x * x }
+        fib := fn(n: n64) n64 { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }
+        main := fn() i32 {
+            total: i32 = 0
+            i: i32 = 0
+            while i < 10 {
+                total = total + square(i)
+                i = i + 1
+            }
+            if fib(20) == 6765 { total } else { 0 }
+        }", Overflow::Trap), 285);
+    }
+
+    #[test]
+    fn fixed_width_integers() {
+        let unsigned = "main := fn() n8 {
+            a: n8 = 250
+            a + 10
+        }";
+        let signed = "main := fn() i8 {
+            c: i8 = -100
+            c - 100
+        }";
+        let product = "square := fn(x: i16) i16 { x * x }
+        main := fn() i16 { square(300) }";
+        assert_eq!(run::<i64>(unsigned, Overflow::Wrap), 4);
+        assert_eq!(run::<i64>(unsigned, Overflow::Saturate), 255);
+        assert_eq!(run::<i64>(signed, Overflow::Wrap), 56);
+        assert_eq!(run::<i64>(signed, Overflow::Saturate), -128);
+        assert_eq!(run::<i64>(product, Overflow::Wrap), 24464);
+        assert_eq!(run::<i64>(product, Overflow::Saturate), 32767);
+    }
+
+    #[test]
+    fn overflow_operators() {
+        assert_eq!(run::<i64>("main := fn() n8 {
+            a: n8 = 250
+            b: n8 = 10
+            (a +| b) -% (b -% 11) +% (a *| b)
+        }", Overflow::Trap), 255);
+    }
+
+    #[test]
+    fn division_and_negation() {
+        let quotient = "div := fn(a: i8, b: i8) i8 { a // b }
+        main := fn() i8 { div(-7, 2) }";
+        let negative_divisor = "div := fn(a: i8, b: i8) i8 { a // b }
+        main := fn() i8 { div(7, -2) }";
+        let exact = "div := fn(a: i8, b: i8) i8 { a // b }
+        main := fn() i8 { div(-8, 2) }";
+        let overflowing = "main := fn() i8 {
+            smallest: i8 = -128
+            smallest // -1
+        }";
+        let negated = "main := fn() i8 {
+            smallest: i8 = -128
+            -smallest
+        }";
+        assert_eq!(run::<i64>(quotient, Overflow::Trap), -4);
+        assert_eq!(run::<i64>(negative_divisor, Overflow::Trap), -4);
+        assert_eq!(run::<i64>(exact, Overflow::Trap), -4);
+        assert_eq!(run::<i64>(overflowing, Overflow::Wrap), -128);
+        assert_eq!(run::<i64>(overflowing, Overflow::Saturate), 127);
+        assert_eq!(run::<i64>(negated, Overflow::Wrap), -128);
+        assert_eq!(run::<i64>(negated, Overflow::Saturate), 127);
+        assert_eq!(run::<f64>("main := fn() f64 { -7.5 // 2.0 }", Overflow::Trap), -4.0);
+    }
+
+    #[test]
+    fn numeric_conversions() {
+        let mixed = "half := fn(x: f64) f64 { x / 2.0 }
+        main := fn() f64 {
+            a: i32 = 7
+            b: f64 = 2.5
+            c: n16 = 1000
+            wide: i64 = c
+            (a + b) + f64(i32(b * 3.0)) + half(a) + f64(wide)
+        }";
+        let narrowed = "main := fn() n8 {
+            c: i32 = 1000
+            n8(c)
+        }";
+        let unsigned = "main := fn() n64 {
+            c: i32 = -1
+            n64(c)
+        }";
+        let cut = "main := fn() i8 {
+            f: f64 = -300.7
+            i8(f)
+        }";
+        let huge = "main := fn() n8 {
+            f: f64 = 1000000000000000000000000000000000000000000.0
+            n8(f)
+        }";
+        let single = "main := fn() f64 {
+            n: i64 = 16777217
+            f64(f32(n)) + f64(f32(0.1))
+        }";
+        assert_eq!(run::<f64>(mixed, Overflow::Trap), 9.5 + 7.0 + 3.5 + 1000.0);
+        assert_eq!(run::<i64>(narrowed, Overflow::Wrap), 232);
+        assert_eq!(run::<i64>(narrowed, Overflow::Saturate), 255);
+        assert_eq!(run::<i64>(unsigned, Overflow::Wrap), -1);
+        assert_eq!(run::<i64>(unsigned, Overflow::Saturate), 0);
+        assert_eq!(run::<i64>(cut, Overflow::Wrap), -44);
+        assert_eq!(run::<i64>(cut, Overflow::Saturate), -128);
+        assert_eq!(run::<i64>(huge, Overflow::Wrap), 255);
+        assert_eq!(run::<i64>(huge, Overflow::Saturate), 255);
+        assert_eq!(run::<f64>(single, Overflow::Trap), 16777216.0 + 0.1f32 as f64);
+
+        let ir = compile(cut, Overflow::Trap, |generator| generator.to_string());
+        assert!(ir.contains("Integer overflow in i8"));
+    }
+
+    #[test]
+    fn natives() {
+        let program = "hypot := fn(x: f64, y: f64) f64 { sqrt(x * x + y * y) }
+        main := fn() f64 {
+            print(true)
+            n: n64 = 18446744073709551615
+            print(n)
+            print(hypot(3.0, 4.0))
+            hypot(3.0, 4.0) + floor(-2.5)
+        }";
+        assert_eq!(run::<f64>(program, Overflow::Trap), 2.0);
+
+        let ir = compile(program, Overflow::Trap, |generator| generator.to_string());
+        assert!(ir.contains("declare double @sqrt(double)"));
+        assert!(ir.contains("declare i32 @printf(i8*, ...)"));
+        assert!(ir.contains("%llu"));
+    }
+
+    #[test]
+    fn overflow_traps() {
+        let ir = compile("main := fn() i32 {
+            a: i32 = 2147483647
+            a + 1
+        }", Overflow::Trap, |generator| generator.to_string());
+        assert!(ir.contains("@llvm.sadd.with.overflow.i32"));
+        assert!(ir.contains("Integer overflow in i32"));
+
+        let ir = compile("main := fn() i32 {
+            a: i32 = 2147483647
+            a + 1
+        }", Overflow::Wrap, |generator| generator.to_string());
+        assert!(!ir.contains("with.overflow"));
+    }
+}
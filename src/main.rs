@@ -3,7 +3,11 @@
 
 mod errors; mod lexer;
 mod parser;
+mod exhaustiveness;
+mod modules;
+mod typeck;
 mod ir;
+mod natives;
 mod interpreter;
 mod llvm;
 
@@ -11,43 +15,112 @@ use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::cell::RefCell;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+/// `meg [--release] [--overflow=wrap|saturate|trap] [--emit-llvm] [--lossless] <file>`
+struct Options {
+    path: String,
+    overflow: ir::Overflow, // what plain integer arithmetic does, which --release makes wrap
+    emit_llvm: bool,
+    lossless: bool, // whether the lexer output records trivia and is put back together into source code
+}
+
+fn options() -> Options {
+    let mut path = None;
+    let mut overflow = ir::Overflow::Trap;
+    let mut emit_llvm = false;
+    let mut lossless = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--emit-llvm" => emit_llvm = true,
+            "--lossless" => lossless = true,
+            "--release" => overflow = ir::Overflow::Wrap,
+            "--overflow=wrap" => overflow = ir::Overflow::Wrap,
+            "--overflow=saturate" => overflow = ir::Overflow::Saturate,
+            "--overflow=trap" => overflow = ir::Overflow::Trap,
+            option if option.starts_with("--") => panic!("Unknown option {}", option),
+            _ => path = Some(arg),
+        }
+    }
+    Options {
+        path: path.expect("Expected a file to run"),
+        overflow,
+        emit_llvm,
+        lossless,
+    }
+}
 
 fn main() -> std::io::Result<()> {
     println!("Welcome to Meg!\n");
 
-    let mut file = File::open(env::args().nth(1).unwrap())?;
+    let options = options();
+    let path = options.path;
+    let mut file = File::open(&path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
 
     let errors = RefCell::new(errors::Errors::new());
 
+    // the parser pulls tokens from its own lexer, so this pass is only for display
+    let lexer_errors = RefCell::new(errors::Errors::new());
     println!("Lexer output:\n");
-    let mut lexer = lexer::Lexer::new(&contents, errors.borrow_mut());
-    let results = lexer.go();
-    for token in &results {
+    let mut lexer = if options.lossless {
+        lexer::Lexer::new_lossless(&contents, lexer_errors.borrow_mut())
+    } else {
+        lexer::Lexer::new(&contents, lexer_errors.borrow_mut())
+    };
+    let mut source = String::new();
+    for token in &mut lexer {
         println!("{:?}", token);
+        if let Some(text) = token.lossless_text() {
+            source.push_str(&text);
+        }
     }
     drop(lexer);
 
+    if options.lossless {
+        println!("Source code from the tokens:\n\n{}", source);
+    }
+
     println!("Lexer errors:\n");
-    for error in &errors.borrow().errors {
+    for error in &lexer_errors.borrow().errors {
         println!("{:?}", error);
     }
 
+    println!("Lexer warnings:\n");
+    for warning in &lexer_errors.borrow().warnings {
+        println!("{:?}", warning);
+    }
+
     println!("Parser output:\n");
-    let mut parser = parser::Parser::new(&results, errors.borrow_mut());
-    let results = parser.go();
-    println!("{:#?}", results);
-    drop(parser);
+    let modules = modules::ModuleLoader::new(search_path(), &errors).load(Path::new(&path));
+    for module in &modules {
+        println!("module {} ({}):\n{:#?}", module.name, module.file.display(), module.ast);
+    }
 
-    println!("Parser errors:\n");
-    for error in &errors.borrow().errors {
-        println!("{:?}", error);
+    println!("Parser and module errors:\n");
+    if report(&errors.borrow()) {
+        return Ok(());
+    }
+
+    // a root module that didn't parse would have been reported above
+    let root = match modules.last() {
+        Some(module) => &module.ast,
+        None => return Ok(()),
+    };
+    let annotations = typeck::TypeChecker::with_modules(root, &modules, errors.borrow_mut()).go();
+
+    println!("Type checking errors:\n");
+    if report(&errors.borrow()) {
+        return Ok(());
     }
 
     println!("IR output:\n");
-    let unwrapped = results.unwrap();
-    let mut ir_generator = ir::IRGenerator::new(&unwrapped, errors.borrow_mut());
+    let mut ir_generator = ir::IRGenerator::with_modules(root, &modules, errors.borrow_mut())
+        .with_annotations(annotations);
     let results = ir_generator.go();
     //println!("{:#?}", results);
     // drop(ir_generator);
@@ -57,12 +130,24 @@ fn main() -> std::io::Result<()> {
 
     println!("IR generation errors:\n");
     let errors = ir_generator.errors;
-    for error in &errors.errors {
-        println!("{:?}", error);
+    if report(&errors) {
+        return Ok(());
+    }
+
+    if options.emit_llvm {
+        println!("LLVM output:\n");
+        let mut llvm_generator = llvm::LLVMGenerator::new(&ir_generator.env, errors);
+        llvm_generator.overflow = options.overflow;
+        llvm_generator.go();
+        println!("{}", llvm_generator);
+
+        println!("LLVM errors:\n");
+        report(&llvm_generator.errors);
     }
 
     println!("Interpreter output:\n");
     let mut interpreter = interpreter::Interpreter::new(&mut ir_generator.env, 0);
+    interpreter.overflow = options.overflow;
     interpreter.go();
 
     for item in interpreter.stack {
@@ -71,3 +156,22 @@ fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Prints the errors found so far and says whether there were any. Each pass trusts that the
+/// ones before it found nothing wrong, so the program stops at the first pass with errors.
+fn report(errors: &errors::Errors) -> bool {
+    for error in &errors.errors {
+        println!("{:?}", error);
+    }
+    !errors.errors.is_empty()
+}
+
+/// Where modules are looked for after the directory of the file using them: each directory in
+/// MEG_PATH, then the libraries that come with Meg
+fn search_path() -> Vec<PathBuf> {
+    let mut search_path: Vec<_> = env::var_os("MEG_PATH")
+        .map(|paths| env::split_paths(&paths).collect())
+        .unwrap_or_default();
+    search_path.push(Path::new(env!("CARGO_MANIFEST_DIR")).join("lib"));
+    search_path
+}
@@ -0,0 +1,211 @@
+//! This module finds and parses every module a Meg program uses, so they can all be turned into IR
+//! together
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use crate::{
+    errors::Errors,
+    lexer::Lexer,
+    parser::{
+        Node,
+        NodeContext,
+        Parser,
+    },
+};
+
+pub struct ParsedModule {
+    pub name: String, // the path it is used by, like core.io
+    pub file: PathBuf,
+    pub ast: NodeContext,
+    pub imports: HashMap<Vec<String>, usize>, // the module each use declaration refers to
+}
+
+pub struct ModuleLoader<'m> {
+    search_path: Vec<PathBuf>,
+    errors: &'m RefCell<Errors>,
+    modules: Vec<ParsedModule>,
+    loading: Vec<PathBuf>, // the modules which are waiting on the one being loaded
+}
+
+impl<'m> ModuleLoader<'m> {
+    /// Modules are looked for next to the file using them first, then in each directory of the
+    /// search path in turn
+    pub fn new(search_path: Vec<PathBuf>, errors: &'m RefCell<Errors>) -> Self {
+        ModuleLoader {
+            search_path,
+            errors,
+            modules: vec![],
+            loading: vec![],
+        }
+    }
+
+    /// Loads the file and everything it uses. Modules come before the modules which use them, so
+    /// the file itself is always last.
+    pub fn load(mut self, file: &Path) -> Vec<ParsedModule> {
+        let name = file.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+        self.load_module(name, file.to_owned(), 0);
+        self.modules
+    }
+
+    fn load_module(&mut self, name: String, file: PathBuf, position: usize) -> Option<usize> {
+        let file = file.canonicalize().unwrap_or(file);
+        if let Some(index) = self.modules.iter().position(|module| module.file == file) {
+            return Some(index);
+        }
+        if let Some(start) = self.loading.iter().position(|loading| *loading == file) {
+            let cycle: Vec<_> = self.loading[start..].iter()
+                .chain(std::iter::once(&file))
+                .map(|file| file.display().to_string())
+                .collect();
+            self.errors.borrow_mut().module(
+                format!("Modules can't use each other in a cycle: {}", cycle.join(" -> ")),
+                position,
+            );
+            return None;
+        }
+
+        let contents = match fs::read_to_string(&file) {
+            Ok(contents) => contents,
+            Err(error) => {
+                self.errors.borrow_mut().module(
+                    format!("Couldn't read module {} from {}: {}", name, file.display(), error),
+                    position,
+                );
+                return None;
+            },
+        };
+        let ast = Parser::new(Lexer::new(&contents, self.errors.borrow_mut())).go()?;
+
+        self.loading.push(file.clone());
+        let mut imports = HashMap::new();
+        for (path, position) in use_declarations(&ast) {
+            let found = match self.find(&path, &file) {
+                Some(found) => found,
+                None => {
+                    self.errors.borrow_mut().module(
+                        format!("Couldn't find module {}", path.join(".")),
+                        position,
+                    );
+                    continue;
+                },
+            };
+            if let Some(index) = self.load_module(path.join("."), found, position) {
+                imports.insert(path, index);
+            }
+        }
+        self.loading.pop();
+
+        self.modules.push(ParsedModule {
+            name,
+            file,
+            ast,
+            imports,
+        });
+        Some(self.modules.len() - 1)
+    }
+
+    /// `use a.b` refers to the file a/b.meg
+    fn find(&self, path: &[String], importer: &Path) -> Option<PathBuf> {
+        let relative: PathBuf = path.iter().collect::<PathBuf>().with_extension("meg");
+        importer.parent()
+            .into_iter()
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(&relative))
+            .find(|candidate| candidate.is_file())
+    }
+}
+
+/// The use declarations at the top level of a module
+fn use_declarations(ast: &NodeContext) -> Vec<(Vec<String>, usize)> {
+    match &ast.node {
+        Node::Block { nodes } => nodes.iter()
+            .filter_map(|node| match &node.node {
+                Node::Use { path } => Some((path.clone(), node.position)),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::errors::Error;
+
+    /// A directory of modules written for a test, which is deleted once the test is done with it
+    pub(crate) struct Project(PathBuf);
+
+    impl std::ops::Deref for Project {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Project {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes each file into a fresh directory
+    pub(crate) fn project(name: &str, files: &[(&str, &str)]) -> Project {
+        let dir = std::env::temp_dir().join(format!("meg-modules-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, contents) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        Project(dir)
+    }
+
+    fn module_errors(errors: &RefCell<Errors>) -> Vec<String> {
+        errors.borrow().errors.iter().map(|e| match e {
+            Error::Module { message, .. } => message.clone(),
+            e => panic!("{:?}", e),
+        }).collect()
+    }
+
+    #[test]
+    fn dependency_order() {
+        let dir = project("order", &[
+            ("main.meg", "use util\nuse shapes.point\n"),
+            ("util.meg", "use shapes.point\n"),
+            ("lib/shapes/point.meg", "pub x := 1\n"),
+        ]);
+        let errors = RefCell::new(Errors::new());
+        let modules = ModuleLoader::new(vec![dir.join("lib")], &errors).load(&dir.join("main.meg"));
+
+        assert!(module_errors(&errors).is_empty());
+        assert_eq!(modules.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), vec!["shapes.point", "util", "main"]);
+        assert_eq!(modules[2].imports[&vec!["shapes".to_owned(), "point".to_owned()]], 0);
+        assert_eq!(modules[2].imports[&vec!["util".to_owned()]], 1);
+    }
+
+    #[test]
+    fn missing_and_cyclic_modules() {
+        let dir = project("cycle", &[
+            ("main.meg", "use a\nuse nowhere\n"),
+            ("a.meg", "use b\n"),
+            ("b.meg", "use a\n"),
+        ]);
+        let errors = RefCell::new(Errors::new());
+        ModuleLoader::new(vec![], &errors).load(&dir.join("main.meg"));
+
+        let errors = module_errors(&errors);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("Modules can't use each other in a cycle: "));
+        assert!(errors[0].contains("a.meg -> ") && errors[0].contains("b.meg -> ") && errors[0].ends_with("a.meg"));
+        assert_eq!(errors[1], "Couldn't find module nowhere");
+    }
+}
@@ -0,0 +1,189 @@
+//! This module holds the builtin functions which are written in Rust rather than Meg, which the
+//! core library is built on
+
+use std::fmt;
+use std::fs;
+use std::io::{
+    self,
+    BufRead,
+    Write,
+};
+use std::rc::Rc;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+use crate::{
+    ir::{
+        Scope,
+        Value,
+    },
+    typeck::Type,
+};
+
+/// A function written in Rust which Meg code can call like any other. Its types are checked like
+/// those of a Meg function, except for `ANY`, which values of every type fit. One taking an array
+/// of `ANY` first has each `ANY` stand for what that array holds instead.
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    pub params: Vec<Type>,
+    pub ret: Type,
+    /// The C function compiled code calls instead, if there is one
+    pub symbol: Option<String>,
+    function: Rc<dyn Fn(Vec<Value>) -> Value>,
+}
+
+// the types natives are mostly written with
+pub const ANY: Type = Type::Unknown;
+pub const N64: Type = Type::Integer { signed: false, bits: 64 };
+pub const F64: Type = Type::Float { bits: 64 };
+
+impl Native {
+    /// The arguments are expected to already match the parameters
+    pub fn call(&self, args: Vec<Value>) -> Value {
+        (self.function)(args)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<_> = self.params.iter().map(|param| param.to_string()).collect();
+        write!(f, "native fn {}({}) {}", self.name, params.join(", "), self.ret)
+    }
+}
+
+#[derive(Default)]
+pub struct Registry {
+    natives: Vec<Native>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    /// Registering a name a second time replaces the first function
+    pub fn register<F>(&mut self, name: &str, params: &[Type], ret: Type, function: F) -> &mut Self
+    where
+        F: Fn(Vec<Value>) -> Value + 'static,
+    {
+        self.natives.retain(|native| native.name != name);
+        self.natives.push(Native {
+            name: name.to_owned(),
+            params: params.to_vec(),
+            ret,
+            symbol: None,
+            function: Rc::new(function),
+        });
+        self
+    }
+
+    /// Lets compiled code call the native registered last as a C function, which has to take and
+    /// return the same numbers or bools
+    pub fn symbol(&mut self, symbol: &str) -> &mut Self {
+        if let Some(native) = self.natives.last_mut() {
+            native.symbol = Some(symbol.to_owned());
+        }
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Native> {
+        self.natives.iter()
+    }
+
+    /// Declares every native as a variable in the scope
+    pub fn declare(&self, scope: &mut Scope) {
+        for native in &self.natives {
+            scope.insert(native.name.clone(), Value::Native(native.clone()));
+        }
+    }
+}
+
+/// The natives every program can use
+pub fn builtins() -> Registry {
+    let mut registry = Registry::new();
+    registry
+        .register("print", &[ANY], Type::Void, |args| {
+            println!("{}", display(&args[0]));
+            Value::Undefined
+        })
+        .register("write_stream", &[N64, Type::Str], Type::Void, |args| match args.as_slice() {
+            [Value::Integer(1), Value::String(text)] => {
+                print!("{}", text);
+                let _ = io::stdout().flush();
+                Value::Undefined
+            },
+            [Value::Integer(2), Value::String(text)] => {
+                eprint!("{}", text);
+                Value::Undefined
+            },
+            args => panic!("Can't write {:?}", args),
+        })
+        .register("read_line", &[], Type::Str, |_| {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line).expect("Couldn't read from stdin");
+            Value::String(line.trim_end_matches(&['\n', '\r'][..]).to_owned())
+        })
+        .register("read_file", &[Type::Str], Type::Str, |args| {
+            let path = string(&args[0]);
+            Value::String(
+                fs::read_to_string(path).unwrap_or_else(|error| panic!("Couldn't read {}: {}", path, error))
+            )
+        })
+        .register("write_file", &[Type::Str, Type::Str], Type::Void, |args| {
+            let path = string(&args[0]);
+            fs::write(path, string(&args[1])).unwrap_or_else(|error| panic!("Couldn't write {}: {}", path, error));
+            Value::Undefined
+        })
+        // seconds since the Unix epoch
+        .register("clock", &[], F64, |_| {
+            Value::Float(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |time| time.as_secs_f64()))
+        })
+        .register("len", &[ANY], N64, |args| match &args[0] {
+            Value::String(s) => Value::Integer(s.chars().count() as i128),
+            Value::Array(elements) => Value::Integer(elements.len() as i128),
+            value => panic!("{:?} doesn't have a length", value),
+        })
+        .register("append", &[Type::Array(Box::new(ANY)), ANY], Type::Array(Box::new(ANY)), |mut args| {
+            let value = args.pop().unwrap();
+            match args.pop().unwrap() {
+                Value::Array(mut elements) => {
+                    elements.push(value);
+                    Value::Array(elements)
+                },
+                array => panic!("Can't append to {:?}, which isn't an array", array),
+            }
+        })
+        .register("to_string", &[ANY], Type::Str, |args| Value::String(display(&args[0])))
+        .register("sqrt", &[F64], F64, |args| Value::Float(float(&args[0]).sqrt()))
+        .symbol("sqrt")
+        .register("floor", &[F64], F64, |args| Value::Float(float(&args[0]).floor()))
+        .symbol("floor");
+    registry
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::Bool(b) => b.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::String(s) => s.clone(),
+        value => format!("{:?}", value),
+    }
+}
+
+fn string(value: &Value) -> &str {
+    match value {
+        Value::String(s) => s,
+        value => panic!("Expected a string, but found {:?}", value),
+    }
+}
+
+fn float(value: &Value) -> f64 {
+    match value {
+        Value::Float(f) => *f,
+        value => panic!("Expected a float, but found {:?}", value),
+    }
+}
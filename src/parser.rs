@@ -1,11 +1,10 @@
 //! This module converts the token stream from the lexer into an AST.
 
-use std::cell::RefMut;
 use std::fmt;
 
 use crate::{
     errors::Errors,
-    lexer::{Token, TokenKind},
+    lexer::{Lexer, Token, TokenKind},
 };
 
 #[derive(Debug)]
@@ -45,8 +44,39 @@ pub enum Node {
         typ: Type,
         value: String,
     },
+    Tuple {
+        elements: Vec<NodeContext>,
+    },
+    ArrayLiteral {
+        elements: Vec<NodeContext>,
+    },
+    ArrayType {
+        size: Option<Box<NodeContext>>,
+        element: Box<NodeContext>,
+    },
+    StructType {
+        field_names: Vec<String>,
+        field_types: Vec<NodeContext>,
+    },
+    StructLiteral {
+        typ: Box<NodeContext>,
+        field_names: Vec<String>,
+        field_values: Vec<NodeContext>,
+    },
+    FieldAccess {
+        object: Box<NodeContext>,
+        field: String,
+    },
+    EnumType {
+        variant_names: Vec<String>,
+        variant_payloads: Vec<Vec<NodeContext>>,
+    },
+    Match {
+        value: Box<NodeContext>,
+        arms: Vec<MatchArm>,
+    },
     Call {
-        name: String,
+        callee: Box<NodeContext>,
         args: Vec<NodeContext>,
     },
     VariableRef {
@@ -56,6 +86,11 @@ pub enum Node {
         name: String,
         typ: Box<NodeContext>,
         body: Box<NodeContext>,
+        public: bool,
+    },
+    Destructure {
+        names: Vec<String>,
+        body: Box<NodeContext>,
     },
     IfExpression {
         condition: Box<NodeContext>,
@@ -65,17 +100,69 @@ pub enum Node {
     WhileExpression {
         condition: Box<NodeContext>,
         body: Box<NodeContext>,
+        label: Option<String>,
     },
     Assignment {
         name: String,
         value: Box<NodeContext>,
     },
+    FieldAssignment {
+        object: Box<NodeContext>,
+        field: String,
+        value: Box<NodeContext>,
+    },
     FunctionExpression {
         arg_types: Vec<NodeContext>,
         arg_names: Vec<String>,
         ret_types: Vec<NodeContext>,
         body: Box<NodeContext>,
-    }
+    },
+    FunctionType {
+        arg_types: Vec<NodeContext>,
+        ret_types: Vec<NodeContext>,
+    },
+    ForExpression {
+        variable: String,
+        iterable: Box<NodeContext>,
+        body: Box<NodeContext>,
+        label: Option<String>,
+    },
+    Return {
+        value: Box<NodeContext>,
+    },
+    Break {
+        label: Option<String>,
+        value: Box<NodeContext>,
+    },
+    Continue {
+        label: Option<String>,
+    },
+    Use {
+        path: Vec<String>,
+    },
+}
+
+#[derive(Debug)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<NodeContext>,
+    pub body: NodeContext,
+}
+
+#[derive(Debug)]
+pub enum Pattern {
+    Wildcard,
+    Binding(String),
+    Literal {
+        typ: Type,
+        value: String,
+    },
+    Tuple(Vec<Pattern>),
+    Variant {
+        enum_name: String,
+        variant: String,
+        payload: Vec<Pattern>,
+    },
 }
 
 pub struct NodeContext {
@@ -91,25 +178,24 @@ impl fmt::Debug for NodeContext {
 }
 
 pub struct Parser<'p> {
-    tokens: &'p [Token],
-    index: usize,
-    source_position: usize,
-    errors: RefMut<'p, Errors>,
+    tokens: Lexer<'p>,
 }
 
 impl<'p> Parser<'p> {
-    pub fn new(tokens: &'p [Token], errors: RefMut<'p, Errors>) -> Self {
+    pub fn new(tokens: Lexer<'p>) -> Self {
         Parser {
             tokens,
-            index: 0,
-            source_position: 0,
-            errors,
         }
     }
 
+    /// The parser reports its errors to the same place as the lexer it pulls tokens from
+    fn errors(&mut self) -> &mut Errors {
+        self.tokens.errors()
+    }
+
+    /// Code which runs off the end keeps finding EOF, so it's reported like any other wrong token
     fn consume(&mut self) -> Token {
-        self.index += 1;
-        self.tokens[self.index - 1].clone()
+        self.tokens.next().unwrap_or_else(|| self.tokens.end_of_file())
     }
 
     fn consume_of_kind(&mut self, kind: TokenKind) -> Option<Token> {
@@ -117,7 +203,7 @@ impl<'p> Parser<'p> {
         if peeked.kind == kind {
             Some(self.consume())
         } else {
-            self.errors.parser(
+            self.errors().parser(
                 format!("Expected token {:?}, but found {:?} instead", kind, peeked.kind),
                 peeked.position,
             );
@@ -138,7 +224,7 @@ impl<'p> Parser<'p> {
         if peeked.kind == TokenKind::Identifier {
             Some(self.consume().value)
         } else {
-            self.errors.parser(
+            self.errors().parser(
                 format!("Expected an identifier, but found {:?} instead", peeked.kind),
                 peeked.position,
             );
@@ -154,56 +240,109 @@ impl<'p> Parser<'p> {
         }
     }
 
-    fn peek(&self) -> Token {
-        self.tokens[self.index].clone()
+    fn peek(&mut self) -> Token {
+        match self.tokens.peek() {
+            Some(token) => token.clone(),
+            None => self.tokens.end_of_file(),
+        }
     }
 
-    // TODO source_position needs to be properly saved and restored
+    fn peek_nth(&mut self, n: usize) -> Token {
+        match self.tokens.peek_nth(n) {
+            Some(token) => token.clone(),
+            None => self.tokens.end_of_file(),
+        }
+    }
 
-    fn in_context(&mut self, constant: bool, node: Node) -> NodeContext {
+    /// A node is placed where the token it starts with is, or at its operator if it has one
+    fn in_context(&self, position: usize, constant: bool, node: Node) -> NodeContext {
         NodeContext {
             node,
-            position: self.source_position,
+            position,
             constant,
-        } 
+        }
     }
 
     pub fn go(&mut self) -> Option<NodeContext> {
+        self.block(false)
+    }
+
+    /// The nodes of a whole file, or of a block up to its closing brace
+    fn block(&mut self, braced: bool) -> Option<NodeContext> {
+        let position = self.peek().position;
         let mut nodes = vec![];
-        loop {
+        let end = loop {
             nodes.push(
-                if self.tokens[self.index + 1].kind == TokenKind::Colon {
+                if self.at_declaration() {
                     self.declaration()?
-                } else if self.tokens[self.index + 1].kind == TokenKind::Equals {
+                } else if self.peek().kind == TokenKind::Identifier
+                    && self.peek_nth(1).kind == TokenKind::Equals {
                     self.assignment()?
                 } else {
-                    self.expr(0)?
+                    let expr = self.expr(0)?;
+                    if self.peek().kind == TokenKind::Equals {
+                        self.field_assignment(expr)?
+                    } else {
+                        expr
+                    }
                 }
             );
-            if self.try_consume_of_kind(TokenKind::EOF).is_some() {
-                break;
+            if let Some(end) = self.try_consume_of_kind(TokenKind::EOF) {
+                break end;
+            }
+            // blocks can be written on one line, like `{ x }`
+            if let Some(end) = self.try_consume_of_kind(TokenKind::RBrace) {
+                break end;
             }
             self.consume_of_kind(TokenKind::Newline)?;
-            if self.try_consume_of_kind(TokenKind::RBrace).is_some() {
-                break;
+            if let Some(end) = self.try_consume_of_kind(TokenKind::RBrace) {
+                break end;
             }
-            if self.try_consume_of_kind(TokenKind::EOF).is_some() {
-                break;
+            if let Some(end) = self.try_consume_of_kind(TokenKind::EOF) {
+                break end;
             }
+        };
+        if braced && end.kind == TokenKind::EOF {
+            self.errors().parser("Expected token RBrace, but found EOF instead".to_owned(), end.position);
+            return None;
         }
 
-        Some(self.in_context(false, Node::Block { nodes }))
+        Some(self.in_context(position, false, Node::Block { nodes }))
+    }
+
+    /// Declarations look like `name: ...` or `a, b := ...`, but so do labeled loops
+    fn at_declaration(&mut self) -> bool {
+        match self.peek().kind {
+            TokenKind::Pub | TokenKind::Const => true,
+            TokenKind::Identifier => {
+                let mut n = 1;
+                while self.peek_nth(n).kind == TokenKind::Comma
+                    && self.peek_nth(n + 1).kind == TokenKind::Identifier {
+                    n += 2;
+                }
+                self.peek_nth(n).kind == TokenKind::Colon
+                    && (n > 1 || !is_loop_keyword(&self.peek_nth(n + 1).kind))
+            }
+            _ => false,
+        }
     }
 
     fn declaration(&mut self) -> Option<NodeContext> {
+        let position = self.peek().position;
+        let public = self.try_consume_of_kind(TokenKind::Pub).is_some();
+        // declarations are constant whether or not they say so
+        self.try_consume_of_kind(TokenKind::Const);
         let name = self.consume_identifier()?;        
+        if self.peek().kind == TokenKind::Comma {
+            return self.destructure(name, position);
+        }
         self.consume_of_kind(TokenKind::Colon)?;
 
         let typ;
         let body;
 
         if self.try_consume_of_kind(TokenKind::Equals).is_some() {
-            typ = self.in_context(true, Node::Literal {
+            typ = self.in_context(position, true, Node::Literal {
                 typ: Type::Unknown,
                 value: "".to_owned(),
             });
@@ -213,28 +352,54 @@ impl<'p> Parser<'p> {
             if self.try_consume_of_kind(TokenKind::Equals).is_some() {
                 body = self.expr(0)?;
             } else {
-                body = self.in_context(true, Node::Literal {
+                body = self.in_context(position, true, Node::Literal {
                     typ: Type::Undefined,
                     value: "undef".to_owned(),
                 });
             }
         }
-        Some(self.in_context(true, Node::Declaration {
+        Some(self.in_context(position, true, Node::Declaration {
             name,
             typ: Box::new(typ),
             body: Box::new(body),
+            public,
+        }))
+    }
+
+    /// `q, r := divmod(a, b)` declares a variable for each value of a tuple
+    fn destructure(&mut self, first: String, position: usize) -> Option<NodeContext> {
+        let mut names = vec![first];
+        while self.try_consume_of_kind(TokenKind::Comma).is_some() {
+            names.push(self.consume_identifier()?);
+        }
+        self.consume_of_kind(TokenKind::Colon)?;
+        self.consume_of_kind(TokenKind::Equals)?;
+        let body = self.expr(0)?;
+
+        Some(self.in_context(position, true, Node::Destructure {
+            names,
+            body: Box::new(body),
         }))
     }
 
-    fn function_expression(&mut self) -> Option<NodeContext> {
+    /// Without names for its parameters, `fn(n64, n64) bool` is the type of a function rather than
+    /// a function itself. Where only a type can go, `only_type` stops a following block from being
+    /// taken as its body.
+    fn function_expression(&mut self, position: usize, only_type: bool) -> Option<NodeContext> {
         self.consume_of_kind(TokenKind::LParen);
         let mut arg_names = vec![];
         let mut arg_types = vec![];
+        let mut is_type = only_type;
 
         if self.try_consume_of_kind(TokenKind::RParen).is_none() {
+            is_type = only_type
+                || self.peek().kind != TokenKind::Identifier
+                || self.peek_nth(1).kind != TokenKind::Colon;
             loop {
-                arg_names.push(self.consume_identifier()?);
-                self.consume_of_kind(TokenKind::Colon)?;
+                if !is_type {
+                    arg_names.push(self.consume_identifier()?);
+                    self.consume_of_kind(TokenKind::Colon)?;
+                }
                 arg_types.push(self.expr(0)?);
                 if self.try_consume_of_kind(TokenKind::Comma).is_none() {
                     break;
@@ -243,94 +408,480 @@ impl<'p> Parser<'p> {
             self.consume_of_kind(TokenKind::RParen)?;
         }
 
-        // TODO multiple return types
-        let ret_type = self.expr(0)?;
+        // several return types are written like a tuple, `(n64, bool)`
+        let mut ret_types = vec![];
+        if self.try_consume_of_kind(TokenKind::LParen).is_some() {
+            loop {
+                ret_types.push(self.return_type()?);
+                if self.try_consume_of_kind(TokenKind::Comma).is_none() {
+                    break;
+                }
+            }
+            self.consume_of_kind(TokenKind::RParen)?;
+        } else {
+            ret_types.push(self.return_type()?);
+        }
+
+        // without any parameters, only the missing body shows that it's a type
+        if is_type || (arg_types.is_empty() && self.peek().kind != TokenKind::LBrace) {
+            return Some(self.in_context(position, true, Node::FunctionType {
+                arg_types,
+                ret_types,
+            }));
+        }
 
         let body = self.expr(0)?; // TODO this needs to specifically be a block???
-        Some(self.in_context(true, Node::FunctionExpression {
+        Some(self.in_context(position, true, Node::FunctionExpression {
             arg_types,
             arg_names,
-            ret_types: vec![ret_type],
+            ret_types,
             body: Box::new(body),
         }))
     }
 
+    /// A function returned from a function is always its type, so the block after
+    /// `fn() fn() n64 { ... }` is the body of the outer function
+    fn return_type(&mut self) -> Option<NodeContext> {
+        if let Some(token) = self.try_consume_of_kind(TokenKind::Fn) {
+            self.function_expression(token.position, true)
+        } else {
+            self.expr(0)
+        }
+    }
+
     fn assignment(&mut self) -> Option<NodeContext> {
+        let position = self.peek().position;
         let name = self.consume_identifier()?;
         self.consume_of_kind(TokenKind::Equals)?;
         let value = self.expr(0)?;
 
-        Some(self.in_context(false, Node::Assignment {
+        Some(self.in_context(position, false, Node::Assignment {
             name,
             value: Box::new(value),
         }))
     }
 
-    fn if_expression(&mut self) -> Option<NodeContext> {
+    /// Assigning to anything other than a variable has to parse the target as an expression first
+    fn field_assignment(&mut self, target: NodeContext) -> Option<NodeContext> {
+        let equals = self.consume_of_kind(TokenKind::Equals)?;
+        let value = self.expr(0)?;
+
+        if let Node::FieldAccess { object, field } = target.node {
+            Some(self.in_context(target.position, false, Node::FieldAssignment {
+                object,
+                field,
+                value: Box::new(value),
+            }))
+        } else {
+            self.errors().parser(
+                "Only variables and fields can be assigned to".to_owned(),
+                equals.position,
+            );
+            None
+        }
+    }
+
+    /// `struct { x: f64, y: f64 }`, where fields are separated by commas or newlines
+    fn struct_type(&mut self, position: usize) -> Option<NodeContext> {
+        self.consume_of_kind(TokenKind::LBrace)?;
+        let mut field_names = vec![];
+        let mut field_types = vec![];
+
+        loop {
+            while self.try_consume_of_kind(TokenKind::Newline).is_some() { }
+            if self.try_consume_of_kind(TokenKind::RBrace).is_some() {
+                break;
+            }
+            field_names.push(self.consume_identifier()?);
+            self.consume_of_kind(TokenKind::Colon)?;
+            field_types.push(self.expr(0)?);
+            if self.try_consume_of_kind(TokenKind::Comma).is_none() && self.peek().kind != TokenKind::Newline {
+                self.consume_of_kind(TokenKind::RBrace)?;
+                break;
+            }
+        }
+
+        Some(self.in_context(position, true, Node::StructType {
+            field_names,
+            field_types,
+        }))
+    }
+
+    /// Struct literals name their fields Zig-style, `Point{ .x = 1.0, .y = 2.0 }`, so they can't be
+    /// mistaken for a block after a condition
+    fn at_struct_literal(&mut self) -> bool {
+        if self.peek().kind != TokenKind::LBrace {
+            return false;
+        }
+        let mut n = 1;
+        while self.peek_nth(n).kind == TokenKind::Newline {
+            n += 1;
+        }
+        let peeked = self.peek_nth(n);
+        peeked.kind == TokenKind::Operator && peeked.value == "."
+    }
+
+    fn struct_literal(&mut self, name: String, position: usize) -> Option<NodeContext> {
+        let typ = self.in_context(position, false, Node::VariableRef { name });
+        self.consume_of_kind(TokenKind::LBrace)?;
+        let mut field_names = vec![];
+        let mut field_values = vec![];
+
+        loop {
+            while self.try_consume_of_kind(TokenKind::Newline).is_some() { }
+            if self.try_consume_of_kind(TokenKind::RBrace).is_some() {
+                break;
+            }
+            let dot = self.consume_of_kind(TokenKind::Operator)?;
+            if dot.value != "." {
+                self.errors().parser(
+                    format!("Expected a field like .name, but found {} instead", dot.value),
+                    dot.position,
+                );
+                return None;
+            }
+            field_names.push(self.consume_identifier()?);
+            self.consume_of_kind(TokenKind::Equals)?;
+            field_values.push(self.expr(0)?);
+            if self.try_consume_of_kind(TokenKind::Comma).is_none() && self.peek().kind != TokenKind::Newline {
+                self.consume_of_kind(TokenKind::RBrace)?;
+                break;
+            }
+        }
+
+        Some(self.in_context(position, false, Node::StructLiteral {
+            typ: Box::new(typ),
+            field_names,
+            field_values,
+        }))
+    }
+
+    /// `enum { Circle(f64), Rect(f64, f64), Empty }`, where variants are separated by commas or
+    /// newlines
+    fn enum_type(&mut self, position: usize) -> Option<NodeContext> {
+        self.consume_of_kind(TokenKind::LBrace)?;
+        let mut variant_names = vec![];
+        let mut variant_payloads = vec![];
+
+        loop {
+            while self.try_consume_of_kind(TokenKind::Newline).is_some() { }
+            if self.try_consume_of_kind(TokenKind::RBrace).is_some() {
+                break;
+            }
+            variant_names.push(self.consume_identifier()?);
+            variant_payloads.push(if self.peek().kind == TokenKind::LParen {
+                self.parenthesized_list()?
+            } else {
+                vec![]
+            });
+            if self.try_consume_of_kind(TokenKind::Comma).is_none() && self.peek().kind != TokenKind::Newline {
+                self.consume_of_kind(TokenKind::RBrace)?;
+                break;
+            }
+        }
+
+        Some(self.in_context(position, true, Node::EnumType {
+            variant_names,
+            variant_payloads,
+        }))
+    }
+
+    /// `(a, b, c)`, as used by call arguments and variant payloads
+    fn parenthesized_list(&mut self) -> Option<Vec<NodeContext>> {
+        self.consume_of_kind(TokenKind::LParen)?;
+        let mut nodes = vec![];
+        while self.peek().kind != TokenKind::RParen {
+            nodes.push(self.expr(0)?);
+            if self.try_consume_of_kind(TokenKind::Comma).is_none() {
+                break;
+            }
+        }
+        self.consume_of_kind(TokenKind::RParen)?;
+        Some(nodes)
+    }
+
+    /// ```text
+    /// match shape {
+    ///     Shape.Rect(w, h) if w == h => w * w
+    ///     Shape.Rect(w, h) => w * h
+    ///     _ => 0
+    /// }
+    /// ```
+    fn match_expression(&mut self, position: usize) -> Option<NodeContext> {
+        let value = self.expr(0)?;
+        self.consume_of_kind(TokenKind::LBrace)?;
+        let mut arms = vec![];
+
+        loop {
+            while self.try_consume_of_kind(TokenKind::Newline).is_some() { }
+            if self.try_consume_of_kind(TokenKind::RBrace).is_some() {
+                break;
+            }
+            let pattern = self.pattern()?;
+            let guard = if self.try_consume_of_kind(TokenKind::If).is_some() {
+                Some(self.expr(0)?)
+            } else {
+                None
+            };
+            let arrow = self.consume_of_kind(TokenKind::Operator)?;
+            if arrow.value != "=>" {
+                self.errors().parser(
+                    format!("Expected => after a pattern, but found {} instead", arrow.value),
+                    arrow.position,
+                );
+                return None;
+            }
+            let body = self.expr(0)?;
+            arms.push(MatchArm { pattern, guard, body });
+
+            if self.try_consume_of_kind(TokenKind::Comma).is_none() && self.peek().kind != TokenKind::Newline {
+                self.consume_of_kind(TokenKind::RBrace)?;
+                break;
+            }
+        }
+
+        Some(self.in_context(position, false, Node::Match {
+            value: Box::new(value),
+            arms,
+        }))
+    }
+
+    fn pattern(&mut self) -> Option<Pattern> {
+        let token = self.consume();
+        Some(match token.kind {
+            TokenKind::Identifier if token.value == "_" => Pattern::Wildcard,
+            TokenKind::Identifier if self.peek().kind == TokenKind::Operator && self.peek().value == "." => {
+                self.consume();
+                let variant = self.consume_identifier()?;
+                let mut payload = vec![];
+                if self.try_consume_of_kind(TokenKind::LParen).is_some() {
+                    while self.peek().kind != TokenKind::RParen {
+                        payload.push(self.pattern()?);
+                        if self.try_consume_of_kind(TokenKind::Comma).is_none() {
+                            break;
+                        }
+                    }
+                    self.consume_of_kind(TokenKind::RParen)?;
+                }
+                Pattern::Variant {
+                    enum_name: token.value,
+                    variant,
+                    payload,
+                }
+            },
+            TokenKind::Identifier => Pattern::Binding(token.value),
+            TokenKind::IntegerLiteral => Pattern::Literal { typ: Type::IntLiteral, value: token.value },
+            TokenKind::FloatLiteral => Pattern::Literal { typ: Type::FloatLiteral, value: token.value },
+            TokenKind::StringLiteral => Pattern::Literal { typ: Type::StrLiteral, value: token.value },
+            TokenKind::True | TokenKind::False => Pattern::Literal { typ: Type::Bool, value: token.value },
+            TokenKind::Operator if token.value == "-"
+                && matches!(self.peek().kind, TokenKind::IntegerLiteral | TokenKind::FloatLiteral) => {
+                let literal = self.consume();
+                Pattern::Literal {
+                    typ: if literal.kind == TokenKind::IntegerLiteral { Type::IntLiteral } else { Type::FloatLiteral },
+                    value: format!("-{}", literal.value),
+                }
+            },
+            TokenKind::LParen => {
+                let mut elements = vec![];
+                while self.peek().kind != TokenKind::RParen {
+                    elements.push(self.pattern()?);
+                    if self.try_consume_of_kind(TokenKind::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.consume_of_kind(TokenKind::RParen)?;
+                if elements.len() == 1 {
+                    elements.pop().unwrap()
+                } else {
+                    Pattern::Tuple(elements)
+                }
+            },
+            _ => {
+                self.errors().parser(
+                    format!("Expected a pattern, but found {:?} instead", token.kind),
+                    token.position,
+                );
+                return None;
+            },
+        })
+    }
+
+    /// Array literals look like `[1, 2, 3]`, but when a type follows the brackets they are an array
+    /// type instead, either with a fixed size, `[3]n64`, or without one, `[]n64`
+    fn array(&mut self, position: usize) -> Option<NodeContext> {
+        let mut elements = vec![];
+        loop {
+            while self.try_consume_of_kind(TokenKind::Newline).is_some() { }
+            if self.try_consume_of_kind(TokenKind::RBracket).is_some() {
+                break;
+            }
+            elements.push(self.expr(0)?);
+            while self.try_consume_of_kind(TokenKind::Newline).is_some() { }
+            if self.try_consume_of_kind(TokenKind::Comma).is_none() {
+                self.consume_of_kind(TokenKind::RBracket)?;
+                break;
+            }
+        }
+
+        if !matches!(self.peek().kind, TokenKind::Identifier | TokenKind::Fn) {
+            return Some(self.in_context(position, false, Node::ArrayLiteral { elements }));
+        }
+
+        if elements.len() > 1 {
+            self.errors().parser(
+                "Array types can only have one size".to_owned(),
+                position,
+            );
+            return None;
+        }
+        // `[]a.B[0]` indexes an array of `a.B`s
+        let element = self.expr(16)?;
+        Some(self.in_context(position, true, Node::ArrayType {
+            size: elements.pop().map(Box::new),
+            element: Box::new(element),
+        }))
+    }
+
+    fn if_expression(&mut self, position: usize) -> Option<NodeContext> {
         // if doesn't actually consume an if cause it is done for it before calling
         let condition = self.expr(0)?;
         let then_body = self.expr(0)?;
         let else_body;
         if self.try_consume_of_kind(TokenKind::Else).is_some() {
             else_body = self.expr(0)?;
-        } else if self.try_consume_of_kind(TokenKind::Elif).is_some() {
-            else_body = self.if_expression()?;
+        } else if let Some(elif) = self.try_consume_of_kind(TokenKind::Elif) {
+            else_body = self.if_expression(elif.position)?;
         } else {
-            else_body = self.in_context(true, Node::Literal { typ: Type::Undefined, value: "undef".to_owned() });
+            else_body = self.in_context(position, true, Node::Literal { typ: Type::Undefined, value: "undef".to_owned() });
         }
 
-        Some(self.in_context(false, Node::IfExpression {
+        Some(self.in_context(position, false, Node::IfExpression {
             condition: Box::new(condition),
             then_body: Box::new(then_body),
             else_body: Box::new(else_body),
         }))
     }
 
-    fn while_expression(&mut self) -> Option<NodeContext> {
+    fn while_expression(&mut self, label: Option<String>, position: usize) -> Option<NodeContext> {
         let condition = self.expr(0)?;
         let body = self.expr(0)?;
 
-        Some(self.in_context(false, Node::WhileExpression {
+        Some(self.in_context(position, false, Node::WhileExpression {
             condition: Box::new(condition),
             body: Box::new(body),
+            label,
         }))
     }
 
-    fn loop_expression(&mut self) -> Option<NodeContext> {
-        let condition = self.in_context(true, Node::Literal { typ: Type::Bool, value: "true".to_owned() });
+    fn loop_expression(&mut self, label: Option<String>, position: usize) -> Option<NodeContext> {
+        let condition = self.in_context(position, true, Node::Literal { typ: Type::Bool, value: "true".to_owned() });
         let body = self.expr(0)?;
 
-        Some(self.in_context(false, Node::WhileExpression {
+        Some(self.in_context(position, false, Node::WhileExpression {
             condition: Box::new(condition),
             body: Box::new(body),
+            label,
+        }))
+    }
+
+    fn for_expression(&mut self, label: Option<String>, position: usize) -> Option<NodeContext> {
+        let variable = self.consume_identifier()?;
+        self.consume_of_kind(TokenKind::In)?;
+        let iterable = self.expr(0)?;
+        let body = self.expr(0)?;
+
+        Some(self.in_context(position, false, Node::ForExpression {
+            variable,
+            iterable: Box::new(iterable),
+            body: Box::new(body),
+            label,
+        }))
+    }
+
+    /// A loop with a label in front of it, like `outer: loop { ... }`
+    fn labeled_loop(&mut self, label: String, position: usize) -> Option<NodeContext> {
+        self.consume_of_kind(TokenKind::Colon)?;
+        let label = Some(label);
+        match self.consume().kind {
+            TokenKind::While => self.while_expression(label, position),
+            TokenKind::Loop => self.loop_expression(label, position),
+            TokenKind::For => self.for_expression(label, position),
+            _ => unreachable!(),
+        }
+    }
+
+    fn return_expression(&mut self, position: usize) -> Option<NodeContext> {
+        let value = self.optional_value(position)?;
+
+        Some(self.in_context(position, false, Node::Return {
+            value: Box::new(value),
+        }))
+    }
+
+    fn break_expression(&mut self, position: usize) -> Option<NodeContext> {
+        let label = self.optional_label()?;
+        let value = self.optional_value(position)?;
+
+        Some(self.in_context(position, false, Node::Break {
+            label,
+            value: Box::new(value),
+        }))
+    }
+
+    fn continue_expression(&mut self, position: usize) -> Option<NodeContext> {
+        let label = self.optional_label()?;
+
+        Some(self.in_context(position, false, Node::Continue {
+            label,
+        }))
+    }
+
+    /// The label after a break or continue, written like `break :outer`
+    fn optional_label(&mut self) -> Option<Option<String>> {
+        if self.try_consume_of_kind(TokenKind::Colon).is_some() {
+            Some(Some(self.consume_identifier()?))
+        } else {
+            Some(None)
+        }
+    }
+
+    /// The value after a return or break, which is undefined if the expression ends right away
+    fn optional_value(&mut self, position: usize) -> Option<NodeContext> {
+        if matches!(self.peek().kind, TokenKind::Newline | TokenKind::RBrace | TokenKind::EOF) {
+            Some(self.in_context(position, true, Node::Literal { typ: Type::Undefined, value: "undef".to_owned() }))
+        } else {
+            self.expr(0)
+        }
+    }
+
+    fn use_declaration(&mut self, position: usize) -> Option<NodeContext> {
+        let mut path = vec![self.consume_identifier()?];
+        while self.peek().kind == TokenKind::Operator && self.peek().value == "." {
+            self.consume();
+            path.push(self.consume_identifier()?);
+        }
+
+        Some(self.in_context(position, true, Node::Use {
+            path,
         }))
     }
 
     fn expr(&mut self, min_bp: u8) -> Option<NodeContext> {
-        let mut left = match self.consume() {
+        let token = self.consume();
+        let position = token.position;
+        let mut left = match token {
             Token {
                 kind: TokenKind::Identifier,
                 value: id,
                 ..
             } => {
-                if self.peek().kind == TokenKind::LParen {
-                    self.consume(); // pass the LParen;
-                    let mut args = Vec::new();
-                    while self.peek().kind != TokenKind::RParen {
-                        args.push(self.expr(0)?);
-                        if self.peek().kind != TokenKind::Comma {
-                            break;
-                        } else {
-                            self.consume_of_kind(TokenKind::Comma)?;
-                        }
-                    }
-                    self.consume_of_kind(TokenKind::RParen)?;
-                    self.in_context(false, Node::Call {
-                        name: id,
-                        args,
-                    })
+                if self.peek().kind == TokenKind::Colon && is_loop_keyword(&self.peek_nth(1).kind) {
+                    self.labeled_loop(id, position)?
+                } else if self.at_struct_literal() {
+                    self.struct_literal(id, position)?
                 } else {
-                    self.in_context(false, Node::VariableRef {
+                    self.in_context(position, false, Node::VariableRef {
                         name: id,
                     })
                 }
@@ -339,7 +890,7 @@ impl<'p> Parser<'p> {
                 kind: TokenKind::IntegerLiteral,
                 value: int,
                 ..
-            } => self.in_context(true, Node::Literal {
+            } => self.in_context(position, true, Node::Literal {
                 typ: Type::IntLiteral,
                 value: int,
             }),
@@ -347,7 +898,7 @@ impl<'p> Parser<'p> {
                 kind: TokenKind::FloatLiteral,
                 value: float,
                 ..
-            } => self.in_context(true, Node::Literal {
+            } => self.in_context(position, true, Node::Literal {
                 typ: Type::FloatLiteral,
                 value: float,
             }),
@@ -355,7 +906,7 @@ impl<'p> Parser<'p> {
                 kind: TokenKind::StringLiteral,
                 value: s,
                 ..
-            } => self.in_context(true, Node::Literal {
+            } => self.in_context(position, true, Node::Literal {
                 typ: Type::StrLiteral,
                 value: s,
             }),
@@ -364,27 +915,49 @@ impl<'p> Parser<'p> {
                 ..
             } => {
                 let left = self.expr(0)?;
-                self.consume_of_kind(TokenKind::RParen)?;
-                left
+                if self.peek().kind == TokenKind::Comma {
+                    let mut elements = vec![left];
+                    while self.try_consume_of_kind(TokenKind::Comma).is_some() {
+                        elements.push(self.expr(0)?);
+                    }
+                    self.consume_of_kind(TokenKind::RParen)?;
+                    self.in_context(position, false, Node::Tuple { elements })
+                } else {
+                    self.consume_of_kind(TokenKind::RParen)?;
+                    left
+                }
             }
             Token {
                 kind: TokenKind::Operator,
                 value: op,
                 ..
             } => {
-                let ((), right_bp) = prefix_binding_power(&op);
+                let ((), right_bp) = if let Some(bp) = prefix_binding_power(&op) {
+                    bp
+                } else {
+                    self.errors().parser(
+                        format!("Operator {} cannot be used as a prefix operator", op), position
+                    );
+                    return None
+                };
                 let right = self.expr(right_bp)?;
-                self.in_context(false, Node::PrefixOp {
+                self.in_context(position, false, Node::PrefixOp {
                     op,
                     right: Box::new(right),
                 })
             },
+            Token {
+                kind: TokenKind::LBracket,
+                ..
+            } => {
+                self.array(position)?
+            },
             Token {
                 kind: TokenKind::LBrace,
                 ..
             } => {
                 while self.try_consume_of_kind(TokenKind::Newline).is_some() { }
-                let block = self.go()?;
+                let block = self.block(true)?;
                 //while self.try_consume_of_kind(TokenKind::Newline).is_some() { }
                 block
             },
@@ -392,32 +965,93 @@ impl<'p> Parser<'p> {
                 kind: TokenKind::If,
                 ..
             } => {
-                self.if_expression()?
+                self.if_expression(position)?
             },
             Token {
                 kind: TokenKind::While,
                 ..
             } => {
-                self.while_expression()?
+                self.while_expression(None, position)?
             },
             Token {
                 kind: TokenKind::Loop,
                 ..
             } => {
-                self.loop_expression()?
+                self.loop_expression(None, position)?
             },
             Token {
                 kind: TokenKind::Fn,
                 ..
             } => {
-                self.function_expression()?
+                self.function_expression(position, false)?
+            },
+            Token {
+                kind: TokenKind::True,
+                ..
+            } => self.in_context(position, true, Node::Literal {
+                typ: Type::Bool,
+                value: "true".to_owned(),
+            }),
+            Token {
+                kind: TokenKind::False,
+                ..
+            } => self.in_context(position, true, Node::Literal {
+                typ: Type::Bool,
+                value: "false".to_owned(),
+            }),
+            Token {
+                kind: TokenKind::For,
+                ..
+            } => {
+                self.for_expression(None, position)?
+            },
+            Token {
+                kind: TokenKind::Return,
+                ..
+            } => {
+                self.return_expression(position)?
+            },
+            Token {
+                kind: TokenKind::Break,
+                ..
+            } => {
+                self.break_expression(position)?
+            },
+            Token {
+                kind: TokenKind::Continue,
+                ..
+            } => {
+                self.continue_expression(position)?
+            },
+            Token {
+                kind: TokenKind::Use,
+                ..
+            } => {
+                self.use_declaration(position)?
+            },
+            Token {
+                kind: TokenKind::Struct,
+                ..
+            } => {
+                self.struct_type(position)?
+            },
+            Token {
+                kind: TokenKind::Enum,
+                ..
+            } => {
+                self.enum_type(position)?
+            },
+            Token {
+                kind: TokenKind::Match,
+                ..
+            } => {
+                self.match_expression(position)?
             },
             Token {
                 kind: TokenKind::EOF,
-                position,
                 ..
             } => {
-                self.errors.parser(
+                self.errors().parser(
                     "Encountered the end of the file while parsing".to_owned(), position
                 );
                 return None
@@ -427,27 +1061,37 @@ impl<'p> Parser<'p> {
 
         loop {
             let peeked = self.peek();
+            let position = peeked.position;
             let op = match peeked.kind {
                 TokenKind::Operator => peeked.value,
                 TokenKind::LBracket => "[".to_owned(),
+                TokenKind::LParen => "(".to_owned(),
                 _ => break,
             };
 
-            if let Some((left_bp, ())) = postfix_binding_power(&op) {
+            // `..` is only a postfix operator when nothing follows it, like in `a[1..]`
+            let postfix = op != ".." || ends_expression(&self.peek_nth(1).kind);
+            if let Some((left_bp, ())) = postfix_binding_power(&op).filter(|_| postfix) {
                 if left_bp < min_bp {
                     break;
                 }
-                self.consume();
-
-                left = if op == "[" {
+                left = if op == "(" {
+                    let args = self.parenthesized_list()?;
+                    self.in_context(position, false, Node::Call {
+                        callee: Box::new(left),
+                        args,
+                    })
+                } else if op == "[" {
+                    self.consume();
                     let right = self.expr(0)?;
                     self.consume_of_kind(TokenKind::RBracket)?;
-                    self.in_context(true, Node::IndexOp {
+                    self.in_context(position, true, Node::IndexOp {
                         object: Box::new(left),
                         index: Box::new(right),
                     })
                 } else {
-                    self.in_context(true, Node::PostfixOp {
+                    self.consume();
+                    self.in_context(position, true, Node::PostfixOp {
                         op,
                         left: Box::new(left),
                     })
@@ -461,12 +1105,20 @@ impl<'p> Parser<'p> {
                 }
                 self.consume();
 
-                let right = self.expr(right_bp)?;
-                left = self.in_context(false, Node::InfixOp {
-                    op,
-                    left: Box::new(left),
-                    right: Box::new(right),
-                });
+                left = if op == "." {
+                    let field = self.consume_identifier()?;
+                    self.in_context(position, false, Node::FieldAccess {
+                        object: Box::new(left),
+                        field,
+                    })
+                } else {
+                    let right = self.expr(right_bp)?;
+                    self.in_context(position, false, Node::InfixOp {
+                        op,
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    })
+                };
                 continue;
             }
 
@@ -477,19 +1129,31 @@ impl<'p> Parser<'p> {
     }
 }
 
-fn prefix_binding_power(op: &String) -> ((), u8) {
-    match op.as_str() {
+fn is_loop_keyword(kind: &TokenKind) -> bool {
+    matches!(kind, TokenKind::While | TokenKind::Loop | TokenKind::For)
+}
+
+fn ends_expression(kind: &TokenKind) -> bool {
+    matches!(kind,
+        TokenKind::RParen | TokenKind::RBracket | TokenKind::LBrace | TokenKind::RBrace
+        | TokenKind::Comma | TokenKind::Newline | TokenKind::EOF
+    )
+}
+
+fn prefix_binding_power(op: &String) -> Option<((), u8)> {
+    Some(match op.as_str() {
         ".." => ((), 1),
-        "!" => ((), 8),
-        "+" | "-" => ((), 9),
-        o => unreachable!(o),
-    }
+        "not" => ((), 7),
+        "!" => ((), 12),
+        "+" | "-" => ((), 13),
+        _ => return None,
+    })
 }
 
 fn postfix_binding_power(op: &String) -> Option<(u8, ())> {
     Some(match op.as_str() {
         ".." => (1, ()),
-        "[" => (11, ()),
+        "[" | "(" => (15, ()),
         _ => return None,
     })
 }
@@ -497,9 +1161,73 @@ fn postfix_binding_power(op: &String) -> Option<(u8, ())> {
 fn infix_binding_power(op: &String) -> Option<(u8, u8)> {
     Some(match op.as_str() {
         ".." => (1, 2),
-        ">" | "<" | ">=" | "<=" | "==" | "!=" => (3, 4),
-        "+" | "-" => (5, 6),
-        "*" | "/" | "//" => (7, 8),
+        "or" => (3, 4),
+        "and" => (5, 6),
+        ">" | "<" | ">=" | "<=" | "==" | "!=" => (7, 8),
+        "+" | "-" | "+%" | "-%" | "+|" | "-|" | "+?" | "-?" => (9, 10),
+        "*" | "/" | "//" | "*%" | "*|" | "*?" => (11, 12),
+        "." => (16, 17),
         _ => return None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    fn parse(contents: &'static str) -> NodeContext {
+        let errors = RefCell::new(Errors::new());
+        let lexer = Lexer::new(contents, errors.borrow_mut());
+        let ast = Parser::new(lexer).go();
+        ast.unwrap()
+    }
+
+    /// The type and the body of the first thing declared
+    fn declaration(ast: &NodeContext) -> (&Node, &Node) {
+        let first = match &ast.node {
+            Node::Block { nodes } => &nodes[0].node,
+            node => panic!("Expected a block, but found {:?}", node),
+        };
+        match first {
+            Node::Declaration { typ, body, .. } => (&typ.node, &body.node),
+            node => panic!("Expected a declaration, but found {:?}", node),
+        }
+    }
+
+    #[test]
+    fn unclosed_block() {
+        let errors = RefCell::new(Errors::new());
+        let ast = Parser::new(Lexer::new("main := fn() n64 {\n    1\n", errors.borrow_mut())).go();
+        assert!(ast.is_none());
+        assert_eq!(errors.borrow().errors, vec![crate::errors::Error::Parser {
+            message: "Expected token RBrace, but found EOF instead".to_owned(),
+            position: 25,
+        }]);
+    }
+
+    #[test]
+    fn function_returning_function() {
+        let ast = parse("make := fn() fn() n64 { fn() n64 { 7 } }");
+        match declaration(&ast).1 {
+            Node::FunctionExpression { arg_types, ret_types, .. } => {
+                assert!(arg_types.is_empty());
+                assert!(matches!(ret_types[0].node, Node::FunctionType { .. }));
+            },
+            node => panic!("Expected a function, but found {:?}", node),
+        }
+    }
+
+    #[test]
+    fn function_type() {
+        let ast = parse("callback: fn(n64) (bool, fn() n64) = undefined");
+        match declaration(&ast).0 {
+            Node::FunctionType { arg_types, ret_types } => {
+                assert_eq!(arg_types.len(), 1);
+                assert!(matches!(ret_types[1].node, Node::FunctionType { .. }));
+            },
+            node => panic!("Expected a function type, but found {:?}", node),
+        }
+    }
+}
@@ -0,0 +1,1663 @@
+//! This module checks that a Meg program uses its types consistently, before it is turned into IR.
+//! Types are checked one module at a time, in the order the module loader found them in, so every
+//! module a module uses has already been checked.
+//!
+//! Integer and float literals don't have a type of their own. They take the type the context they
+//! are used in expects, and so do variables declared with `:=` from them. When nothing decides
+//! what type such a variable is, it has to be written out.
+
+use std::cell::RefMut;
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::fmt;
+
+use crate::{
+    errors::Errors,
+    ir::{
+        top_level_declarations,
+        IntType,
+        NumberType,
+        ScalarType,
+        Signature,
+        TypeAnnotations,
+    },
+    modules::ParsedModule,
+    natives,
+    parser::{
+        self,
+        MatchArm,
+        Node,
+        NodeContext,
+        Pattern,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Integer {
+        signed: bool,
+        bits: u8,
+    },
+    Float {
+        bits: u8,
+    },
+    Bool,
+    Str,
+    Void,
+    Function {
+        params: Vec<Type>,
+        rets: Vec<Type>, // empty for functions returning void
+    },
+    Tuple(Vec<Type>),
+    Array(Box<Type>),
+    Range(Box<Type>),
+    Struct {
+        module: usize,
+        name: String,
+        fields: Vec<(String, Type)>,
+    },
+    Enum {
+        module: usize,
+        name: String,
+        variants: Vec<(String, Vec<Type>)>,
+    },
+    Module(usize),
+    Meta(Box<Type>), // the value of a type expression, like `n64` or a struct type
+
+    IntLiteral, // an integer literal that could still turn out to be any integer type
+    FloatLiteral,
+    Never, // the type of expressions that never finish, like return and break
+    Unknown, // whatever can't be checked, which fits anywhere
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Integer { signed: true, bits } => write!(f, "i{}", bits),
+            Type::Integer { signed: false, bits } => write!(f, "n{}", bits),
+            Type::Float { bits } => write!(f, "f{}", bits),
+            Type::Bool => write!(f, "bool"),
+            Type::Str => write!(f, "str"),
+            Type::Void => write!(f, "void"),
+            Type::Function { params, rets } => {
+                write!(f, "fn({}) ", join(params))?;
+                match rets.as_slice() {
+                    [] => write!(f, "void"),
+                    [ret] => write!(f, "{}", ret),
+                    rets => write!(f, "({})", join(rets)),
+                }
+            },
+            Type::Tuple(elements) => write!(f, "({})", join(elements)),
+            Type::Array(element) => write!(f, "[]{}", element),
+            Type::Range(element) => write!(f, "range of {}", element),
+            Type::Struct { name, .. } | Type::Enum { name, .. } => write!(f, "{}", name),
+            Type::Module(_) => write!(f, "module"),
+            Type::Meta(_) => write!(f, "type"),
+            Type::IntLiteral => write!(f, "integer literal"),
+            Type::FloatLiteral => write!(f, "float literal"),
+            Type::Never => write!(f, "never"),
+            Type::Unknown => write!(f, "_"),
+        }
+    }
+}
+
+fn join(types: &[Type]) -> String {
+    types.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+impl Type {
+    fn is_numeric(&self) -> bool {
+        matches!(self, Type::Integer { .. } | Type::Float { .. } | Type::IntLiteral | Type::FloatLiteral | Type::Unknown)
+    }
+
+    fn is_integer(&self) -> bool {
+        matches!(self, Type::Integer { .. } | Type::IntLiteral | Type::Unknown)
+    }
+
+    /// Whether some part of the type is still a literal's, which the context it's used in decides
+    fn is_vague(&self) -> bool {
+        match self {
+            Type::IntLiteral | Type::FloatLiteral => true,
+            Type::Tuple(elements) => elements.iter().any(Type::is_vague),
+            Type::Array(element) | Type::Range(element) => element.is_vague(),
+            _ => false,
+        }
+    }
+
+    /// What a range or array holds
+    fn element(&self) -> Type {
+        match self {
+            Type::Range(element) | Type::Array(element) => (**element).clone(),
+            _ => Type::Unknown,
+        }
+    }
+
+    pub fn int_type(&self) -> Option<IntType> {
+        match *self {
+            Type::Integer { signed, bits } => Some(IntType { signed, bits }),
+            _ => None,
+        }
+    }
+
+    /// The smallest and largest values of an integer type
+    pub fn bounds(&self) -> Option<(i128, i128)> {
+        self.int_type().map(IntType::bounds)
+    }
+
+    pub fn number_type(&self) -> Option<NumberType> {
+        match *self {
+            Type::Integer { signed, bits } => Some(NumberType::Integer(IntType { signed, bits })),
+            Type::Float { bits } => Some(NumberType::Float { bits }),
+            _ => None,
+        }
+    }
+
+    pub fn scalar_type(&self) -> Option<ScalarType> {
+        match self {
+            Type::Bool => Some(ScalarType::Bool),
+            typ => typ.number_type().map(ScalarType::Number),
+        }
+    }
+
+    /// Whether a value of type `found` can be used where this type is expected, either as it is or
+    /// by converting an integer to a float which holds it exactly
+    pub fn accepts(&self, found: &Type) -> bool {
+        self.holds(found) || converts_to(found, self)
+    }
+
+    /// Whether a value of type `found` can be used where this type is expected as it is. Integers
+    /// widen into the integer types with room for all of their values, and f32 widens into f64.
+    fn holds(&self, found: &Type) -> bool {
+        match (self, found) {
+            (Type::Unknown, _) | (_, Type::Unknown) | (_, Type::Never) => true,
+            (Type::Integer { .. } | Type::IntLiteral, Type::IntLiteral)
+            | (Type::IntLiteral, Type::Integer { .. })
+            | (Type::Float { .. } | Type::FloatLiteral, Type::FloatLiteral)
+            | (Type::FloatLiteral, Type::Float { .. }) => true,
+            (Type::Integer { signed, bits }, Type::Integer { signed: found_signed, bits: found_bits }) => {
+                signed == found_signed && bits >= found_bits || *signed && !found_signed && bits > found_bits
+            },
+            (Type::Float { bits }, Type::Float { bits: found_bits }) => bits >= found_bits,
+            (Type::Function { params, rets }, Type::Function { params: found_params, rets: found_rets }) => {
+                same_types(params, found_params) && same_types(rets, found_rets)
+            },
+            (Type::Tuple(elements), Type::Tuple(found)) => {
+                elements.len() == found.len() && elements.iter().zip(found).all(|(e, f)| e.holds(f))
+            },
+            (Type::Array(element), Type::Array(found)) | (Type::Range(element), Type::Range(found)) => {
+                element.holds(found)
+            },
+            (Type::Struct { module, name, .. }, Type::Struct { module: found_module, name: found_name, .. })
+            | (Type::Enum { module, name, .. }, Type::Enum { module: found_module, name: found_name, .. }) => {
+                module == found_module && name == found_name
+            },
+            (Type::Meta(_), Type::Meta(_)) => true,
+            (expected, found) => expected == found,
+        }
+    }
+}
+
+fn same_types(a: &[Type], b: &[Type]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.holds(b) && b.holds(a))
+}
+
+/// Whether an integer of type `found` is converted to the float expected, which it has to fit in
+/// without rounding
+fn converts_to(found: &Type, expected: &Type) -> bool {
+    match (found, expected) {
+        (Type::Integer { bits, .. }, Type::Float { bits: float_bits }) => {
+            let mantissa = if *float_bits == 32 { 24 } else { 53 };
+            *bits <= mantissa
+        },
+        _ => false,
+    }
+}
+
+/// The more specific of two types, if they fit together at all without converting either
+/// A native which takes an array of `ANY` first works on arrays of whatever that array holds, so
+/// every other `ANY` it takes or gives back, alone or in an array, is that element type
+fn with_element(types: &[Type], element: &Type) -> Vec<Type> {
+    types.iter().map(|typ| match typ {
+        Type::Unknown => element.clone(),
+        Type::Array(any) if **any == Type::Unknown => Type::Array(Box::new(element.clone())),
+        typ => typ.clone(),
+    }).collect()
+}
+
+fn unify(a: &Type, b: &Type) -> Option<Type> {
+    let vague = |t: &Type| matches!(t, Type::IntLiteral | Type::FloatLiteral | Type::Never | Type::Unknown);
+    if *b == Type::Never || a.holds(b) && !vague(a) {
+        Some(a.clone())
+    } else if b.holds(a) {
+        Some(b.clone())
+    } else {
+        None
+    }
+}
+
+/// The types which are built into the language
+fn builtin_type(name: &str) -> Option<Type> {
+    match NumberType::from_name(name) {
+        Some(NumberType::Integer(IntType { signed, bits })) => return Some(Type::Integer { signed, bits }),
+        Some(NumberType::Float { bits }) => return Some(Type::Float { bits }),
+        None => {},
+    }
+    Some(match name {
+        "bool" => Type::Bool,
+        "str" => Type::Str,
+        "void" => Type::Void,
+        _ => return None,
+    })
+}
+
+type Scope = HashMap<String, Type>;
+
+/// A variable declared with `:=` from literals, which gets its type from how it's used later on
+struct Inferring<'t> {
+    name: String,
+    depth: usize, // how many scopes were open when it was declared
+    value: &'t NodeContext,
+}
+
+struct Loop {
+    label: Option<String>,
+    breaks: Vec<Type>, // the type of each value the loop is broken out of with
+}
+
+pub struct TypeChecker<'t> {
+    ast: &'t NodeContext,
+    modules: &'t [ParsedModule],
+    errors: RefMut<'t, Errors>,
+    module: usize, // the module being checked
+    globals: Scope,
+    module_scopes: Vec<Scope>, // the top level of every module checked so far
+    scopes: Vec<Scope>, // the blocks and functions around the code being checked
+    returns: Vec<Vec<Type>>, // what each function around the code being checked returns
+    loops: Vec<Loop>,
+    inferring: Vec<Inferring<'t>>,
+    fixed: HashSet<*const NodeContext>, // the nodes which have been given a type, so each is only checked once
+    annotations: TypeAnnotations,
+}
+
+impl<'t> TypeChecker<'t> {
+    /// Checks a program which is just one module
+    #[cfg(test)]
+    pub fn new(ast: &'t NodeContext, errors: RefMut<'t, Errors>) -> Self {
+        TypeChecker::with_modules(ast, &[], errors)
+    }
+
+    /// Checks every module of a program. The root module is the last one.
+    pub fn with_modules(ast: &'t NodeContext, modules: &'t [ParsedModule], errors: RefMut<'t, Errors>) -> Self {
+        let mut globals = Scope::new();
+        for native in natives::builtins().iter() {
+            globals.insert(native.name.clone(), Type::Function {
+                params: native.params.clone(),
+                rets: match &native.ret {
+                    Type::Void => vec![],
+                    ret => vec![ret.clone()],
+                },
+            });
+        }
+
+        TypeChecker {
+            ast,
+            modules,
+            errors,
+            module: 0,
+            globals,
+            module_scopes: vec![],
+            scopes: vec![],
+            returns: vec![],
+            loops: vec![],
+            inferring: vec![],
+            fixed: HashSet::new(),
+            annotations: TypeAnnotations::default(),
+        }
+    }
+
+    /// Gives back what the IR generator needs to know about the types of the program: which integer
+    /// type each arithmetic operator works in, and where integers become floats
+    pub fn go(&mut self) -> TypeAnnotations {
+        for module in 0..self.modules.len().max(1) {
+            self.module = module;
+            self.module_scopes.push(Scope::new());
+            let ast = self.modules.get(module).map_or(self.ast, |module| &module.ast);
+            let nodes = match &ast.node {
+                Node::Block { nodes } => nodes.as_slice(),
+                _ => std::slice::from_ref(ast),
+            };
+            self.declare_top_level(nodes);
+            for node in nodes {
+                self.node(node, false);
+            }
+            self.infer_remaining();
+        }
+        std::mem::take(&mut self.annotations)
+    }
+
+    fn error(&mut self, message: String, position: usize) {
+        self.errors.typeck(message, position);
+    }
+
+    /// Everything at the top level of a module can be used before it's declared, so the types of
+    /// its declarations are found first. Types go before functions, which can mention them.
+    fn declare_top_level(&mut self, nodes: &'t [NodeContext]) {
+        for node in nodes {
+            match &node.node {
+                Node::Use { path } => {
+                    let alias = path.last().unwrap().clone();
+                    let module = self.modules.get(self.module)
+                        .and_then(|module| module.imports.get(path))
+                        .map_or(Type::Unknown, |&module| Type::Module(module));
+                    self.declare(alias, module);
+                },
+                Node::Declaration { name, body, .. } if is_type_declaration(body) => {
+                    self.declare(name.clone(), Type::Unknown);
+                },
+                _ => {},
+            }
+        }
+        // now every type has a name, the types of fields can be found
+        for node in nodes {
+            if let Node::Declaration { name, body, .. } = &node.node {
+                if is_type_declaration(body) {
+                    let typ = Type::Meta(Box::new(self.type_declaration(name, body)));
+                    self.declare(name.clone(), typ);
+                }
+            }
+        }
+        for node in nodes {
+            match &node.node {
+                Node::Declaration { body, .. } if is_type_declaration(body) => {},
+                Node::Declaration { name, typ, body, .. } => {
+                    let typ = match (&typ.node, &body.node) {
+                        (Node::Literal { typ: parser::Type::Unknown, .. }, Node::FunctionExpression { arg_types, ret_types, .. }) => {
+                            self.function_type(arg_types, ret_types)
+                        },
+                        (Node::Literal { typ: parser::Type::Unknown, .. }, _) => Type::Unknown,
+                        _ => self.resolve_type(typ),
+                    };
+                    self.declare(name.clone(), typ);
+                },
+                Node::Destructure { names, .. } => {
+                    for name in names {
+                        self.declare(name.clone(), Type::Unknown);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn declare(&mut self, name: String, typ: Type) {
+        match self.scopes.last_mut() {
+            Some(scope) => scope.insert(name, typ),
+            None => self.module_scopes[self.module].insert(name, typ),
+        };
+    }
+
+    fn pop_scope(&mut self) {
+        self.infer_remaining();
+        self.scopes.pop();
+    }
+
+    /// Closures can see the variables of the functions around them, so every scope is searched
+    fn lookup(&self, name: &str) -> Option<Type> {
+        self.scopes.iter()
+            .rev()
+            .chain(std::iter::once(&self.module_scopes[self.module]))
+            .chain(std::iter::once(&self.globals))
+            .find_map(|scope| scope.get(name))
+            .cloned()
+    }
+
+    /// The variables at the top level of another module
+    fn member(&self, module: usize, name: &str) -> Type {
+        self.module_scopes.get(module)
+            .and_then(|scope| scope.get(name))
+            .cloned()
+            .unwrap_or(Type::Unknown) // the IR generator says what's wrong with the name
+    }
+
+    /// Something used through the name of another module, which has to be pub there
+    fn module_member(&mut self, module: usize, name: &str, position: usize) -> Type {
+        let used = &self.modules[module];
+        match top_level_declarations(&used.ast).find(|(declared, _)| *declared == name) {
+            Some((_, true)) => self.member(module, name),
+            Some((_, false)) => {
+                self.error(format!("{} is not public in module {}", name, used.name), position);
+                Type::Unknown
+            },
+            None => {
+                self.error(format!("Module {} has nothing called {}", used.name, name), position);
+                Type::Unknown
+            },
+        }
+    }
+
+    /// Turns a type expression into the type it stands for
+    fn resolve_type(&mut self, node: &'t NodeContext) -> Type {
+        match &node.node {
+            Node::VariableRef { name } => match builtin_type(name) {
+                Some(typ) => typ,
+                None => match self.lookup(name) {
+                    Some(Type::Meta(typ)) => *typ,
+                    Some(Type::Unknown) => Type::Unknown,
+                    Some(_) => {
+                        self.error(format!("{} is a value, not a type", name), node.position);
+                        Type::Unknown
+                    },
+                    None => {
+                        self.error(format!("There is no type called {}", name), node.position);
+                        Type::Unknown
+                    },
+                },
+            },
+            Node::FieldAccess { .. } => match self.node(node, true) {
+                Type::Meta(typ) => *typ,
+                Type::Unknown => Type::Unknown,
+                found => {
+                    self.error(format!("Expected a type, but found {}", found), node.position);
+                    Type::Unknown
+                },
+            },
+            Node::ArrayType { size, element } => {
+                if let Some(size) = size {
+                    let found = self.node(size, true);
+                    if !matches!(found, Type::Integer { .. } | Type::IntLiteral | Type::Unknown) {
+                        self.error(format!("The size of an array has to be an integer, not {}", found), size.position);
+                    }
+                }
+                Type::Array(Box::new(self.resolve_type(element)))
+            },
+            Node::FunctionType { arg_types, ret_types } => self.function_type(arg_types, ret_types),
+            Node::Tuple { elements } => Type::Tuple(elements.iter().map(|e| self.resolve_type(e)).collect()),
+            Node::StructType { .. } | Node::EnumType { .. } => self.type_declaration(anonymous_name(node), node),
+            Node::Literal { typ: parser::Type::Unknown, .. } => Type::Unknown,
+            _ => {
+                self.error("Expected a type".to_owned(), node.position);
+                Type::Unknown
+            },
+        }
+    }
+
+    fn function_type(&mut self, arg_types: &'t [NodeContext], ret_types: &'t [NodeContext]) -> Type {
+        let params = arg_types.iter().map(|typ| self.resolve_type(typ)).collect();
+        let mut rets: Vec<_> = ret_types.iter().map(|typ| self.resolve_type(typ)).collect();
+        if rets == [Type::Void] {
+            rets.clear();
+        }
+        Type::Function { params, rets }
+    }
+
+    /// The type a struct or enum declaration makes, which is named after the declaration
+    fn type_declaration(&mut self, name: &str, body: &'t NodeContext) -> Type {
+        let module = self.module;
+        match &body.node {
+            Node::StructType { field_names, field_types } => Type::Struct {
+                module,
+                name: name.to_owned(),
+                fields: field_names.iter()
+                    .cloned()
+                    .zip(field_types.iter().map(|typ| self.resolve_type(typ)))
+                    .collect(),
+            },
+            Node::EnumType { variant_names, variant_payloads } => Type::Enum {
+                module,
+                name: name.to_owned(),
+                variants: variant_names.iter()
+                    .cloned()
+                    .zip(variant_payloads.iter().map(|payload| {
+                        payload.iter().map(|typ| self.resolve_type(typ)).collect()
+                    }))
+                    .collect(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Finds the type of a node, checking everything inside it. Nodes whose value is thrown away
+    /// aren't `used`, so their branches don't have to agree on a type.
+    fn node(&mut self, node: &'t NodeContext, used: bool) -> Type {
+        self.check(node, used, &Type::Unknown)
+    }
+
+    /// Like `node`, when the type the node should have is already known. It's handed on to the
+    /// parts of the node its value comes from, so literals there get it before their scope ends.
+    fn check(&mut self, node: &'t NodeContext, used: bool, expected: &Type) -> Type {
+        match &node.node {
+            Node::Block { nodes } => self.block(nodes, used, expected),
+            Node::InfixOp { op, left, right } => self.infix_op(node, op, left, right),
+            Node::PrefixOp { op, right } => self.prefix_op(node, op, right),
+            Node::PostfixOp { left, .. } => {
+                let start = self.node(left, true);
+                let range = self.range(&start, &Type::Unknown, node.position);
+                self.fix(left, &range.element());
+                range
+            },
+            Node::IndexOp { object, index } => self.index_op(object, index, node.position),
+            Node::Literal { typ, .. } => match typ {
+                parser::Type::IntLiteral => Type::IntLiteral,
+                parser::Type::FloatLiteral => Type::FloatLiteral,
+                parser::Type::StrLiteral => Type::Str,
+                parser::Type::Bool => Type::Bool,
+                parser::Type::Undefined => Type::Void,
+                parser::Type::Unknown => Type::Unknown,
+            },
+            Node::Tuple { elements } => Type::Tuple(elements.iter().map(|e| self.node(e, true)).collect()),
+            Node::ArrayLiteral { elements } => self.array_literal(elements),
+            Node::ArrayType { .. } | Node::FunctionType { .. } => Type::Meta(Box::new(self.resolve_type(node))),
+            Node::StructType { .. } | Node::EnumType { .. } => {
+                Type::Meta(Box::new(self.type_declaration(anonymous_name(node), node)))
+            },
+            Node::StructLiteral { typ, field_names, field_values } => {
+                self.struct_literal(typ, field_names, field_values, node.position)
+            },
+            Node::FieldAccess { object, field } => self.field_access(object, field, node.position),
+            Node::Match { value, arms } => self.match_expression(value, arms, used, expected, node.position),
+            Node::Call { callee, args } => self.call(callee, args, node.position),
+            Node::VariableRef { name } => self.variable(name, node.position),
+            Node::Declaration { name, typ, body, .. } => self.declaration(name, typ, body),
+            Node::Destructure { names, body } => self.destructure(names, body),
+            Node::IfExpression { condition, then_body, else_body } => {
+                self.if_expression(condition, then_body, else_body, used, expected, node.position)
+            },
+            Node::WhileExpression { condition, body, label } => self.while_expression(condition, body, label),
+            Node::Assignment { name, value } => self.assignment(name, value, node.position),
+            Node::FieldAssignment { object, field, value } => {
+                self.field_assignment(object, field, value, node.position)
+            },
+            Node::FunctionExpression { arg_types, arg_names, ret_types, body } => {
+                self.function_expression(node, arg_types, arg_names, ret_types, body)
+            },
+            Node::ForExpression { variable, iterable, body, label } => {
+                self.for_expression(variable, iterable, body, label)
+            },
+            Node::Return { value } => self.return_expression(value),
+            Node::Break { label, value } => {
+                let typ = self.node(value, true);
+                if let Some(found) = self.loops.iter_mut().rev().find(|l| label.is_none() || l.label == *label) {
+                    found.breaks.push(typ);
+                }
+                Type::Never
+            },
+            Node::Continue { .. } => Type::Never,
+            Node::Use { .. } => Type::Void,
+        }
+    }
+
+    fn block(&mut self, nodes: &'t [NodeContext], used: bool, expected: &Type) -> Type {
+        self.scopes.push(Scope::new());
+        let mut diverges = false;
+        let mut typ = Type::Void;
+        for (i, node) in nodes.iter().enumerate() {
+            typ = if used && i == nodes.len() - 1 {
+                let found = self.check(node, true, expected);
+                if expected.accepts(&found) {
+                    self.fix(node, expected);
+                }
+                found
+            } else {
+                self.node(node, false)
+            };
+            diverges |= typ == Type::Never;
+        }
+        self.pop_scope();
+        if diverges { Type::Never } else { typ }
+    }
+
+    /// Checks that a value fits where it's going, saying what went wrong with `message` if not
+    fn expect(&mut self, expected: &Type, found: &Type, node: &'t NodeContext, message: impl FnOnce() -> String) {
+        if expected.accepts(found) {
+            self.coerce(node, found, expected);
+            self.fix(node, expected);
+        } else {
+            let message = message();
+            self.error(message, node.position);
+        }
+    }
+
+    /// Gives the literals in an expression the type the context expects it to have, along with the
+    /// variables declared from literals that it uses
+    fn fix(&mut self, node: &'t NodeContext, typ: &Type) {
+        if typ.is_vague() || *typ == Type::Unknown {
+            return;
+        }
+        match (&node.node, typ) {
+            _ if !self.fixed.insert(node) => {},
+            (Node::Literal { typ: parser::Type::IntLiteral, value }, _) => self.literal_fits(value, typ, node.position),
+            (Node::PrefixOp { op, right }, Type::Integer { signed: false, .. }) if op == "-" => {
+                self.error(format!("Can't use - on {}", typ), node.position);
+                self.fix(right, typ);
+            },
+            (Node::PrefixOp { op, right }, _) if op == "-" => {
+                self.record(node, typ);
+                match &right.node {
+                    Node::Literal { typ: parser::Type::IntLiteral, value } => {
+                        self.literal_fits(&format!("-{}", value), typ, node.position)
+                    },
+                    _ => self.fix(right, typ),
+                }
+            },
+            (Node::VariableRef { name }, _) => self.pin(name, typ),
+            (Node::InfixOp { op, left, right }, _) if is_arithmetic(op) || op == "/" => {
+                if op != "/" {
+                    self.record(node, typ);
+                }
+                self.fix(left, typ);
+                self.fix(right, typ);
+            },
+            (Node::InfixOp { op, left, right }, Type::Range(element)) if op == ".." => {
+                self.fix(left, element);
+                self.fix(right, element);
+            },
+            (Node::PrefixOp { op, right: end }, Type::Range(element)) if op == ".." => self.fix(end, element),
+            (Node::PostfixOp { left: start, .. }, Type::Range(element)) => self.fix(start, element),
+            (Node::IndexOp { object, index }, _) if !is_range(index) => {
+                self.fix(object, &Type::Array(Box::new(typ.clone())))
+            },
+            (Node::Block { nodes }, _) => if let Some(last) = nodes.last() {
+                self.fix(last, typ);
+            },
+            (Node::IfExpression { then_body, else_body, .. }, _) => {
+                self.fix(then_body, typ);
+                self.fix(else_body, typ);
+            },
+            (Node::Match { arms, .. }, _) => for arm in arms {
+                self.fix(&arm.body, typ);
+            },
+            (Node::Tuple { elements }, Type::Tuple(types)) => for (element, typ) in elements.iter().zip(types) {
+                self.fix(element, typ);
+            },
+            (Node::ArrayLiteral { elements }, Type::Array(typ)) => for element in elements {
+                self.fix(element, typ);
+            },
+            _ => {},
+        }
+    }
+
+    fn literal_fits(&mut self, value: &str, typ: &Type, position: usize) {
+        if let Some((min, max)) = typ.bounds() {
+            if !value.parse().is_ok_and(|value: i128| min <= value && value <= max) {
+                self.error(format!("{} doesn't fit in {}", value, typ), position);
+            }
+        }
+    }
+
+    /// Settles the type of a variable whose type depended on literals
+    fn pin(&mut self, name: &str, typ: &Type) {
+        if typ.is_vague() || *typ == Type::Unknown {
+            return;
+        }
+        let depth = self.scopes.iter().rposition(|scope| scope.contains_key(name)).map_or(0, |i| i + 1);
+        let scope = match depth {
+            0 => &mut self.module_scopes[self.module],
+            depth => &mut self.scopes[depth - 1],
+        };
+        match scope.get_mut(name) {
+            Some(current) if current.is_vague() && typ.holds(current) => *current = typ.clone(),
+            _ => return,
+        }
+        if let Some(i) = self.inferring.iter().position(|i| i.name == name && i.depth == depth) {
+            let inferring = self.inferring.remove(i);
+            self.fix(inferring.value, typ);
+        }
+    }
+
+    /// Once nothing in the innermost scope can use its variables anymore, the ones which only had
+    /// literals to go on either take the type of the variables they came from or are ambiguous
+    fn infer_remaining(&mut self) {
+        let depth = self.scopes.len();
+        while let Some(i) = self.inferring.iter().position(|i| i.depth == depth) {
+            let Inferring { name, value, .. } = self.inferring.remove(i);
+            let found = self.flow_type(value);
+            if found.is_vague() || found == Type::Unknown {
+                let current = self.lookup(&name).unwrap_or(Type::Unknown);
+                self.error(
+                    format!("Can't infer the type of {} from {}, so it has to be written out", name, current),
+                    value.position,
+                );
+            } else {
+                self.pin(&name, &found);
+                self.fix(value, &found);
+            }
+        }
+    }
+
+    /// The type of an expression, going again only by the parts that literals' types flow through
+    fn flow_type(&self, node: &NodeContext) -> Type {
+        match &node.node {
+            Node::Literal { typ: parser::Type::IntLiteral, .. } => Type::IntLiteral,
+            Node::Literal { typ: parser::Type::FloatLiteral, .. } => Type::FloatLiteral,
+            Node::VariableRef { name } => self.lookup(name).unwrap_or(Type::Unknown),
+            Node::PrefixOp { op, right } if op == "-" => self.flow_type(right),
+            Node::InfixOp { op, left, right } if is_arithmetic(op) => {
+                unify(&self.flow_type(left), &self.flow_type(right)).unwrap_or(Type::Unknown)
+            },
+            Node::Block { nodes } => nodes.last().map_or(Type::Unknown, |last| self.flow_type(last)),
+            Node::IfExpression { then_body, else_body, .. } => {
+                unify(&self.flow_type(then_body), &self.flow_type(else_body)).unwrap_or(Type::Unknown)
+            },
+            Node::Tuple { elements } => Type::Tuple(elements.iter().map(|e| self.flow_type(e)).collect()),
+            Node::ArrayLiteral { elements } => Type::Array(Box::new(
+                elements.iter()
+                    .map(|e| self.flow_type(e))
+                    .reduce(|a, b| unify(&a, &b).unwrap_or(Type::Unknown))
+                    .unwrap_or(Type::Unknown)
+            )),
+            _ => Type::Unknown,
+        }
+    }
+
+    fn condition(&mut self, condition: &'t NodeContext) {
+        let found = self.node(condition, true);
+        self.expect(&Type::Bool, &found, condition, || {
+            format!("Expected the condition to be bool, but found {}", found)
+        });
+    }
+
+    /// Remembers the integer type an arithmetic operator works in
+    fn record(&mut self, node: &NodeContext, typ: &Type) {
+        if let Some(int) = typ.int_type() {
+            self.annotations.integers.insert(node, int);
+        }
+    }
+
+    /// Remembers to convert an integer which is used where a float is expected
+    fn coerce(&mut self, node: &NodeContext, found: &Type, expected: &Type) {
+        if converts_to(found, expected) {
+            self.annotations.conversions.insert(node, expected.number_type().unwrap());
+        }
+    }
+
+    fn infix_op(&mut self, node: &'t NodeContext, op: &str, left: &'t NodeContext, right: &'t NodeContext) -> Type {
+        let (left_node, right_node, position) = (left, right, node.position);
+        let left = self.node(left, true);
+        let right = self.node(right, true);
+        if op == ".." {
+            let range = self.range(&left, &right, position);
+            self.fix(left_node, &range.element());
+            self.fix(right_node, &range.element());
+            return range;
+        }
+
+        // an integer meeting a float it fits in is converted to it, where a float literal is an f64
+        let unified = unify(&left, &right).or_else(|| {
+            let float = |typ: &Type| if *typ == Type::FloatLiteral { Type::Float { bits: 64 } } else { typ.clone() };
+            let (left, right) = (float(&left), float(&right));
+            if right.accepts(&left) {
+                Some(right.clone())
+            } else if left.accepts(&right) {
+                Some(left.clone())
+            } else {
+                None
+            }
+        });
+        if let Some(typ) = &unified {
+            self.coerce(left_node, &left, typ);
+            self.coerce(right_node, &right, typ);
+            self.fix(left_node, typ);
+            self.fix(right_node, typ);
+        }
+        let result = match (op, unified) {
+            ("and" | "or", Some(typ)) if Type::Bool.accepts(&typ) => Some(Type::Bool),
+            ("+", Some(typ)) if typ.is_numeric() || typ == Type::Str => Some(typ),
+            ("-" | "*" | "//", Some(typ)) if typ.is_numeric() => Some(typ),
+            (op, Some(typ)) if is_arithmetic(op) && typ.is_integer() => Some(typ),
+            // dividing integers exactly gives a float
+            ("/", Some(typ)) if typ.is_integer() && typ != Type::Unknown => Some(Type::Float { bits: 64 }),
+            ("/", Some(typ)) if typ.is_numeric() => Some(typ),
+            ("==" | "!=", Some(typ)) if typ.is_numeric() || matches!(typ, Type::Bool | Type::Str) => Some(Type::Bool),
+            ("<" | ">" | "<=" | ">=", Some(typ)) if typ.is_numeric() || typ == Type::Str => Some(Type::Bool),
+            _ => None,
+        };
+        match result {
+            Some(typ) => {
+                if is_arithmetic(op) {
+                    self.record(node, &typ);
+                }
+                typ
+            },
+            None => {
+                self.error(format!("Can't use {} on {} and {}", op, left, right), position);
+                Type::Unknown
+            },
+        }
+    }
+
+    fn prefix_op(&mut self, node: &NodeContext, op: &str, right: &'t NodeContext) -> Type {
+        let (right_node, position) = (right, node.position);
+        let right = self.node(right, true);
+        match op {
+            ".." => {
+                let range = self.range(&Type::Unknown, &right, position);
+                self.fix(right_node, &range.element());
+                range
+            },
+            "-" if right.is_numeric() && right.bounds().is_none_or(|(min, _)| min < 0) => {
+                self.record(node, &right);
+                right
+            },
+            "not" if Type::Bool.accepts(&right) => Type::Bool,
+            _ => {
+                self.error(format!("Can't use {} on {}", op, right), position);
+                Type::Unknown
+            },
+        }
+    }
+
+    /// Either end of a range can be left out, which is given as Unknown here
+    fn range(&mut self, start: &Type, end: &Type, position: usize) -> Type {
+        match unify(start, end) {
+            Some(typ) if typ.is_integer() => Type::Range(Box::new(typ)),
+            _ => {
+                self.error(format!("Ranges have to be between integers, not {} and {}", start, end), position);
+                Type::Range(Box::new(Type::Unknown))
+            },
+        }
+    }
+
+    fn index_op(&mut self, object: &'t NodeContext, index: &'t NodeContext, position: usize) -> Type {
+        let object = self.node(object, true);
+        let index = self.node(index, true);
+        match (object, index) {
+            (Type::Array(element), index) if index.is_integer() => *element,
+            (Type::Array(element), Type::Range(_)) => Type::Array(element),
+            (Type::Unknown, _) => Type::Unknown,
+            (object, index) => {
+                self.error(format!("Can't index {} with {}", object, index), position);
+                Type::Unknown
+            },
+        }
+    }
+
+    fn array_literal(&mut self, elements: &'t [NodeContext]) -> Type {
+        let mut element = Type::Unknown;
+        let mut first = true;
+        for node in elements {
+            let found = self.node(node, true);
+            if first {
+                element = found;
+                first = false;
+            } else {
+                match unify(&element, &found) {
+                    Some(unified) => element = unified,
+                    None => self.error(
+                        format!("Arrays can only hold one type, but this one has {} and {}", element, found),
+                        node.position,
+                    ),
+                }
+            }
+        }
+        for node in elements {
+            self.fix(node, &element);
+        }
+        Type::Array(Box::new(element))
+    }
+
+    fn struct_literal(&mut self,
+        typ: &'t NodeContext,
+        field_names: &[String],
+        field_values: &'t [NodeContext],
+        position: usize
+    ) -> Type {
+        let found: Vec<_> = field_values.iter().map(|value| self.node(value, true)).collect();
+        let struct_type = match self.node(typ, true) {
+            Type::Meta(struct_type) => *struct_type,
+            _ => return Type::Unknown,
+        };
+        let fields = match &struct_type {
+            Type::Struct { fields, .. } => fields.clone(),
+            _ => {
+                self.error(format!("{} isn't a struct", struct_type), position);
+                return Type::Unknown;
+            },
+        };
+
+        for ((name, found), value) in field_names.iter().zip(&found).zip(field_values) {
+            match fields.iter().find(|(field, _)| field == name) {
+                Some((_, expected)) => self.expect(expected, found, value, || {
+                    format!("Expected field {} to be {}, but found {}", name, expected, found)
+                }),
+                None => self.error(format!("{} has no field {}", struct_type, name), value.position),
+            }
+        }
+        for (field, _) in &fields {
+            if !field_names.contains(field) {
+                self.error(format!("Missing field {} in struct literal of {}", field, struct_type), position);
+            }
+        }
+        struct_type
+    }
+
+    fn field_access(&mut self, object: &'t NodeContext, field: &str, position: usize) -> Type {
+        match self.node(object, true) {
+            Type::Struct { name, fields, .. } => match fields.iter().find(|(f, _)| f == field) {
+                Some((_, typ)) => typ.clone(),
+                None => {
+                    self.error(format!("{} has no field {}", name, field), position);
+                    Type::Unknown
+                },
+            },
+            Type::Module(module) => self.module_member(module, field, position),
+            Type::Meta(typ) => match &*typ {
+                // variants without a payload are written like fields of their enum, `Shape.Empty`
+                Type::Enum { variants, .. } => match variants.iter().find(|(v, _)| v == field) {
+                    Some((_, payload)) if payload.is_empty() => *typ,
+                    Some((_, payload)) => {
+                        self.error(
+                            format!("Variant {} holds {} value(s), but none were given", field, payload.len()),
+                            position,
+                        );
+                        *typ
+                    },
+                    None => {
+                        self.error(format!("{} has no variant {}", typ, field), position);
+                        Type::Unknown
+                    },
+                },
+                _ => Type::Unknown,
+            },
+            Type::Unknown => Type::Unknown,
+            found => {
+                self.error(format!("{} has no field {}", found, field), position);
+                Type::Unknown
+            },
+        }
+    }
+
+    fn match_expression(&mut self,
+        value: &'t NodeContext,
+        arms: &'t [MatchArm],
+        used: bool,
+        expected: &Type,
+        position: usize
+    ) -> Type {
+        let value = self.node(value, true);
+        let mut result = Type::Never;
+        for arm in arms {
+            self.scopes.push(Scope::new());
+            self.pattern(&arm.pattern, &value);
+            if let Some(guard) = &arm.guard {
+                self.condition(guard);
+            }
+            let found = self.check(&arm.body, used, expected);
+            self.pop_scope();
+
+            if used {
+                match unify(&result, &found) {
+                    Some(unified) => result = unified,
+                    None => self.error(
+                        format!("The arms of a match have different types, {} and {}", result, found),
+                        position,
+                    ),
+                }
+            }
+        }
+        if !used {
+            return Type::Void;
+        }
+        for arm in arms {
+            self.fix(&arm.body, &result);
+        }
+        result
+    }
+
+    /// Declares the variables a pattern binds, with the types of the parts of the value they get.
+    /// Patterns that don't fit the value are left to the IR generator to complain about.
+    fn pattern(&mut self, pattern: &Pattern, value: &Type) {
+        match (pattern, value) {
+            (Pattern::Binding(name), value) => self.declare(name.clone(), value.clone()),
+            (Pattern::Tuple(elements), Type::Tuple(types)) if elements.len() == types.len() => {
+                for (element, typ) in elements.iter().zip(types) {
+                    self.pattern(element, typ);
+                }
+            },
+            (Pattern::Variant { variant, payload, .. }, Type::Enum { variants, .. }) => {
+                let types = variants.iter()
+                    .find(|(name, _)| name == variant)
+                    .map_or(vec![], |(_, types)| types.clone());
+                for (i, element) in payload.iter().enumerate() {
+                    self.pattern(element, types.get(i).unwrap_or(&Type::Unknown));
+                }
+            },
+            (Pattern::Tuple(elements) | Pattern::Variant { payload: elements, .. }, _) => {
+                for element in elements {
+                    self.pattern(element, &Type::Unknown);
+                }
+            },
+            (Pattern::Wildcard | Pattern::Literal { .. }, _) => {},
+        }
+    }
+
+    fn call(&mut self, callee: &'t NodeContext, args: &'t [NodeContext], position: usize) -> Type {
+        let found: Vec<_> = args.iter().map(|arg| self.node(arg, true)).collect();
+        let (params, rets) = match &callee.node {
+            Node::FieldAccess { object, field } => match self.member_function(object, field, position) {
+                Some(function) => function,
+                None => return Type::Unknown,
+            },
+            _ => match self.node(callee, true) {
+                Type::Function { params, rets } => (params, rets),
+                Type::Meta(target) if target.number_type().is_some() => {
+                    return self.conversion(*target, args, &found, position);
+                },
+                Type::Unknown => return Type::Unknown,
+                found => {
+                    self.error(format!("Can't call {}, which isn't a function", found), position);
+                    return Type::Unknown;
+                },
+            },
+        };
+        let (params, rets) = match (params.first(), found.first()) {
+            (Some(Type::Array(any)), Some(Type::Array(element))) if **any == Type::Unknown && !element.is_vague() => {
+                (with_element(&params, element), with_element(&rets, element))
+            },
+            _ => (params, rets),
+        };
+
+        if params.len() != args.len() {
+            self.error(format!("Expected {} argument(s), but found {}", params.len(), args.len()), position);
+        }
+        for (i, ((expected, found), arg)) in params.iter().zip(&found).zip(args).enumerate() {
+            self.expect(expected, found, arg, || {
+                format!("Expected argument {} to be {}, but found {}", i + 1, expected, found)
+            });
+        }
+        match rets.as_slice() {
+            [] => Type::Void,
+            [ret] => ret.clone(),
+            rets => Type::Tuple(rets.to_vec()),
+        }
+    }
+
+    /// Calling a number type converts any number to it, even if that loses part of the number. An
+    /// integer which doesn't fit in an integer type overflows like arithmetic does.
+    fn conversion(&mut self, target: Type, args: &'t [NodeContext], found: &[Type], position: usize) -> Type {
+        match (args, found) {
+            ([arg], [found]) if found.is_numeric() => {
+                if *found == Type::IntLiteral && target.is_integer() {
+                    self.fix(arg, &target);
+                }
+            },
+            (_, [found]) => self.error(format!("Can't convert {} to {}", found, target), position),
+            _ => self.error(format!("Expected 1 argument(s), but found {}", args.len()), position),
+        }
+        target
+    }
+
+    /// What `a.b(c)` calls, leaving out the parameter `a` fills in if it's passed along. Variants
+    /// are made like functions, and anything that isn't a module or a struct with a function in
+    /// field `b` calls the function `b` with `a` as its first argument.
+    fn member_function(&mut self, object: &'t NodeContext, name: &str, position: usize) -> Option<(Vec<Type>, Vec<Type>)> {
+        let object_type = self.node(object, true);
+        let (function, passes_object) = match &object_type {
+            Type::Meta(typ) => return match &**typ {
+                Type::Enum { variants, .. } => match variants.iter().find(|(variant, _)| variant == name) {
+                    Some((_, payload)) => Some((payload.clone(), vec![(**typ).clone()])),
+                    None => {
+                        self.error(format!("{} has no variant {}", typ, name), position);
+                        None
+                    },
+                },
+                _ => None,
+            },
+            Type::Module(module) => (self.module_member(*module, name, position), false),
+            Type::Struct { fields, .. } if fields.iter().any(|(field, _)| field == name) => {
+                (fields.iter().find(|(field, _)| field == name).unwrap().1.clone(), false)
+            },
+            // a struct from another module finds its methods in that module
+            Type::Struct { module, .. } if *module != self.module => (self.member(*module, name), true),
+            _ => match self.lookup(name) {
+                Some(function) => (function, true),
+                None if object_type == Type::Unknown => return None,
+                None => {
+                    self.error(format!("Couldn't find a function called {}", name), position);
+                    return None;
+                },
+            },
+        };
+
+        match function {
+            Type::Function { params, rets } if !passes_object => Some((params, rets)),
+            Type::Function { mut params, rets } => {
+                if params.is_empty() {
+                    self.error(format!("{} takes no arguments, so it can't be called on {}", name, object_type), position);
+                    return None;
+                }
+                let receiver = params.remove(0);
+                self.expect(&receiver, &object_type, object, || {
+                    format!("Expected argument 1 to be {}, but found {}", receiver, object_type)
+                });
+                Some((params, rets))
+            },
+            Type::Unknown => None,
+            found => {
+                self.error(format!("Can't call {}, which isn't a function", found), position);
+                None
+            },
+        }
+    }
+
+    fn variable(&mut self, name: &str, position: usize) -> Type {
+        if let Some(typ) = builtin_type(name) {
+            return Type::Meta(Box::new(typ));
+        }
+        self.lookup(name).unwrap_or_else(|| {
+            self.error(format!("Couldn't find a variable called {}", name), position);
+            Type::Unknown
+        })
+    }
+
+    fn declaration(&mut self, name: &str, typ: &'t NodeContext, body: &'t NodeContext) -> Type {
+        let expected = match &typ.node {
+            Node::Literal { typ: parser::Type::Unknown, .. } => None,
+            _ => Some(self.resolve_type(typ)),
+        };
+        // a function can call itself, so it's declared before its body is checked
+        if let Node::FunctionExpression { arg_types, ret_types, .. } = &body.node {
+            let function = expected.clone().unwrap_or_else(|| self.function_type(arg_types, ret_types));
+            self.declare(name.to_owned(), function);
+        }
+        let found = if is_type_declaration(body) {
+            Type::Meta(Box::new(self.type_declaration(name, body)))
+        } else {
+            self.check(body, true, expected.as_ref().unwrap_or(&Type::Unknown))
+        };
+        let declared = match expected {
+            None => {
+                if found.is_vague() {
+                    self.inferring.push(Inferring { name: name.to_owned(), depth: self.scopes.len(), value: body });
+                }
+                found
+            },
+            Some(expected) => {
+                self.expect(&expected, &found, body, || {
+                    format!("Expected {} to be {}, but found {}", name, expected, found)
+                });
+                expected
+            },
+        };
+        self.declare(name.to_owned(), declared);
+        Type::Void
+    }
+
+    fn destructure(&mut self, names: &[String], body: &'t NodeContext) -> Type {
+        let types = match self.node(body, true) {
+            Type::Tuple(types) if types.len() == names.len() => types,
+            Type::Unknown => vec![Type::Unknown; names.len()],
+            found => {
+                self.error(format!("Expected {} values to unpack, but found {}", names.len(), found), body.position);
+                vec![Type::Unknown; names.len()]
+            },
+        };
+        for (name, typ) in names.iter().zip(types) {
+            self.declare(name.clone(), typ);
+        }
+        Type::Void
+    }
+
+    /// Without an else, an if's value is void when the condition is false
+    fn if_expression(&mut self,
+        condition: &'t NodeContext,
+        then_body: &'t NodeContext,
+        else_body: &'t NodeContext,
+        used: bool,
+        expected: &Type,
+        position: usize
+    ) -> Type {
+        self.condition(condition);
+        let then_type = self.check(then_body, used, expected);
+        let else_type = self.check(else_body, used, expected);
+        if !used {
+            return if then_type == Type::Never && else_type == Type::Never { Type::Never } else { Type::Void };
+        }
+        let unified = unify(&then_type, &else_type).unwrap_or_else(|| {
+            self.error(
+                format!("The branches of an if have different types, {} and {}", then_type, else_type),
+                position,
+            );
+            Type::Unknown
+        });
+        self.fix(then_body, &unified);
+        self.fix(else_body, &unified);
+        unified
+    }
+
+    /// A loop's value is whatever it's broken out of with, which is void if it stops by itself
+    fn while_expression(&mut self, condition: &'t NodeContext, body: &'t NodeContext, label: &Option<String>) -> Type {
+        self.condition(condition);
+        let infinite = matches!(&condition.node, Node::Literal { typ: parser::Type::Bool, value } if value == "true");
+        self.loops.push(Loop { label: label.clone(), breaks: vec![] });
+        self.node(body, false);
+        let breaks = self.loops.pop().unwrap().breaks;
+        self.loop_type(breaks, infinite, condition.position)
+    }
+
+    fn loop_type(&mut self, breaks: Vec<Type>, infinite: bool, position: usize) -> Type {
+        let mut result = if infinite { Type::Never } else { Type::Void };
+        for found in breaks {
+            match unify(&result, &found) {
+                Some(unified) => result = unified,
+                None => self.error(
+                    format!("The same loop is broken out of with {} and {}", result, found),
+                    position,
+                ),
+            }
+        }
+        result
+    }
+
+    fn assignment(&mut self, name: &str, value: &'t NodeContext, position: usize) -> Type {
+        let expected = self.variable(name, position);
+        let found = self.check(value, true, &expected);
+        if expected.is_vague() && found.accepts(&expected) {
+            self.pin(name, &found);
+        }
+        self.expect(&expected, &found, value, || {
+            format!("Can't assign {} to {}, which is {}", found, name, expected)
+        });
+        Type::Void
+    }
+
+    fn field_assignment(&mut self, object: &'t NodeContext, field: &str, value: &'t NodeContext, position: usize) -> Type {
+        let found = self.node(value, true);
+        let expected = self.field_access(object, field, position);
+        self.expect(&expected, &found, value, || {
+            format!("Can't assign {} to field {}, which is {}", found, field, expected)
+        });
+        Type::Void
+    }
+
+    fn function_expression(&mut self,
+        node: &NodeContext,
+        arg_types: &'t [NodeContext],
+        arg_names: &[String],
+        ret_types: &'t [NodeContext],
+        body: &'t NodeContext
+    ) -> Type {
+        let function = self.function_type(arg_types, ret_types);
+        let (params, rets) = match &function {
+            Type::Function { params, rets } => (params.clone(), rets.clone()),
+            _ => unreachable!(),
+        };
+        let scalars = |types: &[Type]| types.iter().map(Type::scalar_type).collect::<Option<Vec<_>>>();
+        if let (Some(params), Some(rets)) = (scalars(&params), scalars(&rets)) {
+            self.annotations.signatures.insert(node, Signature { params, rets });
+        }
+
+        // break and continue can't leave the function
+        let outer_loops = std::mem::take(&mut self.loops);
+        self.scopes.push(arg_names.iter().cloned().zip(params).collect());
+        self.returns.push(rets.clone());
+
+        let found = self.check(body, !rets.is_empty(), &returned_type(&rets));
+        if !rets.is_empty() {
+            let expected = returned_type(&rets);
+            self.expect(&expected, &found, body, || {
+                format!("Expected to return {}, but found {}", expected, found)
+            });
+        }
+
+        self.returns.pop();
+        self.pop_scope();
+        self.loops = outer_loops;
+        function
+    }
+
+    fn for_expression(&mut self,
+        variable: &str,
+        iterable: &'t NodeContext,
+        body: &'t NodeContext,
+        label: &Option<String>
+    ) -> Type {
+        let element = match self.node(iterable, true) {
+            Type::Array(element) | Type::Range(element) => *element,
+            Type::Unknown => Type::Unknown,
+            found => {
+                self.error(format!("Can't loop over {}", found), iterable.position);
+                Type::Unknown
+            },
+        };
+        self.scopes.push(Scope::new());
+        self.declare(variable.to_owned(), element);
+        self.loops.push(Loop { label: label.clone(), breaks: vec![] });
+        self.node(body, false);
+        let breaks = self.loops.pop().unwrap().breaks;
+        self.pop_scope();
+        self.loop_type(breaks, false, iterable.position)
+    }
+
+    /// A tuple returns each of its values, so `return t` works as well as `return (a, b)`
+    fn return_expression(&mut self, value: &'t NodeContext) -> Type {
+        let rets = self.returns.last().cloned().unwrap_or_default();
+        let found = self.check(value, true, &returned_type(&rets));
+        let count = match &found {
+            Type::Unknown | Type::Never => return Type::Never,
+            Type::Void => 0,
+            Type::Tuple(elements) => elements.len(),
+            _ => 1,
+        };
+        if count != rets.len() {
+            self.error(format!("Expected to return {} value(s), but found {}", rets.len(), count), value.position);
+        } else if !rets.is_empty() {
+            let expected = returned_type(&rets);
+            self.expect(&expected, &found, value, || {
+                format!("Expected to return {}, but found {}", expected, found)
+            });
+        }
+        Type::Never
+    }
+}
+
+fn returned_type(rets: &[Type]) -> Type {
+    match rets {
+        [] => Type::Void,
+        [ret] => ret.clone(),
+        rets => Type::Tuple(rets.to_vec()),
+    }
+}
+
+/// The operators which give back the same type of number they're given. Those ending in `%`, `|`
+/// and `?` wrap, saturate and trap on overflow, so they only work on integers.
+fn is_arithmetic(op: &str) -> bool {
+    matches!(op,
+        "+" | "-" | "*" | "//"
+        | "+%" | "-%" | "*%"
+        | "+|" | "-|" | "*|"
+        | "+?" | "-?" | "*?"
+    )
+}
+
+fn is_range(node: &NodeContext) -> bool {
+    match &node.node {
+        Node::InfixOp { op, .. } | Node::PrefixOp { op, .. } => op == "..",
+        Node::PostfixOp { .. } => true,
+        _ => false,
+    }
+}
+
+fn is_type_declaration(body: &NodeContext) -> bool {
+    matches!(body.node, Node::StructType { .. } | Node::EnumType { .. })
+}
+
+/// What to call a struct or enum type that wasn't declared with a name
+fn anonymous_name(node: &NodeContext) -> &'static str {
+    match node.node {
+        Node::EnumType { .. } => "enum",
+        _ => "struct",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        errors::Error,
+        lexer::Lexer,
+        modules::{
+            tests::project,
+            ModuleLoader,
+        },
+        parser::Parser,
+    };
+
+    fn messages(errors: &RefCell<Errors>) -> Vec<String> {
+        errors.borrow().errors.iter().map(|e| match e {
+            Error::Typeck { message, .. } => message.clone(),
+            e => panic!("{:?}", e),
+        }).collect()
+    }
+
+    fn type_errors(contents: &'static str) -> Vec<String> {
+        let errors = RefCell::new(Errors::new());
+        let ast = Parser::new(Lexer::new(contents, errors.borrow_mut())).go().unwrap();
+        TypeChecker::new(&ast, errors.borrow_mut()).go();
+        messages(&errors)
+    }
+
+    /// Errors point at the operator or the start of what's wrong
+    #[test]
+    fn error_positions() {
+        let errors = RefCell::new(Errors::new());
+        let ast = Parser::new(Lexer::new("a: n64 = 1\nb := a + true", errors.borrow_mut())).go().unwrap();
+        TypeChecker::new(&ast, errors.borrow_mut()).go();
+        assert_eq!(errors.borrow().errors, vec![
+            Error::Typeck { message: "Can't use + on n64 and bool".to_owned(), position: 18 },
+        ]);
+    }
+
+    #[test]
+    fn operators_and_branches() {
+        assert_eq!(type_errors("main := fn() n64 {
+            a: n64 = 1
+            b: i32 = 2
+            c := a + b
+            d := \"one\" + 1
+            e := not 5
+            print(1.5 +% 2.0)
+            if a { 1 } else { 2 }
+            f := if a > 0 { true } else { 2 }
+            g := if a > 0 { 1 } else { return 2 }
+            (a + 1) * g
+        }"), vec![
+            "Can't use + on n64 and i32",
+            "Can't use + on str and integer literal",
+            "Can't use not on integer literal",
+            "Can't use +% on float literal and float literal",
+            "Expected the condition to be bool, but found n64",
+            "The branches of an if have different types, bool and integer literal",
+        ]);
+    }
+
+    #[test]
+    fn declarations_and_calls() {
+        assert_eq!(type_errors("Point := struct { x: n64, y: n64 }
+        length := fn(p: Point) f64 { 0.0 }
+        main := fn() void {
+            p := Point{ .x = 1, .y = true }
+            q := Point{ .x = 1 }
+            n: n64 = length(p)
+            n = \"ten\"
+            length(1)
+            length(p, p)
+            p.length()
+            p.z = 5
+            n()
+            u: Nothing = 1
+        }"), vec![
+            "Expected field y to be n64, but found bool",
+            "Missing field y in struct literal of Point",
+            "Expected n to be n64, but found f64",
+            "Can't assign str to n, which is n64",
+            "Expected argument 1 to be Point, but found integer literal",
+            "Expected 1 argument(s), but found 2",
+            "Point has no field z",
+            "Can't call n64, which isn't a function",
+            "There is no type called Nothing",
+        ]);
+    }
+
+    #[test]
+    fn function_types() {
+        assert_eq!(type_errors("apply := fn(f: fn(n64) n64, x: n64) n64 { f(x) }
+        main := fn() fn(n64) bool {
+            apply(fn(x: n64) n64 { x }, 1)
+            apply(fn(x: n64) bool { true }, 1)
+            g: fn() void = main
+            return apply
+        }"), vec![
+            "Expected argument 1 to be fn(n64) n64, but found fn(n64) bool",
+            "Expected g to be fn() void, but found fn() fn(n64) bool",
+            "Expected to return fn(n64) bool, but found fn(fn(n64) n64, n64) n64",
+        ]);
+    }
+
+    #[test]
+    fn return_counts() {
+        assert_eq!(type_errors("main := fn() void {
+            f := fn() n64 { return }
+            g := fn() (n64, bool) { return (1, true) }
+            h := fn() (n64, bool) { return 1 }
+            pair := fn() (n64, n64) {
+                t := (1, 2)
+                return t
+            }
+            return 5
+        }"), vec![
+            "Expected to return 1 value(s), but found 0",
+            "Expected to return 2 value(s), but found 1",
+            "Expected to return 0 value(s), but found 1",
+        ]);
+    }
+
+    #[test]
+    fn appending() {
+        assert_eq!(type_errors("main := fn() void {
+            names: []str = [\"a\"]
+            names = append(names, \"b\")
+            names = append(names, 1)
+            numbers: []n64 = append(names, \"c\")
+            append(5, 1)
+        }"), vec![
+            "Expected argument 2 to be str, but found integer literal",
+            "Expected numbers to be []n64, but found []str",
+            "Expected argument 1 to be []_, but found integer literal",
+        ]);
+    }
+
+    #[test]
+    fn enums_and_loops() {
+        assert_eq!(type_errors("Shape := enum { Circle(n64), Empty }
+        main := fn() n64 {
+            s := Shape.Circle(true)
+            area := match s {
+                Shape.Circle(r) => r * 3
+                Shape.Empty => \"none\"
+            }
+            for x in 5 { print(x) }
+            total: n64 = 0
+            for x in [1, 2, 3] { total = total + x }
+            found := loop { if total > 3 { break total } }
+            found
+        }"), vec![
+            "Expected argument 1 to be n64, but found bool",
+            "The arms of a match have different types, n64 and str",
+            "Can't loop over integer literal",
+        ]);
+    }
+
+    #[test]
+    fn inference() {
+        assert_eq!(type_errors("small := fn() i8 {
+            w := 3
+            w
+        }
+        main := fn() void {
+            a := 5
+            n: n64 = a
+            b := 300
+            c: n8 = b
+            d := -1
+            e: n32 = d
+            f := 1.5
+            g: f32 = f * 2.0
+            h := 7
+            print(h)
+            xs := [1, 2, 3]
+            total: i32 = xs[0]
+            k := 2
+            m := k
+            o: i64 = m
+            p := 4
+            q := p + 1
+            p = e
+            r := 5
+            s: n64 = r
+            t: i32 = r
+            u: n8 = if a > 0 { 256 } else { 0 }
+            v := if a > 0 { 1 } else { 2 }
+            print(v + 1)
+        }"), vec![
+            "300 doesn't fit in n8",
+            "Can't use - on n32",
+            "Expected t to be i32, but found n64",
+            "256 doesn't fit in n8",
+            "Can't infer the type of h from integer literal, so it has to be written out",
+            "Can't infer the type of v from integer literal, so it has to be written out",
+        ]);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(type_errors("main := fn() void {
+            a: i32 = 7
+            n: n64 = 5
+            b: f32 = a
+            c: f64 = n
+            d: i16 = a
+            e: i64 = a
+            f: i64 = n
+            g := f64(a) + 0.5
+            h := a + 0.5
+            k := n + 0.5
+            s := f64(\"text\")
+            x: f64 = 1
+            small: n8 = n8(n)
+            y := i32(1, 2)
+        }"), vec![
+            "Expected b to be f32, but found i32",
+            "Expected c to be f64, but found n64",
+            "Expected d to be i16, but found i32",
+            "Expected f to be i64, but found n64",
+            "Can't use + on n64 and float literal",
+            "Can't convert str to f64",
+            "Expected x to be f64, but found integer literal",
+            "Expected 1 argument(s), but found 2",
+        ]);
+    }
+
+    #[test]
+    fn module_members() {
+        let dir = project("typeck-members", &[
+            ("main.meg", "use secrets
+            main := fn() n64 { secrets.shown() + secrets.hidden + secrets.missing }"),
+            ("secrets.meg", "hidden := 1
+            pub shown := fn() n64 { hidden }"),
+        ]);
+
+        let errors = RefCell::new(Errors::new());
+        let modules = ModuleLoader::new(vec![], &errors).load(&dir.join("main.meg"));
+        let root = &modules.last().unwrap().ast;
+        TypeChecker::with_modules(root, &modules, errors.borrow_mut()).go();
+        assert_eq!(messages(&errors), vec![
+            "hidden is not public in module secrets",
+            "Module secrets has nothing called missing",
+        ]);
+    }
+
+    /// The library that comes with Meg should always type check
+    #[test]
+    fn core_library() {
+        let dir = project("typeck-core", &[("main.meg", "use core.io
+        use core.math
+        use core.string
+        use core.collections
+        main := fn() void {
+            io.out.write_line(string.repeat(\"ab\", 3))
+            io.err.write(to_string(math.hypot(3.0, 4.0)))
+        }")]);
+
+        let errors = RefCell::new(Errors::new());
+        let search_path = vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("lib")];
+        let modules = ModuleLoader::new(search_path, &errors).load(&dir.join("main.meg"));
+        let root = &modules.last().unwrap().ast;
+        TypeChecker::with_modules(root, &modules, errors.borrow_mut()).go();
+        assert!(messages(&errors).is_empty(), "{:?}", messages(&errors));
+    }
+}